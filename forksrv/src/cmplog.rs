@@ -0,0 +1,203 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//Comparison operands logged by targets built with AFL++ CmpLog (AFL_LLVM_CMPLOG=1). The layout
+//of the shared memory mirrors include/cmplog.h of AFL++ 4.0x:
+//  struct cmp_map { cmp_header headers[CMP_MAP_W]; cmp_operands log[CMP_MAP_W][CMP_MAP_H]; }
+//Function comparisons (strcmp, memcmp, ...) reuse the log of their slot as cmpfn_operands.
+
+use std::collections::HashSet;
+
+use nix::libc::{self, shmdt};
+
+use error::SpawnError;
+use exitreason::ExitReason;
+use newtypes::SubprocessError;
+use ForkServer;
+
+pub const CMP_MAP_W: usize = 65536;
+pub const CMP_MAP_H: usize = 32;
+pub const CMP_MAP_RTN_H: usize = CMP_MAP_H / 4;
+
+const CMP_TYPE_INS: u64 = 1;
+const CMP_TYPE_RTN: u64 = 2;
+
+//struct cmp_header {hits:24, id:24, shape:5, type:2, attribute:4, overflow:1, reserved:4}
+const HEADER_SIZE: usize = 8;
+//struct cmp_operands {u64 v0, v1, v0_128, v1_128}
+const OPERANDS_SIZE: usize = 32;
+//struct cmpfn_operands {u8 v0[31], v0_len, v1[31], v1_len}
+const FN_OPERANDS_SIZE: usize = 64;
+const FN_OPERAND_MAX_LEN: usize = 31;
+
+pub const CMP_MAP_SIZE: usize = CMP_MAP_W * HEADER_SIZE + CMP_MAP_W * CMP_MAP_H * OPERANDS_SIZE;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CmpValues {
+    //An integer comparison of `size` bytes
+    Ins { size: usize, v0: u128, v1: u128 },
+    //The arguments of a call to a comparison function
+    Rtn { v0: Vec<u8>, v1: Vec<u8> },
+}
+
+//Forkserver for the CmpLog build of the target, every run returns the comparisons it executed
+pub struct CmpLogForkServer {
+    forksrv: ForkServer,
+    cmp_map: *mut [u8],
+}
+
+impl CmpLogForkServer {
+    pub fn new(
+        path: String,
+        args: Vec<String>,
+        hide_output: bool,
+        timeout_in_millis: u64,
+        bitmap_size: usize,
+        extension: String,
+    ) -> Result<Self, SpawnError> {
+        let (shm_id, cmp_map) = ForkServer::create_shm(CMP_MAP_SIZE)?;
+        let forksrv = ForkServer::new(
+            path,
+            args,
+            hide_output,
+            timeout_in_millis,
+            bitmap_size,
+            extension,
+            vec![format!("__AFL_CMPLOG_SHM_ID={shm_id}")],
+        );
+        match forksrv {
+            Ok(forksrv) => Ok(CmpLogForkServer { forksrv, cmp_map }),
+            Err(e) => {
+                unsafe {
+                    shmdt(cmp_map.cast::<u8>() as *const libc::c_void);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    pub fn run(&mut self, data: &[u8]) -> Result<(ExitReason, Vec<CmpValues>), SubprocessError> {
+        //Only the headers need to be reset, operands are only read up to the number of hits
+        let cmp_map = unsafe { &mut *self.cmp_map };
+        for i in cmp_map[..CMP_MAP_W * HEADER_SIZE].iter_mut() {
+            *i = 0;
+        }
+        let exitreason = self.forksrv.run(data)?;
+        Ok((exitreason, parse_cmp_map(unsafe { &*self.cmp_map })))
+    }
+}
+
+impl Drop for CmpLogForkServer {
+    fn drop(&mut self) {
+        unsafe {
+            shmdt(self.cmp_map.cast::<u8>() as *const libc::c_void);
+        }
+    }
+}
+
+fn read_u64(map: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&map[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+//Returns every distinct comparison stored in the map
+#[must_use]
+pub fn parse_cmp_map(map: &[u8]) -> Vec<CmpValues> {
+    assert!(map.len() >= CMP_MAP_SIZE, "cmp map too small");
+    let mut res = HashSet::new();
+    for slot in 0..CMP_MAP_W {
+        let header = read_u64(map, slot * HEADER_SIZE);
+        let hits = (header & 0xff_ffff) as usize;
+        if hits == 0 {
+            continue;
+        }
+        let shape = ((header >> 48) & 0x1f) as usize;
+        let cmp_type = (header >> 53) & 0x3;
+        let log = CMP_MAP_W * HEADER_SIZE + slot * CMP_MAP_H * OPERANDS_SIZE;
+        if cmp_type == CMP_TYPE_INS {
+            let size = shape + 1;
+            for i in 0..hits.min(CMP_MAP_H) {
+                let entry = log + i * OPERANDS_SIZE;
+                let mut v0 = u128::from(read_u64(map, entry));
+                let mut v1 = u128::from(read_u64(map, entry + 8));
+                if size > 8 {
+                    v0 |= u128::from(read_u64(map, entry + 16)) << 64;
+                    v1 |= u128::from(read_u64(map, entry + 24)) << 64;
+                }
+                res.insert(CmpValues::Ins { size, v0, v1 });
+            }
+        } else if cmp_type == CMP_TYPE_RTN {
+            for i in 0..hits.min(CMP_MAP_RTN_H) {
+                let entry = log + i * FN_OPERANDS_SIZE;
+                let v0_len = (map[entry + FN_OPERAND_MAX_LEN] as usize).min(FN_OPERAND_MAX_LEN);
+                let v1_start = entry + FN_OPERAND_MAX_LEN + 1;
+                let v1_len = (map[v1_start + FN_OPERAND_MAX_LEN] as usize).min(FN_OPERAND_MAX_LEN);
+                res.insert(CmpValues::Rtn {
+                    v0: map[entry..entry + v0_len].to_vec(),
+                    v1: map[v1_start..v1_start + v1_len].to_vec(),
+                });
+            }
+        }
+    }
+    res.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use cmplog::*;
+
+    fn header(hits: u64, shape: u64, cmp_type: u64) -> [u8; 8] {
+        (hits | (shape << 48) | (cmp_type << 53)).to_le_bytes()
+    }
+
+    #[test]
+    fn parse_instructions_and_functions() {
+        let mut map = vec![0u8; CMP_MAP_SIZE];
+        //Slot 3: a 4 byte integer comparison that was executed twice with the same operands
+        map[3 * HEADER_SIZE..4 * HEADER_SIZE].copy_from_slice(&header(2, 3, CMP_TYPE_INS));
+        let log = CMP_MAP_W * HEADER_SIZE + 3 * CMP_MAP_H * OPERANDS_SIZE;
+        for i in 0..2 {
+            let entry = log + i * OPERANDS_SIZE;
+            map[entry..entry + 8].copy_from_slice(&1337u64.to_le_bytes());
+            map[entry + 8..entry + 16].copy_from_slice(&42u64.to_le_bytes());
+        }
+        //Slot 7: strcmp("abc", "magic")
+        map[7 * HEADER_SIZE..8 * HEADER_SIZE].copy_from_slice(&header(1, 4, CMP_TYPE_RTN));
+        let entry = CMP_MAP_W * HEADER_SIZE + 7 * CMP_MAP_H * OPERANDS_SIZE;
+        map[entry..entry + 3].copy_from_slice(b"abc");
+        map[entry + 31] = 3;
+        map[entry + 32..entry + 37].copy_from_slice(b"magic");
+        map[entry + 63] = 5;
+
+        let mut values = parse_cmp_map(&map);
+        values.sort_by_key(|v| matches!(v, CmpValues::Rtn { .. }));
+        assert_eq!(
+            values,
+            vec![
+                CmpValues::Ins {
+                    size: 4,
+                    v0: 1337,
+                    v1: 42
+                },
+                CmpValues::Rtn {
+                    v0: b"abc".to_vec(),
+                    v1: b"magic".to_vec()
+                },
+            ]
+        );
+    }
+}
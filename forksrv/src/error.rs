@@ -14,70 +14,58 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use snafu::Snafu;
 
-use nix;
-use std;
+use std::path::PathBuf;
 
-quick_error! {
-    #[derive(Debug, Clone)]
-    pub enum SpawnError {
-        Fork(err: nix::Error) {
-            from()
-            description("execve failed")
-            display("execve error: {}", err)
-            cause(err)
-        }
-        Path(desc: String){
-            description("Invalid Path")
-            display("Problem with binary path: {}", desc)
-        }
+//Everything that can go wrong while starting a forkserver
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub))]
+pub enum SpawnError {
+    #[snafu(display("Problem with binary path {}: {}", path.display(), desc))]
+    Path { path: PathBuf, desc: String },
 
-        Exec(desc: String){
-            description("Execution Failed")
-            display("Execution failed: {}", desc)
-        }
+    #[snafu(display("Could not execute {}: {}", path.display(), source))]
+    Exec { path: PathBuf, source: nix::Error },
 
-        FFINull(err: std::ffi::NulError) {
-            from()
-            description("argument/path contained Null byte")
-            display("Null error: {}", err)
-            cause(err)
-        }
-        DevNull(desc: String){
-            description("failed to open /dev/null")
-            display("failed to open /dev/null: {}", desc)
-        }
-    }
-}
+    #[snafu(display("Failed to open /dev/null: {}", source))]
+    DevNull { source: nix::Error },
 
-pub fn path_err<T>(desc: &str) -> Result<T, SpawnError> {
-    return Err(SpawnError::Path(desc.into()));
-}
+    #[snafu(display(
+        "Binary path, argument or environment contains a null byte: {}",
+        source
+    ))]
+    FFINull { source: std::ffi::NulError },
 
-quick_error! {
-    #[derive(Debug)]
-    pub enum SubprocessError {
-        Spawn(err: SpawnError) {
-            from()
-            description("spawning failed")
-            display("spawning failed: {}", err)
-            cause(err)
-        }
-        Unspecific(desc: String){
-            description("Subprocess Failed")
-            display("Subprocess failed: {}", desc)
-        }
-        Io(err: std::io::Error){
-            from()
-            cause(err)
-        }
-        Unix(err: nix::Error){
-            from()
-            cause(err)
-        }
-    }
-}
+    #[snafu(display("Could not create the input file: {}", source))]
+    InputFile { source: std::io::Error },
+
+    #[snafu(display("Could not set up the forkserver ({}): {}", task, source))]
+    Setup { task: String, source: nix::Error },
+
+    #[snafu(display("Shared memory creation failed ({}): {}", task, source))]
+    Shm {
+        task: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display(
+        "Target {} is not instrumented: no forkserver hello within {} ms. Compile it with an AFL compiler (e.g. afl-clang-fast)",
+        path.display(),
+        timeout_in_millis
+    ))]
+    NotInstrumented {
+        path: PathBuf,
+        timeout_in_millis: u64,
+    },
 
-pub fn descr_err<T>(desc: &str) -> Result<T, SubprocessError> {
-    return Err(SubprocessError::Unspecific(desc.into()));
+    #[snafu(display(
+        "Map size mismatch: the target needs a bitmap of {} bytes, but bitmap_size is {}",
+        target_size,
+        bitmap_size
+    ))]
+    MapSizeMismatch {
+        target_size: usize,
+        bitmap_size: usize,
+    },
 }
@@ -19,33 +19,39 @@ extern crate nix;
 extern crate serde;
 extern crate snafu;
 extern crate tempfile;
-extern crate timeout_readwrite;
 
+pub mod cmplog;
+pub mod error;
 pub mod exitreason;
 pub mod newtypes;
 
-use nix::errno::errno;
 use nix::fcntl;
-use nix::libc::{shmat, shmctl, shmget, strerror, IPC_CREAT, IPC_EXCL, IPC_PRIVATE, IPC_RMID};
+use nix::libc::{self, shmat, shmctl, shmdt, shmget, IPC_CREAT, IPC_EXCL, IPC_PRIVATE, IPC_RMID};
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::signal::{self, Signal};
 use nix::sys::stat;
-use nix::sys::wait::WaitStatus;
+use nix::sys::wait::{self, WaitStatus};
 use nix::unistd;
 use nix::unistd::Pid;
 use nix::unistd::{fork, ForkResult};
 use std::ffi::CString;
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::RawFd;
 
-use std::io::BufReader;
+use std::io::{self, Read};
+use std::path::PathBuf;
 use std::ptr;
-use std::time::Duration;
-use timeout_readwrite::TimeoutReader;
+use std::time::{Duration, Instant};
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
 use std::fs::File;
 use std::os::unix::io::FromRawFd;
 
+use error::{
+    DevNullSnafu, FFINullSnafu, InputFileSnafu, MapSizeMismatchSnafu, NotInstrumentedSnafu,
+    PathSnafu, SetupSnafu, ShmSnafu, SpawnError,
+};
 use exitreason::ExitReason;
 use newtypes::{QemuRunIOSnafu, QemuRunNixSnafu, SubprocessError};
 use snafu::ResultExt;
@@ -54,11 +60,20 @@ pub struct ForkServer {
     inp_file: File,
     ctl_in: File,
     shared_data: *mut [u8],
-    st_out: std::io::BufReader<TimeoutReader<File>>,
+    st_out: File,
+    timeout: Duration,
+    //The forkserver leads its own session and process group, every target process inherits them
+    forkserver_pid: Pid,
 }
 
+//AFL++ forkservers may announce options in their hello, one of them is the size of the map
+const FS_OPT_ENABLED: u32 = 0x8000_0001;
+const FS_OPT_MAPSIZE: u32 = 0x4000_0000;
+
+//How long the forkserver may take to start a target or to report the status of a killed one
+const FORKSERVER_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
 impl ForkServer {
-    #[must_use]
     pub fn new(
         path: String,
         args: Vec<String>,
@@ -66,91 +81,188 @@ impl ForkServer {
         timeout_in_millis: u64,
         bitmap_size: usize,
         extension: String,
-    ) -> Self {
+        extra_env: Vec<String>,
+    ) -> Result<Self, SpawnError> {
+        let metadata = std::fs::metadata(&path).map_err(|e| SpawnError::Path {
+            path: PathBuf::from(&path),
+            desc: format!("binary not found ({e})"),
+        })?;
+        if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+            return PathSnafu {
+                path: &path,
+                desc: "not an executable file",
+            }
+            .fail();
+        }
         let inp_file = tempfile::Builder::new()
-            .suffix(&extension.clone())
+            .suffix(&extension)
             .tempfile()
-            .expect("couldn't create temp file");
+            .context(InputFileSnafu)?;
         let (inp_file, in_path) = inp_file
             .keep()
-            .expect("couldn't persists temp file for input");
+            .map_err(|e| SpawnError::InputFile { source: e.error })?;
         let inp_file_path = in_path
             .to_str()
             .expect("temp path should be unicode!")
             .to_string();
-        let args = Some(path.clone())
+        //Everything that can fail is prepared before forking, the child only has to exec
+        let c_path = CString::new(path.clone()).context(FFINullSnafu)?;
+        let c_args = Some(path.clone())
             .into_iter()
-            .chain(args.into_iter())
-            .map(|s| if s == "@@" { inp_file_path.clone() } else { s });
-        let (ctl_out, ctl_in) = nix::unistd::pipe().expect("failed to create ctl_pipe");
-        let (st_out, st_in) = nix::unistd::pipe().expect("failed to create st_pipe");
-        let (shm_file, shared_data) = ForkServer::create_shm(bitmap_size);
+            .chain(args)
+            .map(|s| if s == "@@" { inp_file_path.clone() } else { s })
+            .map(CString::new)
+            .collect::<Result<Vec<_>, _>>()
+            .context(FFINullSnafu)?;
+        let null = if hide_output {
+            Some(
+                fcntl::open(
+                    "/dev/null",
+                    fcntl::OFlag::O_RDWR | fcntl::OFlag::O_CLOEXEC,
+                    stat::Mode::empty(),
+                )
+                .context(DevNullSnafu)?,
+            )
+        } else {
+            None
+        };
+        let (shm_file, shared_data) = ForkServer::create_shm(bitmap_size)?;
+        let shm_id = CString::new(format!("__AFL_SHM_ID={shm_file}")).context(FFINullSnafu)?;
+        //Asan options: set asan SIG to 223 and disable leak detection
+        let asan_settings =
+            CString::new("ASAN_OPTIONS=exitcode=223,abort_on_erro=true,detect_leaks=0,symbolize=0")
+                .expect("RAND_2089158993");
+        //Variables given by the caller, e.g. to make a sanitizer write its reports to a file
+        let mut env = vec![shm_id];
+        for var in extra_env {
+            env.push(CString::new(var).context(FFINullSnafu)?);
+        }
+        if !env
+            .iter()
+            .any(|var| var.as_bytes().starts_with(b"ASAN_OPTIONS="))
+        {
+            env.push(asan_settings);
+        }
 
-        match unsafe { fork() }.expect("couldn't fork") {
+        let (ctl_out, ctl_in) = unistd::pipe().context(SetupSnafu {
+            task: "failed to create ctl_pipe",
+        })?;
+        let (st_out, st_in) = unistd::pipe().context(SetupSnafu {
+            task: "failed to create st_pipe",
+        })?;
+        //Closed on a successful execve, otherwise the child sends the errno of execve through it
+        let (exec_err_out, exec_err_in) =
+            unistd::pipe2(fcntl::OFlag::O_CLOEXEC).context(SetupSnafu {
+                task: "failed to create exec error pipe",
+            })?;
+
+        match unsafe { fork() }.context(SetupSnafu {
+            task: "couldn't fork",
+        })? {
             // Parent returns
-            ForkResult::Parent { child: _, .. } => {
-                unistd::close(ctl_out).expect("coulnd't close ctl_out");
-                unistd::close(st_in).expect("coulnd't close st_out");
-                let mut st_out = BufReader::new(TimeoutReader::new(
-                    unsafe { File::from_raw_fd(st_out) },
-                    Duration::from_millis(timeout_in_millis),
-                ));
-                st_out
-                    .read_u32::<LittleEndian>()
-                    .expect("couldn't read child hello");
-                Self {
+            ForkResult::Parent { child, .. } => {
+                for fd in [ctl_out, st_in, exec_err_in].iter().chain(null.iter()) {
+                    unistd::close(*fd).context(SetupSnafu {
+                        task: "couldn't close the child's end of a pipe",
+                    })?;
+                }
+                let mut exec_err = unsafe { File::from_raw_fd(exec_err_out) };
+                if let Ok(errno) = exec_err.read_i32::<LittleEndian>() {
+                    Self::kill_forkserver(child);
+                    return Err(SpawnError::Exec {
+                        path: PathBuf::from(&path),
+                        source: nix::Error::from_i32(errno),
+                    });
+                }
+                let mut st_out = unsafe { File::from_raw_fd(st_out) };
+                let deadline = Instant::now() + Duration::from_millis(timeout_in_millis);
+                let hello = match read_u32_until(&mut st_out, Some(deadline)) {
+                    Ok(Some(hello)) => hello,
+                    _ => {
+                        Self::kill_forkserver(child);
+                        return NotInstrumentedSnafu {
+                            path: &path,
+                            timeout_in_millis,
+                        }
+                        .fail();
+                    }
+                };
+                if hello & FS_OPT_ENABLED == FS_OPT_ENABLED && hello & FS_OPT_MAPSIZE != 0 {
+                    let target_size = (((hello & 0x00ff_fffe) >> 1) + 1) as usize;
+                    if target_size > bitmap_size {
+                        Self::kill_forkserver(child);
+                        return MapSizeMismatchSnafu {
+                            target_size,
+                            bitmap_size,
+                        }
+                        .fail();
+                    }
+                }
+                Ok(Self {
                     inp_file,
                     ctl_in: unsafe { File::from_raw_fd(ctl_in) },
                     shared_data,
                     st_out,
-                }
+                    timeout: Duration::from_millis(timeout_in_millis),
+                    forkserver_pid: child,
+                })
             }
             //Child does complex stuff
             ForkResult::Child => {
-                let forkserver_fd = 198; // from AFL config.h
-                unistd::dup2(ctl_out, forkserver_fd as RawFd)
-                    .expect("couldn't dup2 ctl_our to FROKSRV_FD");
-                unistd::dup2(st_in, (forkserver_fd + 1) as RawFd)
-                    .expect("couldn't dup2 ctl_our to FROKSRV_FD+1");
-
-                unistd::dup2(inp_file.as_raw_fd(), 0).expect("couldn't dup2 input file to stdin");
-                unistd::close(inp_file.as_raw_fd()).expect("couldn't close input file");
-
-                unistd::close(ctl_in).expect("couldn't close ctl_in");
-                unistd::close(ctl_out).expect("couldn't close ctl_out");
-                unistd::close(st_in).expect("couldn't close ctl_out");
-                unistd::close(st_out).expect("couldn't close ctl_out");
-
-                let path = CString::new(path).expect("binary path must not contain zero");
-                let args = args
-                    .into_iter()
-                    .map(|s| CString::new(s).expect("args must not contain zero"))
-                    .collect::<Vec<_>>();
-
-                let shm_id = CString::new(format!("__AFL_SHM_ID={shm_file}")).unwrap();
-
-                //Asan options: set asan SIG to 223 and disable leak detection
-                let asan_settings = CString::new(
-                    "ASAN_OPTIONS=exitcode=223,abort_on_erro=true,detect_leaks=0,symbolize=0",
-                )
-                .expect("RAND_2089158993");
+                let err = Self::exec_target(
+                    ctl_out, ctl_in, st_out, st_in, &inp_file, null, &c_path, &c_args, &env,
+                );
+                unistd::write(exec_err_in, &(err as i32).to_le_bytes()).ok();
+                unsafe { libc::_exit(1) }
+            }
+        }
+    }
 
-                let env = vec![shm_id, asan_settings];
+    //Runs in the forked child, only returns if something went wrong
+    #[allow(clippy::too_many_arguments)]
+    fn exec_target(
+        ctl_out: RawFd,
+        ctl_in: RawFd,
+        st_out: RawFd,
+        st_in: RawFd,
+        inp_file: &File,
+        null: Option<RawFd>,
+        path: &CString,
+        args: &[CString],
+        env: &[CString],
+    ) -> nix::Error {
+        let res = (|| {
+            //Own session and process group, so that everything the target spawns can be killed
+            unistd::setsid()?;
+            let forkserver_fd = 198; // from AFL config.h
+            unistd::dup2(ctl_out, forkserver_fd as RawFd)?;
+            unistd::dup2(st_in, (forkserver_fd + 1) as RawFd)?;
 
-                if hide_output {
-                    let null = fcntl::open("/dev/null", fcntl::OFlag::O_RDWR, stat::Mode::empty())
-                        .expect("couldn't open /dev/null");
-                    unistd::dup2(null, 1 as RawFd).expect("couldn't dup2 /dev/null to stdout");
-                    unistd::dup2(null, 2 as RawFd).expect("couldn't dup2 /dev/null to stderr");
-                    unistd::close(null).expect("couldn't close /dev/null");
-                }
-                println!("EXECVE {path:?} {args:?} {env:?}");
-                unistd::execve(&path, &args, &env).expect("couldn't execve afl-qemu-tarce");
-                unreachable!();
+            unistd::dup2(inp_file.as_raw_fd(), 0)?;
+            unistd::close(inp_file.as_raw_fd())?;
+
+            unistd::close(ctl_in)?;
+            unistd::close(ctl_out)?;
+            unistd::close(st_in)?;
+            unistd::close(st_out)?;
+
+            if let Some(null) = null {
+                unistd::dup2(null, 1 as RawFd)?;
+                unistd::dup2(null, 2 as RawFd)?;
             }
+            unistd::execve(path, args, env)
+        })();
+        match res {
+            Ok(never) => match never {},
+            Err(e) => e,
         }
     }
 
+    fn kill_forkserver(pid: Pid) {
+        signal::kill(pid, Signal::SIGKILL).ok();
+        wait::waitpid(pid, None).ok();
+    }
+
     pub fn run(&mut self, data: &[u8]) -> Result<ExitReason, SubprocessError> {
         for i in self.get_shared_mut().iter_mut() {
             *i = 0;
@@ -176,28 +288,47 @@ impl ForkServer {
             task: "Couldn't send start command",
         })?;
 
-        let pid = Pid::from_raw(self.st_out.read_i32::<LittleEndian>().context(
-            QemuRunIOSnafu {
-                task: "Couldn't read target pid",
-            },
-        )?);
+        let pid = Pid::from_raw(self.read_response("Couldn't read target pid")? as i32);
 
-        if let Ok(status) = self.st_out.read_i32::<LittleEndian>() {
+        let deadline = Instant::now() + self.timeout;
+        if let Some(status) =
+            read_u32_until(&mut self.st_out, Some(deadline)).context(QemuRunIOSnafu {
+                task: "Couldn't read exit status",
+            })?
+        {
+            //Targets that crash or exit may leave processes behind as well
+            kill_session(self.forkserver_pid);
             return Ok(ExitReason::from_wait_status(
-                WaitStatus::from_raw(pid, status).expect("402104968"),
+                WaitStatus::from_raw(pid, status as i32).context(QemuRunNixSnafu {
+                    task: "Couldn't decode wait status",
+                })?,
             ));
         }
         signal::kill(pid, Signal::SIGKILL).context(QemuRunNixSnafu {
             task: "Couldn't kill timed out process",
         })?;
-        self.st_out
-            .read_u32::<LittleEndian>()
-            .context(QemuRunIOSnafu {
-                task: "couldn't read timeout exitcode",
-            })?;
+        kill_session(self.forkserver_pid);
+        self.read_response("couldn't read timeout exitcode")?;
         Ok(ExitReason::Timeouted)
     }
 
+    //Reads a message the forkserver sends right away. If it doesn't, it is stuck and treated
+    //like a dead one.
+    fn read_response(&mut self, task: &str) -> Result<u32, SubprocessError> {
+        let deadline = Instant::now() + FORKSERVER_RESPONSE_TIMEOUT;
+        read_u32_until(&mut self.st_out, Some(deadline))
+            .and_then(|value| {
+                value.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::TimedOut, "the forkserver did not respond")
+                })
+            })
+            .context(QemuRunIOSnafu { task })
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
     pub fn get_shared_mut(&mut self) -> &mut [u8] {
         unsafe { &mut *self.shared_data }
     }
@@ -206,40 +337,146 @@ impl ForkServer {
         unsafe { &*self.shared_data }
     }
 
-    fn create_shm(bitmap_size: usize) -> (i32, *mut [u8]) {
+    pub(crate) fn create_shm(bitmap_size: usize) -> Result<(i32, *mut [u8]), SpawnError> {
         unsafe {
             let shm_id = shmget(IPC_PRIVATE, bitmap_size, IPC_CREAT | IPC_EXCL | 0o600);
-            assert!(
-                shm_id >= 0,
-                "shm_id {:?}",
-                CString::from_raw(strerror(errno()))
-            );
+            if shm_id < 0 {
+                return Err(std::io::Error::last_os_error()).context(ShmSnafu { task: "shmget" });
+            }
 
             let trace_bits = shmat(shm_id, ptr::null(), 0);
-            assert!(
-                (trace_bits as isize) >= 0,
-                "shmat {:?}",
-                CString::from_raw(strerror(errno()))
-            );
+            if trace_bits as isize == -1 {
+                let err = std::io::Error::last_os_error();
+                shmctl(
+                    shm_id,
+                    IPC_RMID,
+                    std::ptr::null_mut::<nix::libc::shmid_ds>(),
+                );
+                return Err(err).context(ShmSnafu { task: "shmat" });
+            }
 
+            //Marked for removal right away, it is freed as soon as both processes detached
             let res = shmctl(
                 shm_id,
                 IPC_RMID,
                 std::ptr::null_mut::<nix::libc::shmid_ds>(),
             );
-            assert!(
-                res >= 0,
-                "shmclt {:?}",
-                CString::from_raw(strerror(errno()))
-            );
-            (shm_id, trace_bits.cast::<[u8; 65536]>())
+            if res < 0 {
+                return Err(std::io::Error::last_os_error()).context(ShmSnafu { task: "shmctl" });
+            }
+            Ok((
+                shm_id,
+                ptr::slice_from_raw_parts_mut(trace_bits.cast::<u8>(), bitmap_size),
+            ))
+        }
+    }
+}
+
+impl Drop for ForkServer {
+    fn drop(&mut self) {
+        signal::killpg(self.forkserver_pid, Signal::SIGKILL).ok();
+        wait::waitpid(self.forkserver_pid, None).ok();
+        kill_session(self.forkserver_pid);
+        unsafe {
+            shmdt(self.shared_data.cast::<u8>() as *const libc::c_void);
+        }
+    }
+}
+
+//Processes started by the target are still in the session of the forkserver, even the ones that
+//were reparented to init or moved to a process group of their own. Kills all of them except for
+//the forkserver itself, and scans again until none are left, in case one forked during the scan.
+fn kill_session(forkserver_pid: Pid) {
+    //Killed processes may take a moment to become zombies, so give up eventually
+    for _ in 0..100 {
+        let leftovers = session_processes(forkserver_pid);
+        if leftovers.is_empty() {
+            return;
+        }
+        for pid in leftovers {
+            signal::kill(pid, Signal::SIGKILL).ok();
+        }
+    }
+}
+
+//Living processes in the session or process group of the forkserver, without the forkserver. This
+//runs after every execution, so only the processes that match are read from /proc.
+fn session_processes(forkserver_pid: Pid) -> Vec<Pid> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return vec![];
+    };
+    let mut res = vec![];
+    for entry in entries.flatten() {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<i32>().ok())
+            .map(Pid::from_raw)
+        else {
+            continue;
+        };
+        if pid == forkserver_pid
+            || (unistd::getsid(Some(pid)) != Ok(forkserver_pid)
+                && unistd::getpgid(Some(pid)) != Ok(forkserver_pid))
+        {
+            continue;
+        }
+        //Zombies are gone already, their parent only has to reap them
+        let Ok(stat) = std::fs::read_to_string(format!("/proc/{pid}/stat")) else {
+            continue;
+        };
+        //The command name may contain spaces, the state is the field after it
+        let state = stat
+            .rsplit_once(')')
+            .and_then(|(_, fields)| fields.split_whitespace().next());
+        if !matches!(state, Some("Z" | "X") | None) {
+            res.push(pid);
         }
     }
+    res
+}
+
+//Reads a little endian u32 from the pipe. Returns None if the deadline passed before all four
+//bytes arrived, and an error if the other end was closed.
+fn read_u32_until(pipe: &mut File, deadline: Option<Instant>) -> io::Result<Option<u32>> {
+    let mut buf = [0u8; 4];
+    let mut filled = 0;
+    while filled < buf.len() {
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            //Round up, a timeout of 0 would make poll return immediately
+            let remaining = (deadline - now).as_micros().div_ceil(1000);
+            let mut fds = [PollFd::new(pipe.as_raw_fd(), PollFlags::POLLIN)];
+            match poll(&mut fds, remaining.min(i32::MAX as u128) as i32) {
+                Ok(0) | Err(nix::errno::Errno::EINTR) => continue,
+                Ok(_) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        match pipe.read(&mut buf[filled..]) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Some(LittleEndian::read_u32(&buf)))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{exitreason, ForkServer};
+    use crate::error::SpawnError;
+    use crate::{exitreason, kill_session, session_processes, ForkServer};
+    use nix::unistd::{self, Pid};
+    use std::io;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+    use std::thread;
+    use std::time::Duration;
+
     #[test]
     fn run_forkserver() {
         let hide_output = false;
@@ -247,7 +484,16 @@ mod tests {
         let bitmap_size = 1 << 16;
         let target = "../test".to_string();
         let args = vec![];
-        let mut fork = ForkServer::new(target, args, hide_output, timeout_in_millis, bitmap_size);
+        let mut fork = ForkServer::new(
+            target,
+            args,
+            hide_output,
+            timeout_in_millis,
+            bitmap_size,
+            String::new(),
+            vec![],
+        )
+        .unwrap();
         assert!(fork.get_shared()[1..].iter().all(|v| *v == 0));
         assert_eq!(
             fork.run(b"deadbeeg").unwrap(),
@@ -259,4 +505,55 @@ mod tests {
         );
         assert!(fork.get_shared()[1..].iter().any(|v| *v != 0));
     }
+
+    #[test]
+    fn missing_binary() {
+        let res = ForkServer::new(
+            "/does/not/exist".to_string(),
+            vec![],
+            true,
+            200,
+            1 << 16,
+            String::new(),
+            vec![],
+        );
+        assert!(matches!(res, Err(SpawnError::Path { .. })));
+    }
+
+    #[test]
+    fn uninstrumented_binary() {
+        let res = ForkServer::new(
+            "/bin/true".to_string(),
+            vec![],
+            true,
+            200,
+            1 << 16,
+            String::new(),
+            vec![],
+        );
+        assert!(matches!(res, Err(SpawnError::NotInstrumented { .. })));
+    }
+
+    #[test]
+    fn kills_session() {
+        //The subshell forks sleep and exits, so that sleep is reparented to init
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("(sleep 100 &); sh -c 'sleep 100; exit' & sleep 100 & wait; exit 3");
+        //Like the forkserver, the leader gets a session of its own
+        unsafe {
+            command.pre_exec(|| unistd::setsid().map(|_| ()).map_err(io::Error::from));
+        }
+        let mut leader = command.spawn().expect("RAND_2086164713");
+        thread::sleep(Duration::from_millis(200));
+        let pid = Pid::from_raw(leader.id() as i32);
+        //The orphaned sleep, sh, its sleep and the other sleep
+        assert_eq!(session_processes(pid).len(), 4);
+        kill_session(pid);
+        assert!(session_processes(pid).is_empty());
+        //The leader itself is left alone, it returns from wait once its children are dead
+        let status = leader.wait().expect("RAND_2086164714");
+        assert_eq!(status.code(), Some(3));
+    }
 }
@@ -14,6 +14,7 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use grammartec::size_distribution::SizeDistribution;
 use serde::Deserialize;
 
 #[derive(Deserialize, Clone)]
@@ -22,13 +23,89 @@ pub struct Config {
     pub thread_size: usize,
     pub number_of_generate_inputs: u16,
     pub number_of_deterministic_mutations: usize,
+    //Byte level mutations of the unparsed input per queue entry and round, 0 disables the stage
+    #[serde(default)]
+    pub number_of_raw_mutations: usize,
     pub max_tree_size: usize,
+    //Distribution of the sizes of generated trees and subtrees, between the minimal size and
+    //max_tree_size
+    #[serde(default)]
+    pub size_distribution: SizeDistribution,
     pub bitmap_size: usize,
+    //Memory for the hashes of inputs that were executed already, shared by all threads
+    #[serde(default = "default_dedup_cache_size_in_mb")]
+    pub dedup_cache_size_in_mb: usize,
     pub timeout_in_millis: u64,
+    //Timeouts are only saved if the input also hangs with this timeout. 0 (the default) uses five
+    //times timeout_in_millis, see hang_timeout_in_millis()
+    #[serde(default)]
+    pub hang_timeout_in_millis: u64,
+    //timeout_in_millis is lowered after this many queue entries were measured, 0 keeps it
+    #[serde(default = "default_calibration_inputs")]
+    pub calibration_inputs: usize,
+    //Fuzzing stops if the target dies this often in a row
+    #[serde(default = "default_max_consecutive_restarts")]
+    pub max_consecutive_restarts: u32,
     pub path_to_bin_target: String,
+    //Sanitizer build of the same target, inputs with new coverage are re-executed on it
+    #[serde(default)]
+    pub path_to_sanitizer_bin: Option<String>,
+    //CmpLog build of the same target (AFL_LLVM_CMPLOG=1), enables the input-to-state stage
+    #[serde(default)]
+    pub path_to_cmplog_bin: Option<String>,
+    //Add replacements found by the input-to-state stage to the grammar as new terminals
+    #[serde(default)]
+    pub cmplog_learn_terms: bool,
     pub path_to_grammar: String,
+    //AFL dictionaries, loaded in addition to the ones the grammar adds
+    #[serde(default)]
+    pub dictionaries: Vec<Dictionary>,
+    //Directory with trees (e.g. outputs/trees of an earlier campaign) to learn rule weights from.
+    //The learned weights are saved to $workdir/rule_weights.ron.
+    #[serde(default)]
+    pub learn_rule_weights_from: Option<String>,
+    //Rule weights saved by an earlier campaign or the generator
+    #[serde(default)]
+    pub rule_weights: Option<String>,
+    //Prefer the rules that were rare in the corpus instead of the common ones
+    #[serde(default)]
+    pub invert_rule_weights: bool,
+    //Protobuf grammars: also generate wrong length prefixes to test the parser's framing checks
+    #[serde(default)]
+    pub break_protobuf_framing: bool,
     pub path_to_workdir: String,
     pub arguments: Vec<String>,
     pub hide_output: bool,
     pub extension: String,
 }
+
+impl Config {
+    pub fn hang_timeout_in_millis(&self) -> u64 {
+        if self.hang_timeout_in_millis == 0 {
+            self.timeout_in_millis.saturating_mul(5)
+        } else {
+            self.hang_timeout_in_millis
+        }
+    }
+}
+
+fn default_calibration_inputs() -> usize {
+    20
+}
+
+fn default_dedup_cache_size_in_mb() -> usize {
+    64
+}
+
+fn default_max_consecutive_restarts() -> u32 {
+    10
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Dictionary {
+    pub path: String,
+    //The tokens become terminal alternatives of this nonterminal. Without one they are only
+    //used by the dictionary insertion stage.
+    #[serde(default)]
+    pub nonterminal: Option<String>,
+}
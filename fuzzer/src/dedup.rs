@@ -0,0 +1,88 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use fuzzer::ExecutionReason;
+
+//Number of slots that are searched for a hash before one of them is evicted
+const WAYS: usize = 4;
+
+//Lossy set of hashes of recently executed inputs that is shared by all fuzzing threads. The
+//memory used is fixed at construction time: when a bucket is full, a pseudo random slot of it is
+//overwritten. Lookups and insertions are lock free, two threads racing on the same input may both
+//execute it, which is harmless.
+pub struct DedupCache {
+    slots: Vec<AtomicU64>,
+    lookups: Vec<AtomicU64>,
+    duplicates: Vec<AtomicU64>,
+}
+
+impl DedupCache {
+    pub fn new(size_in_mb: usize) -> Self {
+        let number_of_slots =
+            (size_in_mb * 1024 * 1024 / std::mem::size_of::<AtomicU64>()).max(WAYS);
+        let number_of_slots = number_of_slots - (number_of_slots % WAYS);
+        DedupCache {
+            slots: (0..number_of_slots).map(|_| AtomicU64::new(0)).collect(),
+            lookups: (0..ExecutionReason::ALL.len())
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            duplicates: (0..ExecutionReason::ALL.len())
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+
+    fn hash(code: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(code);
+        //0 marks an empty slot
+        hasher.finish().max(1)
+    }
+
+    //Returns true if the input was seen recently, otherwise it is remembered and false is returned
+    pub fn input_is_known(&self, code: &[u8], exec_reason: ExecutionReason) -> bool {
+        let hash = Self::hash(code);
+        let number_of_buckets = self.slots.len() / WAYS;
+        let bucket = ((hash as usize) % number_of_buckets) * WAYS;
+        let bucket = &self.slots[bucket..bucket + WAYS];
+        self.lookups[exec_reason as usize].fetch_add(1, Ordering::Relaxed);
+        if bucket
+            .iter()
+            .any(|slot| slot.load(Ordering::Relaxed) == hash)
+        {
+            self.duplicates[exec_reason as usize].fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        let victim = bucket
+            .iter()
+            .find(|slot| slot.load(Ordering::Relaxed) == 0)
+            .unwrap_or(&bucket[(hash >> 60) as usize % WAYS]);
+        victim.store(hash, Ordering::Relaxed);
+        false
+    }
+
+    //Returns (number of lookups, number of duplicates) for the given mutation stage
+    pub fn stats(&self, exec_reason: ExecutionReason) -> (u64, u64) {
+        (
+            self.lookups[exec_reason as usize].load(Ordering::Relaxed),
+            self.duplicates[exec_reason as usize].load(Ordering::Relaxed),
+        )
+    }
+}
@@ -14,21 +14,31 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::HashMap;
 use std::collections::HashSet;
-use std::collections::VecDeque;
+use std::fs;
 use std::fs::File;
 use std::io::stdout;
 use std::io::Write;
-use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use chrono::Local;
+use config::Config;
+use dedup::DedupCache;
+use forksrv::cmplog::{CmpLogForkServer, CmpValues};
+use forksrv::error::SpawnError;
 use forksrv::exitreason::ExitReason;
 use forksrv::newtypes::SubprocessError;
 use forksrv::ForkServer;
 use grammartec::context::Context;
-use grammartec::tree::TreeLike;
+use grammartec::newtypes::NodeID;
+use grammartec::parser;
+use grammartec::size_distribution::SizeStats;
+use grammartec::tree::{Tree, TreeLike};
 use shared_state::GlobalSharedState;
 
 #[derive(Debug, Clone, Copy)]
@@ -40,15 +50,45 @@ pub enum ExecutionReason {
     Splice,
     Det,
     Gen,
+    CmpLog,
+    Dict,
+    LeafHavoc,
+    Raw,
+}
+
+impl ExecutionReason {
+    pub const ALL: [ExecutionReason; 11] = [
+        ExecutionReason::Gen,
+        ExecutionReason::Min,
+        ExecutionReason::MinRec,
+        ExecutionReason::Det,
+        ExecutionReason::Splice,
+        ExecutionReason::Havoc,
+        ExecutionReason::HavocRec,
+        ExecutionReason::CmpLog,
+        ExecutionReason::Dict,
+        ExecutionReason::LeafHavoc,
+        ExecutionReason::Raw,
+    ];
 }
 
 pub struct Fuzzer {
     forksrv: ForkServer,
-    last_tried_inputs: HashSet<Vec<u8>>,
-    last_inputs_ring_buffer: VecDeque<Vec<u8>>,
-    pub global_state: Arc<Mutex<GlobalSharedState>>,
-    pub target_path: String,
-    pub target_args: Vec<String>,
+    //Optional sanitizer build of the target, inputs with new coverage are run on it as well
+    sanitizer_forksrv: Option<ForkServer>,
+    sanitizer_log_dir: String,
+    //Optional CmpLog build of the target, used to learn the operands of comparisons
+    cmplog_forksrv: Option<CmpLogForkServer>,
+    dedup: Arc<DedupCache>,
+    //Per thread copy of the global bitmaps: 0xff for bits this thread has never seen set, 0
+    //otherwise. Only runs that hit bits that are still virgin here consult the global bitmaps.
+    virgin_bits: HashMap<bool, Vec<u8>>,
+    virgin_timeout_bits: Vec<u8>,
+    pub global_state: Arc<GlobalSharedState>,
+    //Everything needed to start the target again in exactly the same way after it died
+    config: Config,
+    //Timeout the forkserver currently uses, follows the calibrated one in the global state
+    timeout_in_millis: u64,
     pub execution_count: u64,
     pub average_executions_per_sec: f32,
     pub bits_found_by_havoc: u64,
@@ -59,6 +99,12 @@ pub struct Fuzzer {
     pub bits_found_by_det: u64,
     pub bits_found_by_det_afl: u64,
     pub bits_found_by_gen: u64,
+    pub bits_found_by_cmplog: u64,
+    pub bits_found_by_dict: u64,
+    pub bits_found_by_leaf_havoc: u64,
+    pub bits_found_by_raw: u64,
+    //Sizes of the trees executed since the last merge into the global stats
+    pub tree_sizes: SizeStats,
     pub asan_found_by_havoc: u64,
     pub asan_found_by_havoc_rec: u64,
     pub asan_found_by_min: u64,
@@ -73,30 +119,33 @@ pub struct Fuzzer {
 
 impl Fuzzer {
     pub fn new(
-        path: String,
-        args: Vec<String>,
-        global_state: Arc<Mutex<GlobalSharedState>>,
-        work_dir: String,
-        hide_output: bool,
-        timeout_in_millis: u64,
-        bitmap_size: usize,
-        extension: String,
-    ) -> Self {
-        let fs = ForkServer::new(
-            path.clone(),
-            args.clone(),
-            hide_output,
-            timeout_in_millis,
-            bitmap_size,
-            extension.clone(),
+        config: &Config,
+        global_state: Arc<GlobalSharedState>,
+        dedup: Arc<DedupCache>,
+    ) -> Result<Self, SpawnError> {
+        let fs = Self::spawn_forkserver(config)?;
+        let sanitizer_log_dir = format!(
+            "{}/outputs/sanitizer_logs/{}",
+            config.path_to_workdir,
+            thread::current().name().unwrap_or("unnamed")
         );
-        Fuzzer {
+        let sanitizer_fs = Self::spawn_sanitizer_forkserver(config, &sanitizer_log_dir)?;
+        let cmplog_fs = Self::spawn_cmplog_forkserver(config)?;
+        let bitmap_size = config.bitmap_size;
+        let mut virgin_bits = HashMap::new();
+        virgin_bits.insert(false, vec![0xff; bitmap_size]);
+        virgin_bits.insert(true, vec![0xff; bitmap_size]);
+        Ok(Fuzzer {
             forksrv: fs,
-            last_tried_inputs: HashSet::new(),
-            last_inputs_ring_buffer: VecDeque::new(),
+            sanitizer_forksrv: sanitizer_fs,
+            sanitizer_log_dir,
+            cmplog_forksrv: cmplog_fs,
+            dedup,
+            virgin_bits,
+            virgin_timeout_bits: vec![0xff; bitmap_size],
             global_state,
-            target_path: path,
-            target_args: args,
+            config: config.clone(),
+            timeout_in_millis: config.timeout_in_millis,
             execution_count: 0,
             average_executions_per_sec: 0.0,
             bits_found_by_havoc: 0,
@@ -107,6 +156,11 @@ impl Fuzzer {
             bits_found_by_det: 0,
             bits_found_by_det_afl: 0,
             bits_found_by_gen: 0,
+            bits_found_by_cmplog: 0,
+            bits_found_by_dict: 0,
+            bits_found_by_leaf_havoc: 0,
+            bits_found_by_raw: 0,
+            tree_sizes: SizeStats::new(),
             asan_found_by_havoc: 0,
             asan_found_by_havoc_rec: 0,
             asan_found_by_min: 0,
@@ -115,9 +169,76 @@ impl Fuzzer {
             asan_found_by_det: 0,
             asan_found_by_det_afl: 0,
             asan_found_by_gen: 0,
-            work_dir,
-            extension,
-        }
+            work_dir: config.path_to_workdir.clone(),
+            extension: config.extension.clone(),
+        })
+    }
+
+    fn spawn_forkserver(config: &Config) -> Result<ForkServer, SpawnError> {
+        ForkServer::new(
+            config.path_to_bin_target.clone(),
+            config.arguments.clone(),
+            config.hide_output,
+            config.timeout_in_millis,
+            config.bitmap_size,
+            config.extension.clone(),
+            vec![],
+        )
+    }
+
+    fn spawn_sanitizer_forkserver(
+        config: &Config,
+        log_dir: &str,
+    ) -> Result<Option<ForkServer>, SpawnError> {
+        let Some(path) = &config.path_to_sanitizer_bin else {
+            return Ok(None);
+        };
+        fs::create_dir_all(log_dir).map_err(|e| SpawnError::Path {
+            path: PathBuf::from(log_dir),
+            desc: format!("could not create sanitizer log directory ({e})"),
+        })?;
+        //Reports are written to log_dir/report.<pid> and attached to the crash
+        let log_path = format!("log_path={log_dir}/report");
+        ForkServer::new(
+            path.clone(),
+            config.arguments.clone(),
+            config.hide_output,
+            config.hang_timeout_in_millis(),
+            config.bitmap_size,
+            config.extension.clone(),
+            vec![
+                format!("ASAN_OPTIONS=exitcode=223,detect_leaks=0,symbolize=1,{log_path}"),
+                format!("UBSAN_OPTIONS=halt_on_error=1,exitcode=223,{log_path}"),
+                format!("MSAN_OPTIONS=exitcode=223,{log_path}"),
+            ],
+        )
+        .map(Some)
+    }
+
+    fn spawn_cmplog_forkserver(config: &Config) -> Result<Option<CmpLogForkServer>, SpawnError> {
+        let Some(path) = &config.path_to_cmplog_bin else {
+            return Ok(None);
+        };
+        CmpLogForkServer::new(
+            path.clone(),
+            config.arguments.clone(),
+            config.hide_output,
+            config.hang_timeout_in_millis(),
+            config.bitmap_size,
+            config.extension.clone(),
+        )
+        .map(Some)
+    }
+
+    //Replaces a dead forkserver with a new one for the same target, arguments and settings.
+    //Counters and the per thread coverage information are kept.
+    pub fn restart(&mut self) -> Result<(), SpawnError> {
+        self.forksrv = Self::spawn_forkserver(&self.config)?;
+        self.sanitizer_forksrv =
+            Self::spawn_sanitizer_forkserver(&self.config, &self.sanitizer_log_dir)?;
+        self.cmplog_forksrv = Self::spawn_cmplog_forkserver(&self.config)?;
+        self.timeout_in_millis = self.config.timeout_in_millis;
+        Ok(())
     }
 
     pub fn run_on_with_dedup<T: TreeLike>(
@@ -127,9 +248,10 @@ impl Fuzzer {
         ctx: &Context,
     ) -> Result<bool, SubprocessError> {
         let code: Vec<u8> = tree.unparse_to_vec(ctx);
-        if self.input_is_known(&code) {
+        if self.dedup.input_is_known(&code, exec_reason) {
             return Ok(false);
         }
+        self.tree_sizes.add(tree.size());
         self.run_on(&code, tree, exec_reason, ctx)?;
         Ok(true)
     }
@@ -156,14 +278,12 @@ impl Fuzzer {
             ExitReason::Normal(223) => {
                 if new_bits.is_some() {
                     //ASAN
-                    self.global_state
-                        .lock()
-                        .expect("RAND_3390206382")
-                        .total_found_asan += 1;
-                    self.global_state
-                        .lock()
-                        .expect("RAND_202860771")
-                        .last_found_asan = Local::now().format("[%Y-%m-%d] %H:%M:%S").to_string();
+                    {
+                        let mut stats = self.global_state.stats.lock().expect("RAND_3390206382");
+                        stats.total_found_asan += 1;
+                        stats.last_found_asan =
+                            Local::now().format("[%Y-%m-%d] %H:%M:%S").to_string();
+                    }
                     let mut file = File::create(format!(
                         "{}/outputs/signaled/ASAN_{:09}_{}{}",
                         self.work_dir,
@@ -199,31 +319,46 @@ impl Fuzzer {
                         ExecutionReason::Gen => {
                             self.bits_found_by_gen += 1; /*print!("Gen+")*/
                         }
+                        ExecutionReason::CmpLog => {
+                            self.bits_found_by_cmplog += 1;
+                        }
+                        ExecutionReason::Dict => {
+                            self.bits_found_by_dict += 1;
+                        }
+                        ExecutionReason::LeafHavoc => {
+                            self.bits_found_by_leaf_havoc += 1;
+                        }
+                        ExecutionReason::Raw => {
+                            self.bits_found_by_raw += 1;
+                        }
                     }
                 }
             }
             ExitReason::Timeouted => {
-                self.global_state
-                    .lock()
-                    .expect("RAND_1706238230")
-                    .last_timeout = Local::now().format("[%Y-%m-%d] %H:%M:%S").to_string();
-                let mut file = File::create(format!(
-                    "{}/outputs/timeout/{:09}{}",
-                    self.work_dir, self.execution_count, self.extension,
-                ))
-                .expect("RAND_452993103");
-                tree.unparse_to(ctx, &mut file);
+                //Most timeouts are just slow inputs. Only keep inputs that still hang with the
+                //longer timeout and reach code no earlier hang reached.
+                if self.confirm_hang(code)? && self.new_timeout_bits().is_some() {
+                    {
+                        let mut stats = self.global_state.stats.lock().expect("RAND_1706238230");
+                        stats.total_found_timeouts += 1;
+                        stats.last_timeout = Local::now().format("[%Y-%m-%d] %H:%M:%S").to_string();
+                    }
+                    let mut file = File::create(format!(
+                        "{}/outputs/timeout/{:09}{}",
+                        self.work_dir, self.execution_count, self.extension,
+                    ))
+                    .expect("RAND_452993103");
+                    tree.unparse_to(ctx, &mut file);
+                }
             }
             ExitReason::Signaled(sig) => {
                 if new_bits.is_some() {
-                    self.global_state
-                        .lock()
-                        .expect("RAND_1858328446")
-                        .total_found_sig += 1;
-                    self.global_state
-                        .lock()
-                        .expect("RAND_4287051369")
-                        .last_found_sig = Local::now().format("[%Y-%m-%d] %H:%M:%S").to_string();
+                    {
+                        let mut stats = self.global_state.stats.lock().expect("RAND_1858328446");
+                        stats.total_found_sig += 1;
+                        stats.last_found_sig =
+                            Local::now().format("[%Y-%m-%d] %H:%M:%S").to_string();
+                    }
                     let mut file = File::create(format!(
                         "{}/outputs/signaled/{sig:?}_{:09}{}",
                         self.work_dir, self.execution_count, self.extension,
@@ -257,39 +392,43 @@ impl Fuzzer {
         Ok(found_all)
     }
 
-    pub fn exec_raw(&mut self, code: &[u8]) -> Result<(ExitReason, u32), SubprocessError> {
+    pub fn exec_raw(&mut self, code: &[u8]) -> Result<(ExitReason, Duration), SubprocessError> {
         self.execution_count += 1;
 
+        let timeout_in_millis = self.global_state.timeout_in_millis.load(Ordering::Relaxed);
+        if timeout_in_millis != self.timeout_in_millis {
+            self.forksrv
+                .set_timeout(Duration::from_millis(timeout_in_millis));
+            self.timeout_in_millis = timeout_in_millis;
+        }
+
         let start = Instant::now();
 
-        let exitreason = self.forksrv.run(code)?;
+        let exitreason = match self.forksrv.run(code) {
+            Ok(exitreason) => exitreason,
+            Err(e) => {
+                //Keep the input that killed the forkserver around, it is likely to be interesting
+                if let Ok(mut file) = File::create(format!(
+                    "{}/outputs/restarts/{:09}_{}{}",
+                    self.work_dir,
+                    self.execution_count,
+                    thread::current().name().unwrap_or("unnamed"),
+                    self.extension,
+                )) {
+                    file.write_all(code).ok();
+                }
+                return Err(e);
+            }
+        };
 
-        let execution_time = start.elapsed().subsec_nanos();
+        let execution_time = start.elapsed();
 
         self.average_executions_per_sec = self.average_executions_per_sec * 0.9
-            + ((1.0 / (execution_time as f32)) * 1_000_000_000.0) * 0.1;
+            + (1.0 / execution_time.as_secs_f32().max(f32::EPSILON)) * 0.1;
 
         Ok((exitreason, execution_time))
     }
 
-    fn input_is_known(&mut self, code: &[u8]) -> bool {
-        if self.last_tried_inputs.contains(code) {
-            true
-        } else {
-            self.last_tried_inputs.insert(code.to_vec());
-            if self.last_inputs_ring_buffer.len() == 10000 {
-                self.last_tried_inputs.remove(
-                    &self
-                        .last_inputs_ring_buffer
-                        .pop_back()
-                        .expect("No entry in last_inputs_ringbuffer"),
-                );
-            }
-            self.last_inputs_ring_buffer.push_front(code.to_vec());
-            false
-        }
-    }
-
     fn exec<T: TreeLike>(
         &mut self,
         code: &[u8],
@@ -312,12 +451,20 @@ impl Fuzzer {
                 self.check_deterministic_behaviour(&old_bitmap, &mut new_bits, code)?;
                 if !new_bits.is_empty() {
                     final_bits = Some(new_bits);
-                    let tree = tree_like.to_tree(ctx);
+                    let tree = reparse_opaque(tree_like.to_tree(ctx), ctx);
                     self.global_state
+                        .queue
                         .lock()
                         .expect("RAND_2835014626")
-                        .queue
                         .add(tree, old_bitmap, exitreason, ctx, execution_time);
+                    self.global_state.add_calibration_sample(
+                        execution_time,
+                        self.config.calibration_inputs,
+                        self.config.timeout_in_millis,
+                    );
+                    if !is_crash {
+                        self.run_on_sanitizer(code, tree_like, ctx)?;
+                    }
                     //println!("Entry added to queue! New bits: {:?}", bits.clone().expect("RAND_2243482569"));
                 }
             }
@@ -325,6 +472,72 @@ impl Fuzzer {
         Ok((final_bits, exitreason))
     }
 
+    pub fn has_cmplog(&self) -> bool {
+        self.cmplog_forksrv.is_some()
+    }
+
+    //Runs the input on the CmpLog build and returns the operands of the comparisons it executed
+    pub fn cmplog_values(&mut self, code: &[u8]) -> Result<Vec<CmpValues>, SubprocessError> {
+        match self.cmplog_forksrv.as_mut() {
+            Some(cmplog_forksrv) => {
+                self.execution_count += 1;
+                Ok(cmplog_forksrv.run(code)?.1)
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    //Runs an input that found new coverage on the sanitizer build and reports it as a crash if
+    //the sanitizer detected a problem the plain build missed
+    fn run_on_sanitizer<T: TreeLike>(
+        &mut self,
+        code: &[u8],
+        tree: &T,
+        ctx: &Context,
+    ) -> Result<(), SubprocessError> {
+        let Some(sanitizer_forksrv) = self.sanitizer_forksrv.as_mut() else {
+            return Ok(());
+        };
+        let exitreason = sanitizer_forksrv.run(code)?;
+        let report = self.take_sanitizer_report();
+        if !matches!(
+            exitreason,
+            ExitReason::Normal(223) | ExitReason::Signaled(_)
+        ) {
+            return Ok(());
+        }
+        {
+            let mut stats = self.global_state.stats.lock().expect("RAND_4097728113");
+            stats.total_found_sanitizer += 1;
+            stats.last_found_sanitizer = Local::now().format("[%Y-%m-%d] %H:%M:%S").to_string();
+        }
+        let name = format!(
+            "{}/outputs/signaled/SAN_{:09}_{}",
+            self.work_dir,
+            self.execution_count,
+            thread::current().name().expect("RAND_2525287467")
+        );
+        let mut file = File::create(format!("{name}{}", self.extension)).expect("RAND_1636931542");
+        tree.unparse_to(ctx, &mut file);
+        fs::write(format!("{name}.report"), report).expect("RAND_3232862457");
+        Ok(())
+    }
+
+    //Collects and removes the reports the sanitizer wrote since the last call
+    fn take_sanitizer_report(&self) -> String {
+        let mut report = String::new();
+        let Ok(entries) = fs::read_dir(&self.sanitizer_log_dir) else {
+            return report;
+        };
+        for entry in entries.flatten() {
+            if let Ok(content) = fs::read_to_string(entry.path()) {
+                report.push_str(&content);
+            }
+            fs::remove_file(entry.path()).ok();
+        }
+        report
+    }
+
     fn check_deterministic_behaviour(
         &mut self,
         old_bitmap: &[u8],
@@ -344,20 +557,62 @@ impl Fuzzer {
         Ok(())
     }
 
+    //Runs the input again with the hang timeout, returns true if it still times out
+    fn confirm_hang(&mut self, code: &[u8]) -> Result<bool, SubprocessError> {
+        self.forksrv
+            .set_timeout(Duration::from_millis(self.config.hang_timeout_in_millis()));
+        let exitreason = self.forksrv.run(code);
+        self.forksrv
+            .set_timeout(Duration::from_millis(self.timeout_in_millis));
+        Ok(exitreason? == ExitReason::Timeouted)
+    }
+
     pub fn new_bits(&mut self, is_crash: bool) -> Option<Vec<usize>> {
-        let mut res = vec![];
-        let run_bitmap = self.forksrv.get_shared();
-        let mut gstate_lock = self.global_state.lock().expect("RAND_2040280272");
-        let shared_bitmap = gstate_lock
-            .bitmaps
+        let virgin_bits = self
+            .virgin_bits
             .get_mut(&is_crash)
             .expect("Bitmap missing! Maybe shared state was not initialized correctly?");
+        let shared_bitmap = self
+            .global_state
+            .bitmaps
+            .get(&is_crash)
+            .expect("Bitmap missing! Maybe shared state was not initialized correctly?");
+        Self::merge_new_bits(self.forksrv.get_shared(), virgin_bits, shared_bitmap)
+    }
+
+    fn new_timeout_bits(&mut self) -> Option<Vec<usize>> {
+        Self::merge_new_bits(
+            self.forksrv.get_shared(),
+            &mut self.virgin_timeout_bits,
+            &self.global_state.timeout_bitmap,
+        )
+    }
 
-        for (i, elem) in shared_bitmap.iter_mut().enumerate() {
-            if (run_bitmap[i] != 0) && (*elem == 0) {
-                *elem |= run_bitmap[i];
+    fn merge_new_bits(
+        run_bitmap: &[u8],
+        virgin_bits: &mut [u8],
+        shared_bitmap: &[AtomicU8],
+    ) -> Option<Vec<usize>> {
+        //Fast path: most runs don't hit anything this thread hasn't seen before
+        let mut candidates = vec![];
+        for (offset, (run, virgin)) in run_bitmap.chunks(8).zip(virgin_bits.chunks(8)).enumerate() {
+            if run.iter().zip(virgin.iter()).any(|(r, v)| r & v != 0) {
+                for (i, (r, v)) in run.iter().zip(virgin.iter()).enumerate() {
+                    if r & v != 0 {
+                        candidates.push(offset * 8 + i);
+                    }
+                }
+            }
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let mut res = vec![];
+        for i in candidates {
+            virgin_bits[i] = 0;
+            if shared_bitmap[i].fetch_or(run_bitmap[i], Ordering::Relaxed) == 0 {
                 res.push(i);
-                //println!("Added new bit to bitmap. Is Crash: {:?}; Added bit: {:?}", is_crash, i);
             }
         }
 
@@ -368,3 +623,14 @@ impl Fuzzer {
         None
     }
 }
+
+//Inputs of the raw havoc stage are opaque trees, a single node with the raw bytes. If the bytes
+//still match the grammar, they are parsed back into a real tree that all stages can mutate.
+fn reparse_opaque(tree: Tree, ctx: &Context) -> Tree {
+    let root = NodeID::from(0);
+    if tree.size() != 1 || !tree.has_custom_data(root) {
+        return tree;
+    }
+    let nt = tree.get_rule(root, ctx).nonterm();
+    parser::parse(ctx, nt, tree.get_custom_rule_data(root)).unwrap_or(tree)
+}
@@ -16,19 +16,25 @@
 
 extern crate clap;
 extern crate grammartec;
-extern crate pyo3;
+extern crate nautilus;
 extern crate ron;
-extern crate serde_json;
 
-mod python_grammar_loader;
 use grammartec::context::Context;
-use grammartec::tree::TreeLike;
+use grammartec::enumeration;
+use grammartec::kpath::{self, KPathCoverage};
+use grammartec::size_distribution::{SizeDistribution, SizeStats};
+use grammartec::tree::{Tree, TreeLike};
+use grammartec::uniform_sampler::GenerationStrategy;
+use grammartec::validation::Severity;
+use nautilus::grammar_loader::{self, GrammarOptions};
+use nautilus::rule_weights;
 
 use clap::{value_parser, Arg, ArgAction, Command};
 use std::fs;
 use std::fs::File;
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::process;
 
 fn main() {
     //Parse parameters
@@ -56,6 +62,60 @@ fn main() {
              .short('s')
              .action(ArgAction::SetTrue)
              .help("Store output to files. This will create a folder called corpus containing one file for each generated tree."))
+        .arg(Arg::new("size_distribution")
+             .short('d')
+             .value_name("DISTRIBUTION")
+             .value_parser(value_parser!(SizeDistribution))
+             .default_value("max")
+             .help("Distribution of the tree sizes up to DEPTH: max, uniform, log-uniform or geometric:MEAN"))
+        .arg(Arg::new("strategy")
+             .short('m')
+             .value_name("STRATEGY")
+             .value_parser(value_parser!(GenerationStrategy))
+             .default_value("random")
+             .help("How trees are generated: random (top down random walk) or uniform (uniformly among all trees of the chosen size)"))
+        .arg(Arg::new("enumerate")
+             .long("enumerate")
+             .action(ArgAction::SetTrue)
+             .conflicts_with("kpath")
+             .help("Generate all trees with at most DEPTH nodes instead of random ones (smallest first, see --limit)"))
+        .arg(Arg::new("max_depth")
+             .long("max-depth")
+             .value_name("MAX_DEPTH")
+             .value_parser(value_parser!(usize))
+             .requires("enumerate")
+             .help("Enumerate all trees that are at most MAX_DEPTH nodes deep instead"))
+        .arg(Arg::new("limit")
+             .long("limit")
+             .value_name("NUMBER")
+             .value_parser(value_parser!(usize))
+             .default_value("10000")
+             .help("Maximal number of trees generated by --enumerate"))
+        .arg(Arg::new("kpath")
+             .long("kpath")
+             .value_name("K")
+             .value_parser(value_parser!(usize))
+             .help("Generate a small corpus that covers every rule and every K-path of nested rules"))
+        .arg(Arg::new("learn")
+             .short('l')
+             .value_name("TREES")
+             .help("Learn rule weights from the RON trees in this file or directory (e.g. outputs/trees of a campaign)"))
+        .arg(Arg::new("weights")
+             .short('w')
+             .value_name("WEIGHTS")
+             .help("Use rule weights saved with -W"))
+        .arg(Arg::new("save_weights")
+             .short('W')
+             .value_name("WEIGHTS")
+             .help("Save the weights learned with -l to this file"))
+        .arg(Arg::new("uncommon")
+             .short('u')
+             .action(ArgAction::SetTrue)
+             .help("Invert the rule weights to prefer uncommon derivations"))
+        .arg(Arg::new("break_framing")
+             .long("break-framing")
+             .action(ArgAction::SetTrue)
+             .help("Also generate wrong length prefixes in protobuf grammars"))
         .arg(Arg::new("verbose")
              .short('v')
              .action(ArgAction::SetTrue)
@@ -72,27 +132,48 @@ fn main() {
     let store = matches.get_flag("store");
     let verbose = matches.get_flag("verbose");
 
-    let mut ctx = Context::new();
     //Create new Context and saved it
-    match grammar_path.extension() {
-        Some(ext) if ext == "json" => {
-            let gf = File::open(grammar_path).expect("cannot read grammar file");
-            let rules: Vec<Vec<String>> =
-                serde_json::from_reader(&gf).expect("cannot parse grammar file");
-            assert!(!rules.is_empty(), "rule file didn_t include any rules");
-            let root = "{".to_string() + &rules[0][0] + "}";
-            ctx.add_rule("START", root.as_bytes());
-            for rule in rules {
-                ctx.add_rule(&rule[0], rule[1].as_bytes());
-            }
-        }
-        Some(ext) if ext == "py" => {
-            ctx = python_grammar_loader::load_python_grammar(grammar_path.to_str().unwrap())
+    let mut ctx = grammar_loader::load_grammar(
+        grammar_path.to_str().unwrap(),
+        &GrammarOptions {
+            break_framing: matches.get_flag("break_framing"),
+        },
+    );
+
+    //Report broken grammars here, initialize would only panic on them
+    let errors = ctx
+        .validate()
+        .into_iter()
+        .filter(|f| f.severity == Severity::Error)
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        for error in &errors {
+            eprintln!("{error}");
         }
-        Some(_) | None => panic!("Unknown grammar type"),
+        process::exit(1);
     }
 
+    rule_weights::apply(
+        &mut ctx,
+        matches.get_one::<String>("learn").map(String::as_str),
+        matches.get_one::<String>("weights").map(String::as_str),
+        matches
+            .get_one::<String>("save_weights")
+            .map(String::as_str),
+        matches.get_flag("uncommon"),
+    );
     ctx.initialize(tree_depth);
+    ctx.set_size_distribution(
+        *matches
+            .get_one::<SizeDistribution>("size_distribution")
+            .expect("size_distribution has a default value"),
+    );
+    ctx.set_generation_strategy(
+        *matches
+            .get_one::<GenerationStrategy>("strategy")
+            .expect("strategy has a default value"),
+    );
+    let mut tree_sizes = SizeStats::new();
 
     //Generate Tree
     if store {
@@ -101,10 +182,33 @@ fn main() {
             fs::create_dir("corpus").expect("Could not create corpus directory");
         }
     }
-    for i in 0..number_of_trees {
-        let nonterm = ctx.nt_id("START");
-        let len = ctx.get_random_len_for_nt(&nonterm);
-        let generated_tree = ctx.generate_tree_from_nt(nonterm, len); //1 is the index of the "START" Node
+    let nonterm = ctx.nt_id("START");
+    let limit = *matches.get_one::<usize>("limit").unwrap_or(&10000);
+    let (trees, number_of_trees): (Box<dyn Iterator<Item = Tree>>, usize) =
+        if let Some(k) = matches.get_one::<usize>("kpath") {
+            let (trees, coverage) = kpath::kpath_corpus(&ctx, nonterm, *k);
+            eprintln!("K-path coverage: {coverage}");
+            report_rule_coverage(&ctx, &trees);
+            let number_of_trees = trees.len();
+            (Box::new(trees.into_iter()), number_of_trees)
+        } else if matches.get_flag("enumerate") {
+            let trees = match matches.get_one::<usize>("max_depth") {
+                Some(depth) => enumeration::enumerate_by_depth(&ctx, nonterm, *depth, limit),
+                None => enumeration::enumerate_by_size(&ctx, nonterm, tree_depth, limit),
+            };
+            report_rule_coverage(&ctx, &trees);
+            let number_of_trees = trees.len();
+            (Box::new(trees.into_iter()), number_of_trees)
+        } else {
+            let ctx = &ctx;
+            let trees = (0..number_of_trees).map(move |_| {
+                let len = ctx.get_random_len_for_nt(&nonterm);
+                ctx.generate_tree_from_nt(nonterm, len)
+            });
+            (Box::new(trees), number_of_trees)
+        };
+    for (i, generated_tree) in trees.enumerate() {
+        tree_sizes.add(generated_tree.size());
         if verbose {
             println!("Generating tree {} from {number_of_trees}", i + 1);
         }
@@ -127,4 +231,15 @@ fn main() {
             )
             .expect("Writing to tree file failed");
     }
+    if verbose {
+        eprintln!("Tree sizes: {tree_sizes}");
+    }
+}
+
+fn report_rule_coverage(ctx: &Context, trees: &[Tree]) {
+    let mut coverage = KPathCoverage::new(ctx, ctx.nt_id("START"), 1);
+    for tree in trees {
+        coverage.add_tree(tree, ctx);
+    }
+    eprintln!("Rule coverage of {} trees: {coverage}", trees.len());
 }
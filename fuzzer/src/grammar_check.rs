@@ -0,0 +1,67 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate clap;
+extern crate grammartec;
+extern crate nautilus;
+
+use grammartec::context::Context;
+use grammartec::validation::Severity;
+use nautilus::grammar_loader::{self, GrammarOptions};
+
+use clap::{value_parser, Arg, ArgAction, Command};
+use std::path::PathBuf;
+use std::process;
+
+fn main() {
+    let matches = Command::new("grammar-check")
+        .about("Check a grammar for undefined, unreachable and non-productive nonterminals, duplicate rules, scripts with the wrong arity and regexes that can not be generated")
+        .arg(Arg::new("grammar_path")
+             .short('g')
+             .value_name("GRAMMAR")
+             .required(true)
+             .value_parser(value_parser!(PathBuf))
+             .help("Path to grammar"))
+        .arg(Arg::new("strict")
+             .short('s')
+             .action(ArgAction::SetTrue)
+             .help("Also fail on warnings"))
+        .get_matches();
+
+    let grammar_path = matches
+        .get_one::<PathBuf>("grammar_path")
+        .expect("grammar_path is a required parameter");
+    let ctx: Context =
+        grammar_loader::load_grammar(grammar_path.to_str().unwrap(), &GrammarOptions::default());
+
+    let findings = ctx.validate();
+    for finding in &findings {
+        println!("{finding}");
+    }
+    let errors = findings
+        .iter()
+        .filter(|f| f.severity == Severity::Error)
+        .count();
+    let warnings = findings.len() - errors;
+    println!(
+        "{}: {} rules, {errors} errors, {warnings} warnings",
+        grammar_path.display(),
+        ctx.get_number_of_rules()
+    );
+    if errors > 0 || (matches.get_flag("strict") && warnings > 0) {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,81 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate clap;
+extern crate grammartec;
+extern crate nautilus;
+
+use grammartec::context::Context;
+use grammartec::export;
+use grammartec::validation::Severity;
+use nautilus::grammar_loader::{self, GrammarOptions};
+
+use clap::{builder::PossibleValuesParser, value_parser, Arg, Command};
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+fn main() {
+    let matches = Command::new("grammar-export")
+        .about("Write a grammar as JSON rule list, as Python grammar or as Graphviz DOT graph of the nonterminals")
+        .arg(Arg::new("grammar_path")
+             .short('g')
+             .value_name("GRAMMAR")
+             .required(true)
+             .value_parser(value_parser!(PathBuf))
+             .help("Path to grammar"))
+        .arg(Arg::new("format")
+             .short('f')
+             .value_name("FORMAT")
+             .value_parser(PossibleValuesParser::new(["json", "python", "dot"]))
+             .default_value("python")
+             .help("Output format"))
+        .arg(Arg::new("output")
+             .short('o')
+             .value_name("OUTPUT")
+             .value_parser(value_parser!(PathBuf))
+             .help("Write to this file instead of stdout"))
+        .get_matches();
+
+    let grammar_path = matches
+        .get_one::<PathBuf>("grammar_path")
+        .expect("grammar_path is a required parameter");
+    let mut ctx: Context =
+        grammar_loader::load_grammar(grammar_path.to_str().unwrap(), &GrammarOptions::default());
+
+    //The min sizes and option counts are only available for valid grammars
+    if ctx.validate().iter().all(|f| f.severity != Severity::Error) {
+        ctx.initialize(0);
+    } else {
+        eprintln!("The grammar has errors (see grammar-check), sizes are not exported");
+    }
+
+    let output = match matches.get_one::<String>("format").map(String::as_str) {
+        Some("json") => export::to_json(&ctx).unwrap_or_else(|e| {
+            eprintln!("Could not export the grammar as JSON: {e}");
+            process::exit(1);
+        }),
+        Some("dot") => export::to_dot(&ctx),
+        _ => export::to_python(&ctx),
+    };
+    match matches.get_one::<PathBuf>("output") {
+        Some(path) => fs::write(path, output).unwrap_or_else(|e| {
+            eprintln!("Could not write {}: {}", path.display(), e);
+            process::exit(1);
+        }),
+        None => print!("{output}"),
+    }
+}
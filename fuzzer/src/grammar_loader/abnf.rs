@@ -0,0 +1,755 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs;
+
+use grammartec::context::Context;
+use grammartec::export::escape_terminal;
+
+//Loads ABNF grammars (RFC 5234, with the %s and %i strings of RFC 7405). Every ABNF rule becomes a
+//nonterminal of the same name, the first rule is the start symbol. Alternatives that only consist
+//of terminals become regex rules, repetitions of nonterminals are expanded into helper
+//nonterminals named RULE_N. Core rules like DIGIT or CRLF are added when they are used but not
+//defined.
+
+const CORE_RULES_SOURCE: &str = "RFC 5234";
+
+const CORE_RULES: &str = r#"
+ALPHA = %x41-5A / %x61-7A
+BIT = "0" / "1"
+CHAR = %x01-7F
+CR = %x0D
+CRLF = CR LF
+CTL = %x00-1F / %x7F
+DIGIT = %x30-39
+DQUOTE = %x22
+HEXDIG = DIGIT / "A" / "B" / "C" / "D" / "E" / "F"
+HTAB = %x09
+LF = %x0A
+LWSP = *(WSP / CRLF WSP)
+OCTET = %x00-FF
+SP = %x20
+VCHAR = %x21-7E
+WSP = SP / HTAB
+"#;
+
+#[derive(Clone, Debug)]
+enum Element {
+    Ref(String),
+    //A quoted string, true if it is case insensitive
+    Str(Vec<u8>, bool),
+    //A range of numeric values like %x41-5A
+    Range(u32, u32),
+    //Concatenated numeric values like %x0D.0A
+    Bytes(Vec<u8>),
+    Group(Vec<Vec<Element>>),
+    //Element, minimal and maximal number of repetitions
+    Repeat(Box<Element>, usize, Option<usize>),
+    //Prose values like <a description>
+    Prose,
+}
+
+struct AbnfRule {
+    name: String,
+    source: String,
+    line: usize,
+    alts: Vec<Vec<Element>>,
+}
+
+fn warn(source: &str, line: usize, msg: &str) {
+    eprintln!("warning: {source}:{line}: {msg}");
+}
+
+//Removes a comment, ; starts a comment outside of strings and prose values
+fn strip_comment(line: &str) -> &str {
+    let mut closing = None;
+    for (i, c) in line.char_indices() {
+        match (closing, c) {
+            (None, ';') => return &line[..i],
+            (None, '"') => closing = Some('"'),
+            (None, '<') => closing = Some('>'),
+            (Some(end), c) if c == end => closing = None,
+            _ => {}
+        }
+    }
+    line
+}
+
+//Splits "name = elements" or "name =/ elements" into the name, true for =/ and the elements
+fn split_definition(line: &str) -> Option<(&str, bool, &str)> {
+    let line = line.trim_start();
+    let name_len = line
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+        .unwrap_or(line.len());
+    if name_len == 0 || !line.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let rest = line[name_len..].trim_start().strip_prefix('=')?;
+    match rest.strip_prefix('/') {
+        Some(rest) => Some((&line[..name_len], true, rest)),
+        None => Some((&line[..name_len], false, rest)),
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    line: usize,
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, msg: &str) -> ! {
+        panic!("{}:{}: {}", self.source, self.line, msg);
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        while self.pos < self.text.len() && self.text[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        self.text.get(self.pos).copied()
+    }
+
+    fn take_while(&mut self, pred: impl Fn(u8) -> bool) -> &'a [u8] {
+        let start = self.pos;
+        while self.pos < self.text.len() && pred(self.text[self.pos]) {
+            self.pos += 1;
+        }
+        &self.text[start..self.pos]
+    }
+
+    fn parse_alts(&mut self) -> Vec<Vec<Element>> {
+        let mut alts = vec![self.parse_concat()];
+        while self.peek() == Some(b'/') {
+            self.pos += 1;
+            alts.push(self.parse_concat());
+        }
+        alts
+    }
+
+    fn parse_concat(&mut self) -> Vec<Element> {
+        let mut elements = vec![];
+        while !matches!(self.peek(), None | Some(b'/' | b')' | b']')) {
+            elements.push(self.parse_repetition());
+        }
+        elements
+    }
+
+    fn parse_number(&mut self, radix: u32) -> Option<usize> {
+        let digits = self.take_while(|b| (b as char).is_digit(radix));
+        std::str::from_utf8(digits)
+            .ok()
+            .and_then(|d| usize::from_str_radix(d, radix).ok())
+    }
+
+    fn parse_repetition(&mut self) -> Element {
+        self.peek();
+        let min = self.parse_number(10);
+        if self.text.get(self.pos) == Some(&b'*') {
+            self.pos += 1;
+            let max = self.parse_number(10);
+            let elem = self.parse_element();
+            Element::Repeat(Box::new(elem), min.unwrap_or(0), max)
+        } else if let Some(n) = min {
+            let elem = self.parse_element();
+            Element::Repeat(Box::new(elem), n, Some(n))
+        } else {
+            self.parse_element()
+        }
+    }
+
+    fn parse_element(&mut self) -> Element {
+        match self.peek() {
+            Some(b'(') => {
+                self.pos += 1;
+                let alts = self.parse_alts();
+                self.expect(b')');
+                Element::Group(alts)
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let alts = self.parse_alts();
+                self.expect(b']');
+                Element::Repeat(Box::new(Element::Group(alts)), 0, Some(1))
+            }
+            Some(b'"') => Element::Str(self.parse_string(), true),
+            Some(b'<') => {
+                self.take_while(|b| b != b'>');
+                self.expect(b'>');
+                warn(self.source, self.line, "prose value generates nothing");
+                Element::Prose
+            }
+            Some(b'%') => {
+                self.pos += 1;
+                self.parse_percent()
+            }
+            Some(b) if b.is_ascii_alphabetic() => {
+                let name = self.take_while(|b| b.is_ascii_alphanumeric() || b == b'-');
+                Element::Ref(String::from_utf8_lossy(name).into_owned())
+            }
+            Some(b) => self.error(&format!("unexpected {:?}", b as char)),
+            None => self.error("unexpected end of rule"),
+        }
+    }
+
+    fn expect(&mut self, b: u8) {
+        if self.peek() != Some(b) {
+            self.error(&format!("expected {:?}", b as char));
+        }
+        self.pos += 1;
+    }
+
+    fn parse_string(&mut self) -> Vec<u8> {
+        self.expect(b'"');
+        let data = self.take_while(|b| b != b'"').to_vec();
+        if self.text.get(self.pos) != Some(&b'"') {
+            self.error("unterminated string");
+        }
+        self.pos += 1;
+        data
+    }
+
+    //Parses the part after % of numeric values and case sensitive strings
+    fn parse_percent(&mut self) -> Element {
+        let radix = match self.text.get(self.pos).map(u8::to_ascii_lowercase) {
+            Some(b's') => {
+                self.pos += 1;
+                return Element::Str(self.parse_string(), false);
+            }
+            Some(b'i') => {
+                self.pos += 1;
+                return Element::Str(self.parse_string(), true);
+            }
+            Some(b'x') => 16,
+            Some(b'd') => 10,
+            Some(b'b') => 2,
+            _ => self.error("expected x, d, b, s or i after %"),
+        };
+        self.pos += 1;
+        let mut value = || {
+            self.parse_number(radix)
+                .map(|v| v as u32)
+                .unwrap_or_else(|| self.error("expected a numeric value"))
+        };
+        let first = value();
+        match self.text.get(self.pos) {
+            Some(b'-') => {
+                self.pos += 1;
+                let last = self.parse_number(radix).map(|v| v as u32);
+                let last = last.unwrap_or_else(|| self.error("expected a numeric value"));
+                self.range(first, last)
+            }
+            _ => {
+                let mut values = vec![first];
+                while self.text.get(self.pos) == Some(&b'.') {
+                    self.pos += 1;
+                    let v = self.parse_number(radix).map(|v| v as u32);
+                    values.push(v.unwrap_or_else(|| self.error("expected a numeric value")));
+                }
+                Element::Bytes(values.into_iter().flat_map(value_bytes).collect())
+            }
+        }
+    }
+
+    //Values are bytes, larger values are written as UTF-8. Ranges are only supported up to 0xFF.
+    fn range(&self, first: u32, last: u32) -> Element {
+        if last < first {
+            self.error("empty numeric range");
+        }
+        if first > 0xFF {
+            warn(
+                self.source,
+                self.line,
+                "numeric range above 0xFF is replaced by its first value",
+            );
+            return Element::Bytes(value_bytes(first));
+        }
+        if last > 0xFF {
+            warn(self.source, self.line, "numeric range is cut off at 0xFF");
+        }
+        Element::Range(first, last.min(0xFF))
+    }
+}
+
+fn value_bytes(value: u32) -> Vec<u8> {
+    match u8::try_from(value) {
+        Ok(b) => vec![b],
+        Err(_) => char::from_u32(value)
+            .map(|c| c.to_string().into_bytes())
+            .unwrap_or_default(),
+    }
+}
+
+fn parse_rules(source: &str, text: &str) -> Vec<(AbnfRule, bool)> {
+    //Rules can continue on the following lines, so lines are collected before parsing
+    let mut definitions: Vec<(usize, String, bool, String)> = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = strip_comment(line);
+        if let Some((name, incremental, elements)) = split_definition(line) {
+            definitions.push((i + 1, name.to_string(), incremental, elements.to_string()));
+        } else if !line.trim().is_empty() {
+            match definitions.last_mut() {
+                Some(def) => {
+                    def.3.push(' ');
+                    def.3.push_str(line);
+                }
+                None => panic!("{}:{}: expected a rule definition", source, i + 1),
+            }
+        }
+    }
+    definitions
+        .into_iter()
+        .map(|(line, name, incremental, elements)| {
+            let mut parser = Parser {
+                source,
+                line,
+                text: elements.as_bytes(),
+                pos: 0,
+            };
+            let alts = parser.parse_alts();
+            if parser.peek().is_some() {
+                parser.error(&format!("unexpected {:?}", parser.text[parser.pos] as char));
+            }
+            let rule = AbnfRule {
+                name,
+                source: source.to_string(),
+                line,
+                alts,
+            };
+            (rule, incremental)
+        })
+        .collect()
+}
+
+//Merges incremental alternatives (=/) into their rule, rule names are case insensitive
+fn merge_rules(rules: Vec<(AbnfRule, bool)>, merged: &mut Vec<AbnfRule>) {
+    for (rule, incremental) in rules {
+        let existing = merged
+            .iter_mut()
+            .find(|r| r.name.eq_ignore_ascii_case(&rule.name));
+        match existing {
+            Some(existing) => {
+                if !incremental {
+                    warn(
+                        &rule.source,
+                        rule.line,
+                        &format!("{} is defined again, use =/ to add alternatives", rule.name),
+                    );
+                }
+                existing.alts.extend(rule.alts);
+            }
+            None => {
+                if incremental {
+                    warn(
+                        &rule.source,
+                        rule.line,
+                        &format!("=/ used on undefined rule {}", rule.name),
+                    );
+                }
+                merged.push(rule);
+            }
+        }
+    }
+}
+
+fn collect_refs(elem: &Element, res: &mut Vec<String>) {
+    match elem {
+        Element::Ref(name) => res.push(name.clone()),
+        Element::Group(alts) => alts.iter().flatten().for_each(|e| collect_refs(e, res)),
+        Element::Repeat(inner, _, _) => collect_refs(inner, res),
+        _ => {}
+    }
+}
+
+//Adds the core rules that are used, directly or by other core rules, but not defined
+fn add_core_rules(rules: &mut Vec<AbnfRule>) {
+    let mut core = parse_rules(CORE_RULES_SOURCE, CORE_RULES)
+        .into_iter()
+        .map(|(rule, _)| rule)
+        .collect::<Vec<_>>();
+    let mut i = 0;
+    while i < rules.len() {
+        let mut refs = vec![];
+        rules[i]
+            .alts
+            .iter()
+            .flatten()
+            .for_each(|e| collect_refs(e, &mut refs));
+        for name in refs {
+            if rules.iter().any(|r| r.name.eq_ignore_ascii_case(&name)) {
+                continue;
+            }
+            if let Some(pos) = core.iter().position(|r| r.name.eq_ignore_ascii_case(&name)) {
+                rules.push(core.remove(pos));
+            }
+        }
+        i += 1;
+    }
+}
+
+//Regex syntax for a byte, values above 0x7F are matched as raw bytes
+fn regex_byte(b: u8) -> String {
+    if b.is_ascii_alphanumeric() {
+        (b as char).to_string()
+    } else if b.is_ascii() {
+        format!("\\x{{{b:x}}}")
+    } else {
+        format!("(?-u:\\x{b:02x})")
+    }
+}
+
+//Elements without nonterminals can be turned into a regex
+fn is_terminal(elem: &Element) -> bool {
+    match elem {
+        Element::Ref(_) | Element::Prose => false,
+        Element::Group(alts) => alts.iter().flatten().all(is_terminal),
+        Element::Repeat(inner, _, _) => is_terminal(inner),
+        Element::Str(..) | Element::Range(..) | Element::Bytes(_) => true,
+    }
+}
+
+//The bytes of elements that always produce the same output
+fn literal(elem: &Element) -> Option<Vec<u8>> {
+    match elem {
+        Element::Str(data, case_insensitive) => {
+            if *case_insensitive && data.iter().any(u8::is_ascii_alphabetic) {
+                None
+            } else {
+                Some(data.clone())
+            }
+        }
+        Element::Bytes(data) => Some(data.clone()),
+        Element::Range(first, last) if first == last => Some(vec![*first as u8]),
+        Element::Group(alts) if alts.len() == 1 => alts[0]
+            .iter()
+            .map(literal)
+            .collect::<Option<Vec<_>>>()
+            .map(|l| l.concat()),
+        _ => None,
+    }
+}
+
+fn alts_regex(alts: &[Vec<Element>]) -> String {
+    alts.iter()
+        .map(|alt| alt.iter().map(element_regex).collect::<String>())
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+fn element_regex(elem: &Element) -> String {
+    match elem {
+        Element::Str(data, case_insensitive) => data
+            .iter()
+            .map(|b| {
+                if *case_insensitive && b.is_ascii_alphabetic() {
+                    format!(
+                        "[{}{}]",
+                        b.to_ascii_lowercase() as char,
+                        b.to_ascii_uppercase() as char
+                    )
+                } else {
+                    regex_byte(*b)
+                }
+            })
+            .collect(),
+        Element::Bytes(data) => data.iter().map(|b| regex_byte(*b)).collect(),
+        Element::Range(first, last) if *last < 0x80 => {
+            format!("[\\x{{{first:x}}}-\\x{{{last:x}}}]")
+        }
+        Element::Range(first, last) => format!("(?-u:[\\x{first:02x}-\\x{last:02x}])"),
+        Element::Group(alts) => format!("(?:{})", alts_regex(alts)),
+        Element::Repeat(inner, min, max) => {
+            let op = match (min, max) {
+                (0, None) => "*".to_string(),
+                (1, None) => "+".to_string(),
+                (0, Some(1)) => "?".to_string(),
+                (min, None) => format!("{{{min},}}"),
+                (min, Some(max)) if min == max => format!("{{{min}}}"),
+                (min, Some(max)) => format!("{{{min},{max}}}"),
+            };
+            format!("(?:{}){op}", element_regex(inner))
+        }
+        Element::Ref(_) | Element::Prose => unreachable!(),
+    }
+}
+
+struct Converter {
+    source: String,
+    //Maps lower case rule names to the name they were defined with
+    names: HashMap<String, String>,
+    helpers: HashSet<String>,
+    //The helper that produces a backslash at the end of a terminal, see escape_terminal
+    backslash: String,
+}
+
+impl Converter {
+    fn helper_name(&mut self, rule: &str) -> String {
+        let name = (1..)
+            .map(|i| format!("{rule}_{i}"))
+            .find(|name| !self.helpers.contains(name))
+            .expect("RAND_2291375846");
+        self.helpers.insert(name.clone());
+        name
+    }
+
+    fn nt(&self, name: &str) -> Vec<u8> {
+        let name = self
+            .names
+            .get(&name.to_ascii_lowercase())
+            .map_or(name, String::as_str);
+        format!("{{{name}}}").into_bytes()
+    }
+
+    fn add_rule(&self, ctx: &mut Context, nt: &str, format: &[u8], line: usize) {
+        let rid = ctx.add_rule(nt, format);
+        ctx.set_rule_source(rid, format!("{}:{line}", self.source));
+    }
+
+    fn add_regex(&self, ctx: &mut Context, nt: &str, regex: &str, line: usize) {
+        let rid = ctx.add_regex(nt, regex);
+        ctx.set_rule_source(rid, format!("{}:{line}", self.source));
+    }
+
+    fn add_alts(
+        &mut self,
+        ctx: &mut Context,
+        nt: &str,
+        base: &str,
+        alts: &[Vec<Element>],
+        line: usize,
+    ) {
+        //Alternatives that need a regex are combined into one regex rule
+        let (regex_alts, alts): (Vec<_>, Vec<_>) = alts.iter().cloned().partition(|alt| {
+            alt.iter().all(is_terminal) && !alt.iter().all(|e| literal(e).is_some())
+        });
+        if !regex_alts.is_empty() {
+            if alts.is_empty() {
+                self.add_regex(ctx, nt, &alts_regex(&regex_alts), line);
+            } else {
+                let helper = self.helper_name(base);
+                self.add_regex(ctx, &helper, &alts_regex(&regex_alts), line);
+                self.add_rule(ctx, nt, format!("{{{helper}}}").as_bytes(), line);
+            }
+        }
+        for alt in &alts {
+            let format = alt
+                .iter()
+                .map(|elem| self.element_format(ctx, base, elem, line))
+                .collect::<Vec<_>>()
+                .concat();
+            self.add_rule(ctx, nt, &format, line);
+        }
+    }
+
+    //The format string for an element, helper nonterminals are named after the rule (base)
+    fn element_format(
+        &mut self,
+        ctx: &mut Context,
+        base: &str,
+        elem: &Element,
+        line: usize,
+    ) -> Vec<u8> {
+        if let Some(data) = literal(elem) {
+            return escape_terminal(ctx, &data, &self.backslash);
+        }
+        match elem {
+            Element::Ref(name) => self.nt(name),
+            Element::Prose => vec![],
+            Element::Group(alts) => {
+                let helper = self.helper_name(base);
+                self.add_alts(ctx, &helper, base, alts, line);
+                format!("{{{helper}}}").into_bytes()
+            }
+            Element::Repeat(inner, min, max) if !is_terminal(elem) => {
+                let inner = self.element_format(ctx, base, inner, line);
+                //The optional repetitions are a chain of helpers that each add one or nothing
+                let optional = match max {
+                    Some(max) => {
+                        let mut tail = vec![];
+                        for _ in *min..*max {
+                            let helper = self.helper_name(base);
+                            self.add_rule(ctx, &helper, b"", line);
+                            self.add_rule(ctx, &helper, &[&inner[..], &tail].concat(), line);
+                            tail = format!("{{{helper}}}").into_bytes();
+                        }
+                        tail
+                    }
+                    None => {
+                        let helper = self.helper_name(base);
+                        let tail = format!("{{{helper}}}").into_bytes();
+                        self.add_rule(ctx, &helper, b"", line);
+                        self.add_rule(ctx, &helper, &[&inner[..], &tail].concat(), line);
+                        tail
+                    }
+                };
+                [inner.repeat(*min), optional].concat()
+            }
+            _ => {
+                let helper = self.helper_name(base);
+                self.add_regex(ctx, &helper, &element_regex(elem), line);
+                format!("{{{helper}}}").into_bytes()
+            }
+        }
+    }
+}
+
+pub fn load_abnf_grammar(grammar_path: &str) -> Context {
+    let text = fs::read_to_string(grammar_path).expect("cannot read grammar file");
+    let mut rules = vec![];
+    merge_rules(parse_rules(grammar_path, &text), &mut rules);
+    let start = rules
+        .first()
+        .unwrap_or_else(|| panic!("{} does not contain any rules", grammar_path))
+        .name
+        .clone();
+    add_core_rules(&mut rules);
+
+    let mut converter = Converter {
+        source: grammar_path.to_string(),
+        names: rules
+            .iter()
+            .map(|r| (r.name.to_ascii_lowercase(), r.name.clone()))
+            .collect(),
+        helpers: HashSet::new(),
+        backslash: String::new(),
+    };
+    converter.backslash = converter.helper_name("BACKSLASH");
+    let mut ctx = Context::new();
+    converter.add_rule(&mut ctx, "START", &converter.nt(&start), 0);
+    for rule in &rules {
+        converter.source = rule.source.clone();
+        converter.add_alts(&mut ctx, &rule.name, &rule.name, &rule.alts, rule.line);
+    }
+    ctx
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use grammar_loader::{load_test_grammar, GrammarOptions};
+    use grammartec::context::Context;
+    use grammartec::export;
+    use grammartec::tree::TreeLike;
+
+    const HTTP: &[u8] = br#"
+request = method SP uri [ SP version ] CRLF
+method = %s"GET" / %s"POST" / "head"
+uri = 1*( ALPHA / DIGIT / "/" )
+version = "HTTP/" DIGIT "." DIGIT
+header = token ":" quoted-string
+quoted-string = DQUOTE *( qdtext / quoted-pair ) DQUOTE
+qdtext = HTAB / SP / %x21 / %x23-5B / %x5D-7E
+quoted-pair = "\" ( HTAB / SP / VCHAR )
+list = token 2*3( "," token )
+list =/ "{" token "}"
+token = 1*( ALPHA / "-" )
+"#;
+
+    fn samples(ctx: &Context, nt: &str) -> Vec<Vec<u8>> {
+        (0..100)
+            .map(|_| {
+                let tree = ctx.generate_tree_from_nt(ctx.nt_id(nt), 30);
+                tree.unparse_to_vec(ctx)
+            })
+            .collect()
+    }
+
+    fn load(grammar: &[u8]) -> Context {
+        let mut ctx = load_test_grammar("abnf", grammar, &GrammarOptions::default());
+        ctx.initialize(30);
+        ctx
+    }
+
+    #[test]
+    fn backslash_before_nonterminal() {
+        let ctx = load(HTTP);
+        for pair in samples(&ctx, "quoted-pair") {
+            assert_eq!(pair.len(), 2, "{:?}", String::from_utf8_lossy(&pair));
+            assert_eq!(pair[0], b'\\');
+            assert!(pair[1] == b'\t' || (0x20..=0x7e).contains(&pair[1]));
+        }
+        for string in samples(&ctx, "quoted-string") {
+            assert!(string.len() >= 2 && string[0] == b'"' && string[string.len() - 1] == b'"');
+        }
+        //Braces in strings are terminals
+        let braced = samples(&ctx, "list")
+            .into_iter()
+            .filter(|list| list.starts_with(b"{"))
+            .collect::<Vec<_>>();
+        assert!(!braced.is_empty());
+        for list in braced {
+            assert!(list.ends_with(b"}") && !list.contains(&b','));
+        }
+    }
+
+    #[test]
+    fn repetitions_and_strings() {
+        let ctx = load(HTTP);
+        //The first rule is the start symbol
+        assert!(export::to_python(&ctx).contains("ctx.rule(\"START\", b\"{request}\")"));
+        let mut methods = HashSet::new();
+        for request in samples(&ctx, "request") {
+            let request = String::from_utf8(request).expect("RAND_3187406520");
+            assert!(request.ends_with("\r\n"), "{:?}", request);
+            let parts = request.trim_end().split(' ').collect::<Vec<_>>();
+            //[ SP version ] is optional
+            assert!(parts.len() == 2 || parts.len() == 3, "{:?}", request);
+            methods.insert(parts[0].to_string());
+            assert!(
+                !parts[1].is_empty()
+                    && parts[1]
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '/'),
+                "{:?}",
+                request
+            );
+            if let Some(version) = parts.get(2) {
+                assert!(
+                    version.to_ascii_uppercase().starts_with("HTTP/"),
+                    "{:?}",
+                    request
+                );
+                assert_eq!(version.len(), 8, "{:?}", request);
+            }
+        }
+        //%s strings are case sensitive, quoted strings are not
+        for method in &methods {
+            assert!(
+                method == "GET" || method == "POST" || method.eq_ignore_ascii_case("head"),
+                "{:?}",
+                method
+            );
+        }
+        //2*3( "," token )
+        for list in samples(&ctx, "list") {
+            if !list.starts_with(b"{") {
+                let commas = list.iter().filter(|b| **b == b',').count();
+                assert!(
+                    commas == 2 || commas == 3,
+                    "{:?}",
+                    String::from_utf8_lossy(&list)
+                );
+            }
+        }
+        //Only the core rules that are used are added
+        assert!(ctx.get_nt_id("DIGIT").is_some());
+        assert!(ctx.get_nt_id("VCHAR").is_some());
+        assert!(ctx.get_nt_id("OCTET").is_none());
+    }
+}
@@ -0,0 +1,1095 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::mem;
+use std::path::Path;
+
+use grammartec::context::Context;
+use grammartec::export::escape_terminal;
+use grammartec::rule::Rule;
+use grammartec::validation::regex_problem;
+use regex_syntax::hir::{Class, ClassUnicode, ClassUnicodeRange, Hir, HirKind};
+
+//Loads ANTLR4 (.g4) parser, lexer and combined grammars. Parser rules become plain rules whose
+//elements are separated by a space (ANTLR grammars usually skip whitespace in the lexer). Lexer
+//rules become regex rules where possible. EBNF operators and groups are expanded into helper
+//nonterminals named RULE_N. The first parser rule is the start symbol. A lexer grammar named in
+//tokenVocab or import is loaded from the same directory.
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Id(String),
+    //String literal, escapes are already decoded
+    Str(String),
+    //Character set, the raw text between [ and ]
+    Set(String),
+    //Action or option block, the raw text between { and }
+    Action(String),
+    //Element options like <assoc=right>
+    ElementOptions,
+    Punct(&'static str),
+}
+
+struct Lexed {
+    token: Token,
+    line: usize,
+}
+
+#[derive(Clone, Debug)]
+enum Element {
+    Ref(String),
+    Literal(String),
+    Range(char, char),
+    Set(String),
+    Not(Box<Element>),
+    Wildcard,
+    Group(Vec<Vec<Element>>),
+    //The element with ?, * or +
+    Repeat(Box<Element>, char),
+    //Actions, predicates and EOF
+    Empty,
+}
+
+struct GrammarRule {
+    name: String,
+    //The grammar file that defines the rule, imported rules come from other files
+    source: String,
+    line: usize,
+    lexer: bool,
+    alts: Vec<Vec<Element>>,
+}
+
+#[derive(Default)]
+struct GrammarFile {
+    token_vocab: Option<String>,
+    imports: Vec<String>,
+    case_insensitive: bool,
+    tokens: Vec<String>,
+    rules: Vec<GrammarRule>,
+}
+
+fn is_lexer_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase())
+}
+
+fn warn(source: &str, line: usize, msg: &str) {
+    eprintln!("warning: {source}:{line}: {msg}");
+}
+
+fn tokenize(source: &str, text: &str) -> Vec<Lexed> {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut res = vec![];
+    let mut line = 1;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start_line = line;
+        let next = chars.get(i + 1).copied();
+        if c == '\n' {
+            line += 1;
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                line += usize::from(chars[i] == '\n');
+                i += 1;
+            }
+            i += 2;
+        } else if c.is_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let id = chars[start..i].iter().collect();
+            res.push(Lexed {
+                token: Token::Id(id),
+                line,
+            });
+        } else if c == '\'' {
+            let (lit, end) = read_string(&chars, i + 1);
+            i = end + 1;
+            res.push(Lexed {
+                token: Token::Str(lit),
+                line,
+            });
+        } else if c == '[' {
+            let start = i + 1;
+            i += 1;
+            while i < chars.len() && chars[i] != ']' {
+                i += if chars[i] == '\\' { 2 } else { 1 };
+            }
+            let set = chars[start..i.min(chars.len())].iter().collect();
+            i += 1;
+            res.push(Lexed {
+                token: Token::Set(set),
+                line,
+            });
+        } else if c == '{' {
+            let start = i + 1;
+            let mut depth = 0;
+            while i < chars.len() {
+                match chars[i] {
+                    '{' => depth += 1,
+                    '}' => depth -= 1,
+                    '\n' => line += 1,
+                    '\\' => i += 1,
+                    _ => {}
+                }
+                if depth == 0 {
+                    break;
+                }
+                i += 1;
+            }
+            let action = chars[start..i.min(chars.len())].iter().collect();
+            i += 1;
+            res.push(Lexed {
+                token: Token::Action(action),
+                line: start_line,
+            });
+        } else if c == '<' {
+            while i < chars.len() && chars[i] != '>' {
+                i += 1;
+            }
+            i += 1;
+            res.push(Lexed {
+                token: Token::ElementOptions,
+                line,
+            });
+        } else {
+            let two = next.map(|n| format!("{c}{n}"));
+            let punct = match two.as_deref() {
+                Some("..") => Some(".."),
+                Some("->") => Some("->"),
+                Some("+=") => Some("+="),
+                Some("::") => Some("::"),
+                _ => None,
+            };
+            if let Some(punct) = punct {
+                i += 2;
+                res.push(Lexed {
+                    token: Token::Punct(punct),
+                    line,
+                });
+                continue;
+            }
+            let punct = match c {
+                ':' => ":",
+                ';' => ";",
+                '|' => "|",
+                '(' => "(",
+                ')' => ")",
+                '?' => "?",
+                '*' => "*",
+                '+' => "+",
+                '~' => "~",
+                '.' => ".",
+                '#' => "#",
+                '=' => "=",
+                ',' => ",",
+                '@' => "@",
+                _ => {
+                    warn(source, line, &format!("unexpected character {c:?}"));
+                    i += 1;
+                    continue;
+                }
+            };
+            i += 1;
+            res.push(Lexed {
+                token: Token::Punct(punct),
+                line,
+            });
+        }
+    }
+    res
+}
+
+//Reads a string literal starting after the opening quote, returns it and the index of the closing
+//quote
+fn read_string(chars: &[char], mut i: usize) -> (String, usize) {
+    let mut res = String::new();
+    while i < chars.len() && chars[i] != '\'' {
+        if chars[i] == '\\' && i + 1 < chars.len() {
+            i += 1;
+            match chars[i] {
+                'n' => res.push('\n'),
+                'r' => res.push('\r'),
+                't' => res.push('\t'),
+                'b' => res.push('\u{8}'),
+                'f' => res.push('\u{c}'),
+                'u' => {
+                    let (code, end) = read_unicode_escape(chars, i + 1);
+                    res.extend(char::from_u32(code));
+                    i = end;
+                    continue;
+                }
+                c => res.push(c),
+            }
+        } else {
+            res.push(chars[i]);
+        }
+        i += 1;
+    }
+    (res, i)
+}
+
+//Parses the XXXX of \uXXXX or \u{XXXXXX}, returns the code point and the index after it
+fn read_unicode_escape(chars: &[char], i: usize) -> (u32, usize) {
+    let (digits, end) = if chars.get(i) == Some(&'{') {
+        let close = (i..chars.len())
+            .find(|j| chars[*j] == '}')
+            .unwrap_or(chars.len());
+        (&chars[(i + 1).min(close)..close], close + 1)
+    } else {
+        let end = (i + 4).min(chars.len());
+        (&chars[i..end], end)
+    };
+    let code = u32::from_str_radix(&digits.iter().collect::<String>(), 16).unwrap_or(0);
+    (code, end)
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Lexed>,
+    pos: usize,
+    warned_modes: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|l| &l.token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or(self.tokens.last())
+            .map_or(0, |l| l.line)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|l| l.token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn is_punct(&self, punct: &str) -> bool {
+        matches!(self.peek(), Some(Token::Punct(p)) if *p == punct)
+    }
+
+    fn expect_punct(&mut self, punct: &str) {
+        if self.is_punct(punct) {
+            self.pos += 1;
+        } else {
+            panic!(
+                "{}:{}: expected {:?}, found {:?}",
+                self.source,
+                self.line(),
+                punct,
+                self.peek()
+            );
+        }
+    }
+
+    fn skip_to_semicolon(&mut self) {
+        while self.peek().is_some() && !self.is_punct(";") {
+            self.pos += 1;
+        }
+        self.pos += 1;
+    }
+
+    fn parse_file(&mut self) -> GrammarFile {
+        let mut file = GrammarFile::default();
+        while let Some(token) = self.next() {
+            match token {
+                Token::Id(id) if id == "lexer" || id == "parser" || id == "grammar" => {
+                    self.skip_to_semicolon();
+                }
+                Token::Id(id) if id == "options" => {
+                    if let Some(Token::Action(body)) = self.next() {
+                        for option in body.split(';') {
+                            let mut kv = option.splitn(2, '=').map(str::trim);
+                            match (kv.next(), kv.next()) {
+                                (Some("tokenVocab"), Some(v)) => file.token_vocab = Some(v.into()),
+                                (Some("caseInsensitive"), Some(v)) => {
+                                    file.case_insensitive = v == "true";
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+                Token::Id(id) if id == "tokens" => {
+                    if let Some(Token::Action(body)) = self.next() {
+                        file.tokens.extend(
+                            body.split(',')
+                                .map(str::trim)
+                                .filter(|t| !t.is_empty())
+                                .map(String::from),
+                        );
+                    }
+                }
+                Token::Id(id) if id == "channels" => {
+                    self.next();
+                }
+                Token::Id(id) if id == "import" => {
+                    while let Some(token) = self.next() {
+                        match token {
+                            //import A=B; loads B
+                            Token::Id(name) if !self.is_punct("=") => file.imports.push(name),
+                            Token::Punct(";") => break,
+                            _ => {}
+                        }
+                    }
+                }
+                Token::Id(id) if id == "mode" => {
+                    if !self.warned_modes {
+                        warn(self.source, self.line(), "lexer modes are ignored");
+                        self.warned_modes = true;
+                    }
+                    self.skip_to_semicolon();
+                }
+                //Named actions like @header {...} or @lexer::members {...}
+                Token::Punct("@") => {
+                    while let Some(token) = self.next() {
+                        if let Token::Action(_) = token {
+                            break;
+                        }
+                    }
+                }
+                Token::Id(id) => {
+                    let name = if id == "fragment" {
+                        match self.next() {
+                            Some(Token::Id(name)) => name,
+                            other => panic!(
+                                "{}:{}: expected a rule name, found {:?}",
+                                self.source,
+                                self.line(),
+                                other
+                            ),
+                        }
+                    } else {
+                        id
+                    };
+                    let rule = self.parse_rule(name);
+                    file.rules.push(rule);
+                }
+                other => panic!("{}:{}: unexpected {:?}", self.source, self.line(), other),
+            }
+        }
+        file
+    }
+
+    fn parse_rule(&mut self, name: String) -> GrammarRule {
+        let line = self.line();
+        //Arguments, return values, locals, rule options and actions
+        while self.peek().is_some() && !self.is_punct(":") {
+            self.pos += 1;
+        }
+        self.expect_punct(":");
+        let alts = self.parse_alts();
+        self.expect_punct(";");
+        //Exception handlers
+        while let Some(Token::Id(id)) = self.peek() {
+            if id != "catch" && id != "finally" {
+                break;
+            }
+            while self.peek().is_some() && !matches!(self.peek(), Some(Token::Action(_))) {
+                self.pos += 1;
+            }
+            self.pos += 1;
+        }
+        GrammarRule {
+            lexer: is_lexer_name(&name),
+            source: self.source.to_string(),
+            name,
+            line,
+            alts,
+        }
+    }
+
+    fn parse_alts(&mut self) -> Vec<Vec<Element>> {
+        let mut alts = vec![self.parse_alt()];
+        while self.is_punct("|") {
+            self.pos += 1;
+            alts.push(self.parse_alt());
+        }
+        alts
+    }
+
+    fn parse_alt(&mut self) -> Vec<Element> {
+        let mut elements = vec![];
+        loop {
+            match self.peek() {
+                None | Some(Token::Punct("|" | ")" | ";")) => break,
+                Some(Token::Punct("#")) => {
+                    self.pos += 2;
+                }
+                Some(Token::Punct("->")) => self.parse_commands(),
+                _ => elements.push(self.parse_element()),
+            }
+        }
+        elements
+    }
+
+    //Lexer commands: skip and channel only matter for the parser, everything else is ignored
+    fn parse_commands(&mut self) {
+        self.pos += 1;
+        loop {
+            match self.peek() {
+                None | Some(Token::Punct("|" | ";")) => break,
+                Some(Token::Id(id)) => {
+                    if matches!(id.as_str(), "more" | "mode" | "pushMode" | "popMode") {
+                        warn(
+                            self.source,
+                            self.line(),
+                            &format!("lexer command {id} is ignored"),
+                        );
+                    }
+                    self.pos += 1;
+                }
+                Some(Token::Punct("(")) => {
+                    while self.peek().is_some() && !self.is_punct(")") {
+                        self.pos += 1;
+                    }
+                    self.pos += 1;
+                }
+                _ => self.pos += 1,
+            }
+        }
+    }
+
+    fn parse_element(&mut self) -> Element {
+        //Labels like x=ID or xs+=expr
+        if let (Some(Token::Id(_)), Some(Token::Punct("=" | "+="))) =
+            (self.peek(), self.tokens.get(self.pos + 1).map(|l| &l.token))
+        {
+            self.pos += 2;
+        }
+        let atom = self.parse_atom();
+        match self.peek() {
+            Some(Token::Punct(op @ ("?" | "*" | "+"))) => {
+                let op = op.chars().next().expect("RAND_3150623117");
+                self.pos += 1;
+                //Non greedy operators generate the same inputs
+                if self.is_punct("?") {
+                    self.pos += 1;
+                }
+                Element::Repeat(Box::new(atom), op)
+            }
+            _ => atom,
+        }
+    }
+
+    fn parse_atom(&mut self) -> Element {
+        let line = self.line();
+        match self.next() {
+            Some(Token::Id(id)) if id == "EOF" => Element::Empty,
+            Some(Token::Id(id)) => Element::Ref(id),
+            Some(Token::Str(lit)) => {
+                if self.is_punct("..") {
+                    self.pos += 1;
+                    match self.next() {
+                        Some(Token::Str(end)) => Element::Range(
+                            lit.chars().next().unwrap_or('\0'),
+                            end.chars().next().unwrap_or('\0'),
+                        ),
+                        other => panic!(
+                            "{}:{}: expected the end of a range, found {:?}",
+                            self.source, line, other
+                        ),
+                    }
+                } else {
+                    Element::Literal(lit)
+                }
+            }
+            Some(Token::Set(set)) => Element::Set(set),
+            Some(Token::Punct(".")) => Element::Wildcard,
+            Some(Token::Punct("~")) => Element::Not(Box::new(self.parse_atom())),
+            Some(Token::Punct("(")) => {
+                let alts = self.parse_alts();
+                self.expect_punct(")");
+                Element::Group(alts)
+            }
+            Some(Token::Action(_)) => {
+                if self.is_punct("?") {
+                    self.pos += 1;
+                    warn(self.source, line, "semantic predicate is ignored");
+                } else {
+                    warn(self.source, line, "action is ignored");
+                }
+                Element::Empty
+            }
+            Some(Token::ElementOptions) => self.parse_atom(),
+            other => panic!("{}:{}: unexpected {:?}", self.source, line, other),
+        }
+    }
+}
+
+fn parse_grammar_file(path: &Path) -> GrammarFile {
+    let source = path.display().to_string();
+    let text = fs::read_to_string(path).expect("cannot read grammar file");
+    let mut parser = Parser {
+        tokens: tokenize(&source, &text),
+        source: &source,
+        pos: 0,
+        warned_modes: false,
+    };
+    parser.parse_file()
+}
+
+//Regex syntax for a character, escaped so that it is safe inside and outside of classes
+fn regex_char(c: char) -> String {
+    if c.is_ascii_alphanumeric() || c == '_' {
+        c.to_string()
+    } else {
+        format!("\\x{{{:x}}}", c as u32)
+    }
+}
+
+//Translates the content of an ANTLR set like [a-z\n] to the content of a regex class
+fn set_to_class(set: &str) -> String {
+    let chars = set.chars().collect::<Vec<_>>();
+    let mut res = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '\\' && i + 1 < chars.len() {
+            i += 1;
+            match chars[i] {
+                'n' => res.push_str(&regex_char('\n')),
+                'r' => res.push_str(&regex_char('\r')),
+                't' => res.push_str(&regex_char('\t')),
+                'b' => res.push_str(&regex_char('\u{8}')),
+                'f' => res.push_str(&regex_char('\u{c}')),
+                'u' => {
+                    let (code, end) = read_unicode_escape(&chars, i + 1);
+                    res.push_str(&regex_char(char::from_u32(code).unwrap_or('\0')));
+                    i = end;
+                    continue;
+                }
+                //Unicode properties like \p{L} are understood by the regex parser
+                'p' | 'P' => {
+                    res.push('\\');
+                    res.push(chars[i]);
+                }
+                other => res.push_str(&regex_char(other)),
+            }
+        } else if c == '-' && i > 0 && i + 1 < chars.len() {
+            res.push('-');
+        } else {
+            res.push_str(&regex_char(c));
+        }
+        i += 1;
+    }
+    res
+}
+
+//The content of a regex class matching the element, if it is a set of characters
+fn class_content(elem: &Element) -> Option<String> {
+    match elem {
+        Element::Set(set) => Some(set_to_class(set)),
+        Element::Literal(lit) if lit.chars().count() == 1 => {
+            Some(regex_char(lit.chars().next().expect("RAND_2744187953")))
+        }
+        Element::Range(a, b) => Some(format!("{}-{}", regex_char(*a), regex_char(*b))),
+        Element::Wildcard => Some("\\x00-\\x7F".to_string()),
+        Element::Group(alts) => alts
+            .iter()
+            .map(|alt| match &alt[..] {
+                [elem] => class_content(elem),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+//The regex generator only handles ASCII classes, so classes are restricted to ASCII. Classes that
+//are empty afterwards generate nothing.
+fn class_regex(
+    source: &str,
+    line: usize,
+    elem: &Element,
+    case_insensitive: bool,
+) -> Option<String> {
+    let (content, negated) = match elem {
+        Element::Not(inner) => (class_content(inner)?, true),
+        _ => (class_content(elem)?, false),
+    };
+    let mut class = match Rule::parse_regex(&format!("[{content}]")).map(Hir::into_kind) {
+        Ok(HirKind::Class(Class::Unicode(class))) => class,
+        Ok(_) => return None,
+        Err(e) => {
+            warn(source, line, &e);
+            return None;
+        }
+    };
+    //~ negates the case folded set
+    if case_insensitive {
+        class.case_fold_simple();
+    }
+    if negated {
+        class.negate();
+    }
+    class.intersect(&ClassUnicode::new(vec![ClassUnicodeRange::new(
+        '\0', '\x7F',
+    )]));
+    if class.ranges().is_empty() {
+        warn(
+            source,
+            line,
+            "character set without ASCII characters generates nothing",
+        );
+        return Some(String::new());
+    }
+    Some(Hir::class(Class::Unicode(class)).to_string())
+}
+
+//(?i) would also match non-ASCII characters like the Kelvin sign, so letters are turned into
+//classes instead
+fn escape_regex(lit: &str, case_insensitive: bool) -> String {
+    lit.chars()
+        .map(|c| {
+            if case_insensitive && c.is_ascii_alphabetic() {
+                format!("[{}{}]", c.to_ascii_lowercase(), c.to_ascii_uppercase())
+            } else {
+                regex_char(c)
+            }
+        })
+        .collect()
+}
+
+struct Converter<'a> {
+    //The file of the rule that is converted
+    source: String,
+    case_insensitive: bool,
+    rules: HashMap<String, &'a GrammarRule>,
+    //Lexer rules that can be written as a single regex
+    regexes: HashMap<String, Option<String>>,
+    //Names that are taken by rules or helpers
+    names: HashSet<String>,
+    //The helper that produces a backslash at the end of a literal, see escape_terminal
+    backslash: String,
+}
+
+impl<'a> Converter<'a> {
+    fn lexer_regex(&mut self, name: &str, visiting: &mut Vec<String>) -> Option<String> {
+        if let Some(regex) = self.regexes.get(name) {
+            return regex.clone();
+        }
+        //Recursive lexer rules can not be inlined
+        if visiting.iter().any(|n| n == name) {
+            return None;
+        }
+        let rule = *self.rules.get(name)?;
+        if !rule.lexer {
+            return None;
+        }
+        visiting.push(name.to_string());
+        let outer = mem::replace(&mut self.source, rule.source.clone());
+        let regex = self.alts_regex(&rule.alts, rule.line, visiting);
+        self.source = outer;
+        visiting.pop();
+        let regex = regex.filter(|regex| {
+            let regex = format!("(?:{regex})");
+            Rule::parse_regex(&regex).is_ok_and(|hir| regex_problem(&hir).is_none())
+        });
+        self.regexes.insert(name.to_string(), regex.clone());
+        regex
+    }
+
+    fn alts_regex(
+        &mut self,
+        alts: &[Vec<Element>],
+        line: usize,
+        visiting: &mut Vec<String>,
+    ) -> Option<String> {
+        let mut res = vec![];
+        for alt in alts {
+            let mut seq = String::new();
+            for elem in alt {
+                seq.push_str(&self.element_regex(elem, line, visiting)?);
+            }
+            res.push(seq);
+        }
+        Some(res.join("|"))
+    }
+
+    fn element_regex(
+        &mut self,
+        elem: &Element,
+        line: usize,
+        visiting: &mut Vec<String>,
+    ) -> Option<String> {
+        match elem {
+            Element::Ref(name) => Some(format!("(?:{})", self.lexer_regex(name, visiting)?)),
+            Element::Literal(lit) => Some(escape_regex(lit, self.case_insensitive)),
+            Element::Group(alts) => Some(format!("(?:{})", self.alts_regex(alts, line, visiting)?)),
+            Element::Repeat(inner, op) => Some(format!(
+                "(?:{}){op}",
+                self.element_regex(inner, line, visiting)?
+            )),
+            Element::Empty => Some(String::new()),
+            Element::Range(..) | Element::Set(_) | Element::Not(_) | Element::Wildcard => {
+                class_regex(&self.source, line, elem, self.case_insensitive)
+            }
+        }
+    }
+
+    fn helper_name(&mut self, rule: &str) -> String {
+        let name = (1..)
+            .map(|i| format!("{rule}_{i}"))
+            .find(|name| !self.names.contains(name))
+            .expect("RAND_4163001742");
+        self.names.insert(name.clone());
+        name
+    }
+
+    fn add_rule(&self, ctx: &mut Context, nt: &str, format: &[u8], line: usize) {
+        let rid = ctx.add_rule(nt, format);
+        ctx.set_rule_source(rid, format!("{}:{line}", self.source));
+    }
+
+    fn add_regex(&self, ctx: &mut Context, nt: &str, regex: &str, line: usize) {
+        let rid = ctx.add_regex(nt, regex);
+        ctx.set_rule_source(rid, format!("{}:{line}", self.source));
+    }
+
+    //Adds the alternatives as rules of nt, elements are joined with sep
+    fn add_alts(
+        &mut self,
+        ctx: &mut Context,
+        nt: &str,
+        base: &str,
+        alts: &[Vec<Element>],
+        sep: &[u8],
+        line: usize,
+    ) {
+        for alt in alts {
+            let parts = alt
+                .iter()
+                .map(|elem| self.element_format(ctx, base, elem, sep, line))
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>();
+            self.add_rule(ctx, nt, &parts.join(sep), line);
+        }
+    }
+
+    //The format string for an element, helper nonterminals are named after the rule (base)
+    fn element_format(
+        &mut self,
+        ctx: &mut Context,
+        base: &str,
+        elem: &Element,
+        sep: &[u8],
+        line: usize,
+    ) -> Vec<u8> {
+        match elem {
+            Element::Ref(name) => format!("{{{name}}}").into_bytes(),
+            Element::Literal(lit) => escape_terminal(ctx, lit.as_bytes(), &self.backslash),
+            Element::Empty => vec![],
+            Element::Range(..) | Element::Set(_) | Element::Not(_) | Element::Wildcard => {
+                if let Some(regex) = class_regex(&self.source, line, elem, self.case_insensitive) {
+                    let helper = self.helper_name(base);
+                    self.add_regex(ctx, &helper, &regex, line);
+                    format!("{{{helper}}}").into_bytes()
+                } else {
+                    warn(&self.source, line, "unsupported character set is ignored");
+                    vec![]
+                }
+            }
+            Element::Group(alts) => {
+                let helper = self.helper_name(base);
+                self.add_alts(ctx, &helper, base, alts, sep, line);
+                format!("{{{helper}}}").into_bytes()
+            }
+            Element::Repeat(inner, op) => {
+                let helper = self.helper_name(base);
+                let inner = self.element_format(ctx, base, inner, sep, line);
+                let more = [&inner[..], sep, format!("{{{helper}}}").as_bytes()].concat();
+                match op {
+                    '?' => {
+                        self.add_rule(ctx, &helper, b"", line);
+                        self.add_rule(ctx, &helper, &inner, line);
+                    }
+                    '*' => {
+                        self.add_rule(ctx, &helper, b"", line);
+                        self.add_rule(ctx, &helper, &more, line);
+                    }
+                    _ => {
+                        self.add_rule(ctx, &helper, &inner, line);
+                        self.add_rule(ctx, &helper, &more, line);
+                    }
+                }
+                format!("{{{helper}}}").into_bytes()
+            }
+        }
+    }
+}
+
+pub fn load_antlr_grammar(grammar_path: &str) -> Context {
+    let path = Path::new(grammar_path);
+    let mut file = parse_grammar_file(path);
+    //Rules of the main grammar override those of imported grammars
+    let mut defined = file
+        .rules
+        .iter()
+        .map(|r| r.name.clone())
+        .collect::<HashSet<_>>();
+    let dependencies = file
+        .imports
+        .iter()
+        .chain(file.token_vocab.iter())
+        .cloned()
+        .collect::<Vec<_>>();
+    for dependency in dependencies {
+        let dep_path = path.with_file_name(format!("{dependency}.g4"));
+        if !dep_path.exists() {
+            warn(
+                grammar_path,
+                0,
+                &format!("{} not found", dep_path.display()),
+            );
+            continue;
+        }
+        let dep = parse_grammar_file(&dep_path);
+        file.case_insensitive |= dep.case_insensitive;
+        file.tokens.extend(dep.tokens);
+        for rule in dep.rules {
+            if defined.insert(rule.name.clone()) {
+                file.rules.push(rule);
+            }
+        }
+    }
+
+    let start = file
+        .rules
+        .iter()
+        .find(|r| !r.lexer)
+        .or(file.rules.first())
+        .unwrap_or_else(|| panic!("{} does not contain any rules", grammar_path))
+        .name
+        .clone();
+    let mut converter = Converter {
+        source: grammar_path.to_string(),
+        case_insensitive: file.case_insensitive,
+        rules: file.rules.iter().map(|r| (r.name.clone(), r)).collect(),
+        regexes: HashMap::new(),
+        names: defined.clone(),
+        backslash: String::new(),
+    };
+    converter.names.insert("START".to_string());
+    converter.backslash = converter.helper_name("BACKSLASH");
+
+    let mut ctx = Context::new();
+    converter.add_rule(&mut ctx, "START", format!("{{{start}}}").as_bytes(), 0);
+    for rule in &file.rules {
+        converter.source = rule.source.clone();
+        if rule.lexer {
+            if let Some(regex) = converter.lexer_regex(&rule.name, &mut vec![]) {
+                converter.add_regex(&mut ctx, &rule.name, &regex, rule.line);
+                continue;
+            }
+        }
+        let sep: &[u8] = if rule.lexer { b"" } else { b" " };
+        converter.add_alts(&mut ctx, &rule.name, &rule.name, &rule.alts, sep, rule.line);
+    }
+    converter.source = grammar_path.to_string();
+    for token in &file.tokens {
+        if !defined.contains(token) {
+            warn(
+                grammar_path,
+                0,
+                &format!("token {token} has no lexer rule, it generates nothing"),
+            );
+            converter.add_rule(&mut ctx, token, b"", 0);
+            defined.insert(token.clone());
+        }
+    }
+    ctx
+}
+
+#[cfg(test)]
+mod tests {
+    use grammar_loader::{load_test_grammar, GrammarOptions};
+    use grammartec::context::Context;
+    use grammartec::export;
+    use grammartec::tree::TreeLike;
+
+    const CALC: &[u8] = br#"grammar Calc;
+list : expr (',' expr)* ';'? EOF ;
+expr : atom ('+' atom)+ | '-' expr ;
+atom : INT | STR | ID | NESTED ;
+INT : DIGIT+ ;
+fragment DIGIT : [0-9] ;
+STR : '"' ~["\\\r\n]* '"' ;
+ID : ('a'..'z' | '_') [a-z0-9_]* ;
+NESTED : '(' NESTED? ')' ;
+WS : [ \t\r\n]+ -> skip ;
+"#;
+
+    fn samples(ctx: &Context, nt: &str) -> Vec<String> {
+        (0..100)
+            .map(|_| {
+                let tree = ctx.generate_tree_from_nt(ctx.nt_id(nt), 20);
+                String::from_utf8(tree.unparse_to_vec(ctx)).expect("RAND_1587426911")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parser_rules() {
+        let ctx = load_test_grammar("g4", CALC, &GrammarOptions::default());
+        let python = export::to_python(&ctx);
+        //The first parser rule is the start symbol, elements are separated by spaces and EOF
+        //generates nothing
+        for rule in [
+            "ctx.rule(\"START\", b\"{list}\")",
+            "ctx.rule(\"list\", b\"{expr} {list_1} {list_3}\")",
+            "ctx.rule(\"expr\", b\"- {expr}\")",
+            "ctx.rule(\"atom\", b\"{INT}\")",
+            //(',' expr)*: a group in a repetition
+            "ctx.rule(\"list_1\", b\"\")",
+            "ctx.rule(\"list_1\", b\"{list_2} {list_1}\")",
+            "ctx.rule(\"list_2\", b\", {expr}\")",
+            //';'?
+            "ctx.rule(\"list_3\", b\"\")",
+            "ctx.rule(\"list_3\", b\";\")",
+            //('+' atom)+
+            "ctx.rule(\"expr_1\", b\"{expr_2}\")",
+            "ctx.rule(\"expr_1\", b\"{expr_2} {expr_1}\")",
+            "ctx.rule(\"expr_2\", b\"+ {atom}\")",
+        ] {
+            assert!(python.contains(rule), "{} missing in\n{}", rule, python);
+        }
+        assert_eq!(ctx.get_rules_for_nt(ctx.nt_id("list_1")).len(), 2);
+        assert_eq!(ctx.get_rules_for_nt(ctx.nt_id("expr")).len(), 2);
+    }
+
+    #[test]
+    fn lexer_rules() {
+        let mut ctx = load_test_grammar("g4", CALC, &GrammarOptions::default());
+        let python = export::to_python(&ctx);
+        //Lexer rules become regexes, fragments are inlined
+        assert!(python.contains("ctx.regex(\"INT\", "), "{}", python);
+        assert!(
+            python.contains("ctx.regex(\"DIGIT\", \"[0-9]\")"),
+            "{}",
+            python
+        );
+        //Recursive lexer rules can't, their elements are not separated
+        assert!(
+            python.contains("ctx.rule(\"NESTED\", b\"({NESTED_1})\")"),
+            "{}",
+            python
+        );
+        ctx.initialize(20);
+
+        for int in samples(&ctx, "INT") {
+            assert!(
+                !int.is_empty() && int.bytes().all(|b| b.is_ascii_digit()),
+                "{:?}",
+                int
+            );
+        }
+        //~ excludes the characters of the set, generated characters are ASCII
+        for string in samples(&ctx, "STR") {
+            let inner = &string[1..string.len() - 1];
+            assert!(
+                string.starts_with('"') && string.ends_with('"'),
+                "{:?}",
+                string
+            );
+            assert!(
+                inner.is_ascii() && !inner.contains(['"', '\\', '\r', '\n']),
+                "{:?}",
+                string
+            );
+        }
+        for id in samples(&ctx, "ID") {
+            let first = id.chars().next().expect("RAND_3871522036");
+            assert!(first == '_' || first.is_ascii_lowercase(), "{:?}", id);
+            assert!(
+                id.chars()
+                    .all(|c| c == '_' || c.is_ascii_lowercase() || c.is_ascii_digit()),
+                "{:?}",
+                id
+            );
+        }
+        for nested in samples(&ctx, "NESTED") {
+            let depth = nested.len() / 2;
+            assert_eq!(nested, "(".repeat(depth) + &")".repeat(depth));
+        }
+        //Skipped tokens are still part of the grammar
+        for ws in samples(&ctx, "WS") {
+            assert!(!ws.is_empty() && ws.trim().is_empty(), "{:?}", ws);
+        }
+    }
+
+    #[test]
+    fn sets_and_options() {
+        let mut ctx = load_test_grammar(
+            "g4",
+            br#"lexer grammar Words;
+options { caseInsensitive = true; }
+WORD : 'ab' ~('a' | 'b' | [c-z])+ ;
+ANY : . ;
+"#,
+            &GrammarOptions::default(),
+        );
+        ctx.initialize(20);
+        //Without parser rules the first lexer rule is the start symbol
+        assert!(export::to_python(&ctx).contains("ctx.rule(\"START\", b\"{WORD}\")"));
+        for word in samples(&ctx, "WORD") {
+            assert!(word.to_ascii_lowercase().starts_with("ab"), "{:?}", word);
+            assert!(word.len() > 2, "{:?}", word);
+            assert!(
+                !word[2..].chars().any(|c| c.is_ascii_alphabetic()),
+                "{:?}",
+                word
+            );
+        }
+        for any in samples(&ctx, "ANY") {
+            assert!(any.len() == 1 && any.is_ascii(), "{:?}", any);
+        }
+    }
+
+    #[test]
+    fn backslash_before_nonterminal() {
+        //ESC is recursive, so it can't be a regex and its literal is followed by {ESC}
+        let grammar = br"grammar Esc;
+s : '\\' ESC ;
+ESC : '\\' ESC | 'x' ;
+";
+        let mut ctx = load_test_grammar("g4", grammar, &GrammarOptions::default());
+        ctx.initialize(20);
+        for esc in samples(&ctx, "ESC") {
+            let escapes = esc.strip_suffix('x').expect("RAND_1587426914");
+            assert!(escapes.chars().all(|c| c == '\\'), "{:?}", esc);
+        }
+        for s in samples(&ctx, "s") {
+            assert!(s.starts_with("\\ \\") && s.ends_with('x'), "{:?}", s);
+        }
+    }
+}
@@ -0,0 +1,390 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+
+use grammartec::context::Context;
+use grammartec::export::{escape_braces, escape_terminal};
+use grammartec::rule::Rule;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::tree_sitter;
+
+//Either [NONTERM, RHS] or [NONTERM, RHS, WEIGHT]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonRule {
+    Plain(String, String),
+    Weighted(String, String, f64),
+}
+
+//An expansion of a dictionary grammar, either "RHS" or ["RHS", {"prob": P}] like in fuzzingbook
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Expansion {
+    Plain(String),
+    WithOptions(String, Map<String, Value>),
+}
+
+pub fn load_json_grammar(grammar_path: &str) -> Context {
+    let gf = File::open(grammar_path).expect("cannot read grammar file");
+    let grammar: Value = serde_json::from_reader(&gf)
+        .unwrap_or_else(|e| panic!("{}: cannot parse grammar file: {}", grammar_path, e));
+    match grammar {
+        Value::Array(_) => load_rule_list(grammar_path, grammar),
+        //tree-sitter's src/grammar.json
+        Value::Object(ref dict) if dict.get("rules").is_some_and(Value::is_object) => {
+            tree_sitter::load_tree_sitter_grammar(grammar_path, grammar)
+        }
+        Value::Object(dict) => load_dictionary_grammar(grammar_path, dict),
+        _ => panic!(
+            "{}: expected a list of [NONTERM, RHS] rules or a dictionary like {{\"<start>\": [\"<expr>\"]}}",
+            grammar_path
+        ),
+    }
+}
+
+fn add_rule(ctx: &mut Context, nt: &str, rhs: &[u8], weight: Option<f64>, source: String) {
+    if let Err(e) = Rule::check_format(rhs) {
+        panic!("{}: {}", source, e);
+    }
+    let rid = ctx.add_rule(nt, rhs);
+    if let Some(weight) = weight {
+        assert!(
+            weight.is_finite() && weight >= 0.0,
+            "{}: invalid weight for {}: {}",
+            source,
+            nt,
+            weight
+        );
+        ctx.set_rule_weight(rid, weight);
+    }
+    ctx.set_rule_source(rid, source);
+}
+
+//The first rule's nonterminal is the start symbol
+fn load_rule_list(grammar_path: &str, grammar: Value) -> Context {
+    let rules: Vec<JsonRule> = serde_json::from_value(grammar).unwrap_or_else(|e| {
+        panic!(
+            "{}: expected a list of [NONTERM, RHS] or [NONTERM, RHS, WEIGHT] rules: {}",
+            grammar_path, e
+        )
+    });
+    let mut ctx = Context::new();
+    match rules.first() {
+        //Exported grammars start with START already
+        Some(JsonRule::Plain(nt, _) | JsonRule::Weighted(nt, _, _)) if nt == "START" => {}
+        Some(JsonRule::Plain(nt, _) | JsonRule::Weighted(nt, _, _)) => {
+            ctx.add_rule("START", ("{".to_string() + nt + "}").as_bytes());
+        }
+        None => panic!("rule file didn_t include any rules"),
+    };
+    for (i, rule) in rules.into_iter().enumerate() {
+        //Rules are numbered from 1, like in an editor
+        let source = format!("{grammar_path}: rule {}", i + 1);
+        let (nt, rhs, weight) = match rule {
+            JsonRule::Plain(nt, rhs) => (nt, rhs, None),
+            JsonRule::Weighted(nt, rhs, weight) => (nt, rhs, Some(weight)),
+        };
+        add_rule(&mut ctx, &nt, rhs.as_bytes(), weight, source);
+    }
+    ctx
+}
+
+//Nonterminal names may contain characters that can't be used in {NT}, they are replaced by _
+fn sanitize(name: &str) -> String {
+    let mut res = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    if !res.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        res.insert(0, '_');
+    }
+    res
+}
+
+//Translates the nonterminals and expansions of a dictionary grammar
+struct Translator<'a> {
+    grammar_path: &'a str,
+    //Maps the names in the grammar to nonterminals
+    names: HashMap<String, String>,
+    used: HashSet<String>,
+    //The helper that produces a backslash at the end of a terminal, see escape_terminal
+    backslash: String,
+}
+
+impl<'a> Translator<'a> {
+    fn new(grammar_path: &'a str) -> Self {
+        Translator {
+            grammar_path,
+            names: HashMap::new(),
+            used: HashSet::from(["START".to_string()]),
+            backslash: String::new(),
+        }
+    }
+
+    //Names that become the same after sanitizing, like <a.b> and <a_b>, get a number appended
+    fn nt(&mut self, name: &str) -> String {
+        if let Some(nt) = self.names.get(name) {
+            return nt.clone();
+        }
+        let base = sanitize(name);
+        let nt = self.unused(&base);
+        if nt != base {
+            eprintln!(
+                "warning: {}: <{name}> is renamed to {nt}, {base} is taken",
+                self.grammar_path
+            );
+        }
+        self.names.insert(name.to_string(), nt.clone());
+        nt
+    }
+
+    fn unused(&mut self, base: &str) -> String {
+        let nt = (1..)
+            .map(|i| {
+                if i == 1 {
+                    base.to_string()
+                } else {
+                    format!("{base}_{i}")
+                }
+            })
+            .find(|nt| !self.used.contains(nt))
+            .expect("RAND_1230377745");
+        self.used.insert(nt.clone());
+        nt
+    }
+
+    //Translates "<digit> + <expr>" to "{digit} + {expr}". Like in fuzzingbook, <...> without
+    //spaces and angle brackets inside is a nonterminal, everything else is a terminal.
+    fn expansion(&mut self, ctx: &mut Context, rhs: &str) -> Vec<u8> {
+        let mut res = vec![];
+        let mut rest = rhs;
+        while let Some(start) = rest.find('<') {
+            let candidate = &rest[start + 1..];
+            match candidate.find(['<', '>', ' ']) {
+                Some(end) if end > 0 && candidate[end..].starts_with('>') => {
+                    res.extend(escape_terminal(
+                        ctx,
+                        &rest.as_bytes()[..start],
+                        &self.backslash,
+                    ));
+                    res.extend(format!("{{{}}}", self.nt(&candidate[..end])).into_bytes());
+                    rest = &candidate[end + 1..];
+                }
+                _ => {
+                    res.extend(escape_braces(&rest.as_bytes()[..=start]));
+                    rest = candidate;
+                }
+            }
+        }
+        res.extend(escape_braces(rest.as_bytes()));
+        res
+    }
+}
+
+//Grammars in the fuzzingbook/Gramatron format: {"<start>": ["<expr>"], "<expr>": [...], ...}.
+//<start> is the start symbol, if it doesn't exist the first nonterminal is used.
+fn load_dictionary_grammar(grammar_path: &str, dict: Map<String, Value>) -> Context {
+    let key_to_name = |key: &str| {
+        key.strip_prefix('<')
+            .and_then(|k| k.strip_suffix('>'))
+            .unwrap_or_else(|| {
+                panic!(
+                    "{}: nonterminal {:?} is not of the form <name>",
+                    grammar_path, key
+                )
+            })
+            .to_string()
+    };
+    let mut translator = Translator::new(grammar_path);
+    //Names that don't need to be changed keep them
+    let names = dict.keys().map(|k| key_to_name(k)).collect::<Vec<_>>();
+    for name in names.iter().filter(|name| sanitize(name) == **name) {
+        translator.nt(name);
+    }
+    for name in &names {
+        translator.nt(name);
+    }
+    translator.backslash = translator.unused("backslash");
+    let start = dict
+        .keys()
+        .find(|k| *k == "<start>")
+        .or_else(|| dict.keys().next())
+        .unwrap_or_else(|| panic!("{}: the grammar doesn't contain any rules", grammar_path));
+    let mut ctx = Context::new();
+    let start = translator.nt(&key_to_name(start));
+    ctx.add_rule("START", format!("{{{start}}}").as_bytes());
+    for (key, expansions) in dict.iter() {
+        let nt = translator.nt(&key_to_name(key));
+        let expansions: Vec<Expansion> = serde_json::from_value(expansions.clone())
+            .unwrap_or_else(|e| {
+                panic!(
+                    "{}: the expansions of {} must be a list of strings or [string, options] pairs: {}",
+                    grammar_path, key, e
+                )
+            });
+        let expansions = expansions
+            .into_iter()
+            .map(|expansion| match expansion {
+                Expansion::Plain(rhs) => (rhs, None),
+                Expansion::WithOptions(rhs, options) => {
+                    (rhs, options.get("prob").and_then(Value::as_f64))
+                }
+            })
+            .collect::<Vec<_>>();
+        //Like in fuzzingbook, expansions without a probability share the rest
+        let given = expansions.iter().filter_map(|(_, prob)| *prob).sum::<f64>();
+        let unspecified = expansions.iter().filter(|(_, prob)| prob.is_none()).count();
+        let rest = (1.0 - given).max(0.0) / unspecified.max(1) as f64;
+        let uses_probabilities = unspecified < expansions.len();
+        for (i, (rhs, prob)) in expansions.into_iter().enumerate() {
+            let source = format!("{grammar_path}: {key} expansion {}", i + 1);
+            let weight = if uses_probabilities {
+                Some(prob.unwrap_or(rest))
+            } else {
+                None
+            };
+            let rhs = translator.expansion(&mut ctx, &rhs);
+            add_rule(&mut ctx, &nt, &rhs, weight, source);
+        }
+    }
+    ctx
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use grammar_loader::{load_test_grammar, GrammarOptions};
+    use grammartec::context::Context;
+    use grammartec::export;
+    use grammartec::tree::TreeLike;
+
+    fn load(grammar: &str) -> Context {
+        let mut ctx = load_test_grammar("json", grammar.as_bytes(), &GrammarOptions::default());
+        ctx.initialize(30);
+        ctx
+    }
+
+    fn samples(ctx: &Context, nt: &str) -> HashSet<Vec<u8>> {
+        (0..200)
+            .map(|_| {
+                let tree = ctx.generate_tree_from_nt(ctx.nt_id(nt), 30);
+                tree.unparse_to_vec(ctx)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fuzzingbook_grammar() {
+        let ctx = load(
+            r#"{
+                "<expr>": ["<digit> + <expr>", ["<digit>", {"prob": 0.9}]],
+                "<start>": ["<expr>"],
+                "<digit>": ["0", "1"],
+                "<other>": ["a < b", "<not a nonterminal>", "<>", "{x}"]
+            }"#,
+        );
+        let python = export::to_python(&ctx);
+        assert!(
+            python.contains("ctx.rule(\"START\", b\"{start}\")"),
+            "{}",
+            python
+        );
+        assert!(
+            python.contains("ctx.rule(\"expr\", b\"{digit} + {expr}\", weight=0.09999"),
+            "{}",
+            python
+        );
+        assert!(
+            python.contains("ctx.rule(\"expr\", b\"{digit}\", weight=0.9)"),
+            "{}",
+            python
+        );
+        let others = samples(&ctx, "other");
+        let expected = ["a < b", "<not a nonterminal>", "<>", "{x}"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect::<HashSet<_>>();
+        assert_eq!(others, expected);
+        for expr in samples(&ctx, "START") {
+            let expr = String::from_utf8(expr).expect("RAND_3380193651");
+            assert!(expr.split(" + ").all(|d| d == "0" || d == "1"), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn first_key_without_start() {
+        let ctx = load(r#"{"<a>": ["x<b>"], "<b>": ["y"]}"#);
+        assert_eq!(samples(&ctx, "START"), HashSet::from([b"xy".to_vec()]));
+    }
+
+    #[test]
+    fn names_that_collide_stay_distinct() {
+        let ctx = load(
+            r#"{
+                "<start>": ["<a.b><a_b><a-b><1><START>"],
+                "<a.b>": ["1"],
+                "<a_b>": ["2"],
+                "<a-b>": ["3"],
+                "<1>": ["4"],
+                "<START>": ["5"]
+            }"#,
+        );
+        assert_eq!(samples(&ctx, "START"), HashSet::from([b"12345".to_vec()]));
+        //Names that are valid already are kept
+        assert_eq!(samples(&ctx, "a_b"), HashSet::from([b"2".to_vec()]));
+        assert_eq!(samples(&ctx, "a_b_2"), HashSet::from([b"1".to_vec()]));
+        assert_eq!(samples(&ctx, "_1"), HashSet::from([b"4".to_vec()]));
+    }
+
+    #[test]
+    fn backslash_before_nonterminal() {
+        let ctx = load(
+            r#"{"<start>": ["\\<c>\\\\", "<backslash>"], "<c>": ["n"], "<backslash>": ["b"]}"#,
+        );
+        let expected = ["\\n\\\\", "b"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect::<HashSet<_>>();
+        assert_eq!(samples(&ctx, "START"), expected);
+    }
+
+    #[test]
+    fn rule_list() {
+        let ctx = load(r#"[["S", "a{T}"], ["T", "b", 2.0], ["T", "\\{c\\}"]]"#);
+        let python = export::to_python(&ctx);
+        assert!(
+            python.contains("ctx.rule(\"T\", b\"b\", weight=2.0)"),
+            "{}",
+            python
+        );
+        let expected = ["ab", "a{c}"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect::<HashSet<_>>();
+        assert_eq!(samples(&ctx, "START"), expected);
+    }
+}
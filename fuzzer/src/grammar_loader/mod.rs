@@ -0,0 +1,88 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::path::Path;
+
+use grammartec::context::Context;
+
+mod abnf;
+mod antlr;
+mod json;
+mod protobuf;
+mod python;
+mod tree_sitter;
+
+//Settings of the loaders that are not part of the grammar file
+#[derive(Default)]
+pub struct GrammarOptions {
+    //Protobuf: also generate wrong length prefixes
+    pub break_framing: bool,
+}
+
+//Loads a grammar, the format is picked by the file extension: .json (a list of rules, a
+//fuzzingbook style dictionary or a tree-sitter grammar.json), .py, .g4 (ANTLR4), .abnf or .proto
+pub fn load_grammar(grammar_path: &str, options: &GrammarOptions) -> Context {
+    match Path::new(grammar_path).extension().and_then(|e| e.to_str()) {
+        Some("json") => json::load_json_grammar(grammar_path),
+        Some("py") => python::load_python_grammar(grammar_path),
+        Some("g4") => antlr::load_antlr_grammar(grammar_path),
+        Some("abnf") => abnf::load_abnf_grammar(grammar_path),
+        Some("proto") => protobuf::load_protobuf_grammar(grammar_path, options.break_framing),
+        _ => panic!(
+            "Unknown grammar type {:?}, expected .json, .py, .g4, .abnf or .proto",
+            grammar_path
+        ),
+    }
+}
+
+//Loads a grammar given as string, for the tests of the loaders
+#[cfg(test)]
+pub fn load_test_grammar(extension: &str, grammar: &[u8], options: &GrammarOptions) -> Context {
+    use std::fs;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "nautilus-grammar-{}-{}.{extension}",
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::write(&path, grammar).expect("RAND_2049338106");
+    let ctx = load_grammar(path.to_str().expect("RAND_2049338106"), options);
+    fs::remove_file(&path).expect("RAND_2049338106");
+    ctx
+}
+
+#[cfg(test)]
+mod tests {
+    use grammar_loader::{load_grammar, load_test_grammar, GrammarOptions};
+    use grammartec::export;
+
+    //The exported grammar can be loaded again and exporting that gives the same file
+    #[test]
+    fn export_import_export() {
+        let options = GrammarOptions::default();
+        let ctx = load_grammar("../grammars/javascript_new.py", &options);
+        let python = export::to_python(&ctx);
+        let ctx = load_test_grammar("py", python.as_bytes(), &options);
+        assert_eq!(export::to_python(&ctx), python);
+
+        let json = export::to_json(&ctx).expect("RAND_2416931290");
+        let ctx = load_test_grammar("json", json.as_bytes(), &options);
+        assert_eq!(export::to_json(&ctx).expect("RAND_2416931290"), json);
+    }
+}
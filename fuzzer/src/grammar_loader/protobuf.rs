@@ -0,0 +1,1035 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use grammartec::context::Context;
+use grammartec::export::{escape_braces, escape_terminal};
+use grammartec::rule::NativeScript;
+
+//Turns the messages of a .proto file into a grammar of their binary wire format. Every message
+//becomes a nonterminal named like its fully qualified name with _ instead of .
+//(package_Outer_Inner) that produces its fields in declaration order, fields are nonterminals
+//named MESSAGE-FIELD. Length prefixes are computed by native scripts when the tree is unparsed, so
+//they stay correct after mutations. The first message of the file is the start symbol.
+
+//Length prefixed nonterminals are named like this, followed by the nonterminal of the content
+const FRAMED_PREFIX: &str = "_len-";
+
+//Weight of each broken length prefix, the correct one has weight 1
+const BROKEN_FRAMING_WEIGHT: f64 = 0.1;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    //Numbers, kept as text because they are only parsed where needed
+    Number(String),
+    Str(String),
+    Punct(char),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Label {
+    //proto3 fields without a label are optional as well
+    Optional,
+    Required,
+    Repeated,
+}
+
+struct Field {
+    name: String,
+    number: u64,
+    label: Label,
+    ty: String,
+    packed: Option<bool>,
+}
+
+enum Item {
+    Field(Field),
+    Oneof(String, Vec<Field>),
+}
+
+struct Message {
+    //Name within the package, e.g. Outer.Inner
+    name: String,
+    items: Vec<Item>,
+    proto3: bool,
+}
+
+struct ProtoFile {
+    package: String,
+    proto3: bool,
+    imports: Vec<String>,
+    //Fully qualified names of all messages and enums, including nested ones
+    messages: Vec<(String, Message)>,
+    enums: Vec<(String, Vec<i64>)>,
+}
+
+fn warn(source: &str, line: usize, msg: &str) {
+    eprintln!("warning: {source}:{line}: {msg}");
+}
+
+fn tokenize(source: &str, text: &str) -> Vec<(Token, usize)> {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut res = vec![];
+    let mut line = 1;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c == '\n' {
+            line += 1;
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                line += usize::from(chars[i] == '\n');
+                i += 1;
+            }
+            i += 2;
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            res.push((Token::Ident(chars[start..i].iter().collect()), line));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            res.push((Token::Number(chars[start..i].iter().collect()), line));
+        } else if c == '"' || c == '\'' {
+            let start = i + 1;
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                i += if chars[i] == '\\' { 2 } else { 1 };
+            }
+            let value = chars[start..i.min(chars.len())].iter().collect();
+            i += 1;
+            res.push((Token::Str(value), line));
+        } else if "{}[]()<>;=,.-+:/".contains(c) {
+            res.push((Token::Punct(c), line));
+            i += 1;
+        } else {
+            warn(source, line, &format!("unexpected character {c:?}"));
+            i += 1;
+        }
+    }
+    res
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    file: ProtoFile,
+}
+
+impl<'a> Parser<'a> {
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or(self.tokens.last())
+            .map_or(0, |t| t.1)
+    }
+
+    fn error(&self, msg: &str) -> ! {
+        panic!("{}:{}: {}", self.source, self.line(), msg);
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|t| &t.0)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn is_punct(&self, c: char) -> bool {
+        self.peek() == Some(&Token::Punct(c))
+    }
+
+    fn expect_punct(&mut self, c: char) {
+        if !self.is_punct(c) {
+            self.error(&format!("expected {c:?}, found {:?}", self.peek()));
+        }
+        self.pos += 1;
+    }
+
+    fn ident(&mut self) -> String {
+        match self.next() {
+            Some(Token::Ident(id)) => id,
+            other => self.error(&format!("expected a name, found {other:?}")),
+        }
+    }
+
+    //Names like foo.Bar or .foo.Bar
+    fn full_ident(&mut self) -> String {
+        let mut name = String::new();
+        if self.is_punct('.') {
+            self.pos += 1;
+            name.push('.');
+        }
+        name.push_str(&self.ident());
+        while self.is_punct('.') {
+            self.pos += 1;
+            name.push('.');
+            name.push_str(&self.ident());
+        }
+        name
+    }
+
+    fn number(&mut self) -> i64 {
+        let negative = self.is_punct('-');
+        if negative {
+            self.pos += 1;
+        }
+        let text = match self.next() {
+            Some(Token::Number(text)) => text,
+            other => self.error(&format!("expected a number, found {other:?}")),
+        };
+        let value = if let Some(hex) = text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+            i64::from_str_radix(hex, 16)
+        } else if text.len() > 1 && text.starts_with('0') {
+            i64::from_str_radix(&text[1..], 8)
+        } else {
+            text.parse()
+        };
+        let value = value.unwrap_or_else(|_| self.error(&format!("invalid number {text}")));
+        if negative {
+            -value
+        } else {
+            value
+        }
+    }
+
+    //Skips a statement up to its ; or a block up to its }
+    fn skip_statement(&mut self) {
+        let mut depth = 0;
+        while let Some(token) = self.next() {
+            match token {
+                Token::Punct('{') => depth += 1,
+                Token::Punct('}') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return;
+                    }
+                }
+                Token::Punct(';') if depth == 0 => return,
+                _ => {}
+            }
+        }
+    }
+
+    fn parse_file(&mut self) {
+        while let Some(token) = self.next() {
+            match token {
+                Token::Ident(id) if id == "syntax" || id == "edition" => {
+                    self.expect_punct('=');
+                    if let Some(Token::Str(value)) = self.next() {
+                        //Like proto3, editions pack repeated scalars by default
+                        self.file.proto3 = value != "proto2";
+                    }
+                    self.expect_punct(';');
+                }
+                Token::Ident(id) if id == "package" => {
+                    self.file.package = self.full_ident();
+                    self.expect_punct(';');
+                }
+                Token::Ident(id) if id == "import" => {
+                    if let Some(Token::Ident(_)) = self.peek() {
+                        //public or weak
+                        self.pos += 1;
+                    }
+                    match self.next() {
+                        Some(Token::Str(path)) => self.file.imports.push(path),
+                        other => self.error(&format!("expected a file name, found {other:?}")),
+                    }
+                    self.expect_punct(';');
+                }
+                Token::Ident(id) if id == "message" => {
+                    let name = self.ident();
+                    self.parse_message(name);
+                }
+                Token::Ident(id) if id == "enum" => {
+                    let name = self.ident();
+                    self.parse_enum(name);
+                }
+                Token::Ident(id) if id == "extend" => {
+                    warn(self.source, self.line(), "extensions are ignored");
+                    self.skip_statement();
+                }
+                Token::Punct(';') => {}
+                //option and service
+                _ => self.skip_statement(),
+            }
+        }
+    }
+
+    fn qualified(&self, name: &str) -> String {
+        if self.file.package.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", self.file.package, name)
+        }
+    }
+
+    fn parse_enum(&mut self, name: String) {
+        self.expect_punct('{');
+        let mut values = vec![];
+        while !self.is_punct('}') {
+            match self.next() {
+                Some(Token::Ident(id)) if id == "option" || id == "reserved" => {
+                    self.skip_statement()
+                }
+                Some(Token::Ident(_)) => {
+                    self.expect_punct('=');
+                    values.push(self.number());
+                    self.skip_statement();
+                }
+                Some(Token::Punct(';')) => {}
+                other => self.error(&format!("unexpected {other:?} in enum")),
+            }
+        }
+        self.pos += 1;
+        let full_name = self.qualified(&name);
+        self.file.enums.push((full_name, values));
+    }
+
+    fn parse_field_options(&mut self) -> Option<bool> {
+        let mut packed = None;
+        if self.is_punct('[') {
+            while let Some(token) = self.next() {
+                match token {
+                    Token::Ident(id) if id == "packed" => {
+                        self.expect_punct('=');
+                        packed = Some(self.ident() == "true");
+                    }
+                    Token::Punct(']') => break,
+                    _ => {}
+                }
+            }
+        }
+        self.expect_punct(';');
+        packed
+    }
+
+    fn parse_field(&mut self, label: Label, ty: String) -> Field {
+        let name = self.ident();
+        self.expect_punct('=');
+        let number = self.number() as u64;
+        let packed = self.parse_field_options();
+        Field {
+            name,
+            number,
+            label,
+            ty,
+            packed,
+        }
+    }
+
+    fn parse_message(&mut self, name: String) {
+        self.expect_punct('{');
+        let mut items = vec![];
+        loop {
+            let token = self
+                .next()
+                .unwrap_or_else(|| self.error("unexpected end of file in message"));
+            let label = match token {
+                Token::Punct('}') => break,
+                Token::Punct(';') => continue,
+                Token::Ident(ref id) if id == "message" => {
+                    let nested = self.ident();
+                    self.parse_message(format!("{name}.{nested}"));
+                    continue;
+                }
+                Token::Ident(ref id) if id == "enum" => {
+                    let nested = self.ident();
+                    self.parse_enum(format!("{name}.{nested}"));
+                    continue;
+                }
+                Token::Ident(ref id) if id == "oneof" => {
+                    let oneof = self.ident();
+                    self.expect_punct('{');
+                    let mut fields = vec![];
+                    while !self.is_punct('}') {
+                        match self.peek() {
+                            Some(Token::Ident(id)) if id == "option" => self.skip_statement(),
+                            Some(Token::Punct(';')) => self.pos += 1,
+                            _ => {
+                                let ty = self.full_ident();
+                                fields.push(self.parse_field(Label::Optional, ty));
+                            }
+                        }
+                    }
+                    self.pos += 1;
+                    items.push(Item::Oneof(oneof, fields));
+                    continue;
+                }
+                Token::Ident(ref id) if id == "map" => {
+                    //map<K, V> name = N; is a repeated message with key = 1 and value = 2
+                    self.expect_punct('<');
+                    let key = self.full_ident();
+                    self.expect_punct(',');
+                    let value = self.full_ident();
+                    self.expect_punct('>');
+                    let field = self.parse_field(Label::Repeated, String::new());
+                    let entry = format!("{name}.{}Entry", field.name);
+                    let entry_field = |name: &str, number, ty| {
+                        Item::Field(Field {
+                            name: name.to_string(),
+                            number,
+                            label: Label::Optional,
+                            ty,
+                            packed: None,
+                        })
+                    };
+                    let message = Message {
+                        name: entry.clone(),
+                        items: vec![entry_field("key", 1, key), entry_field("value", 2, value)],
+                        proto3: self.file.proto3,
+                    };
+                    self.file.messages.push((self.qualified(&entry), message));
+                    items.push(Item::Field(Field {
+                        ty: format!(".{}", self.qualified(&entry)),
+                        ..field
+                    }));
+                    continue;
+                }
+                Token::Ident(ref id)
+                    if id == "option"
+                        || id == "reserved"
+                        || id == "extensions"
+                        || id == "extend" =>
+                {
+                    self.skip_statement();
+                    continue;
+                }
+                Token::Ident(ref id) if id == "optional" => Label::Optional,
+                Token::Ident(ref id) if id == "required" => Label::Required,
+                Token::Ident(ref id) if id == "repeated" => Label::Repeated,
+                //Fields without a label
+                Token::Ident(_) | Token::Punct('.') => {
+                    self.pos -= 1;
+                    Label::Optional
+                }
+                other => self.error(&format!("unexpected {other:?} in message")),
+            };
+            let ty = self.full_ident();
+            if ty == "group" {
+                warn(self.source, self.line(), "groups are ignored");
+                self.skip_statement();
+                continue;
+            }
+            items.push(Item::Field(self.parse_field(label, ty)));
+        }
+        let full_name = self.qualified(&name);
+        let proto3 = self.file.proto3;
+        self.file.messages.push((
+            full_name,
+            Message {
+                name,
+                items,
+                proto3,
+            },
+        ));
+    }
+}
+
+fn parse_proto_file(path: &Path) -> ProtoFile {
+    let source = path.display().to_string();
+    let text = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("cannot read grammar file {}: {}", source, e));
+    let mut parser = Parser {
+        source: &source,
+        tokens: tokenize(&source, &text),
+        pos: 0,
+        file: ProtoFile {
+            package: String::new(),
+            proto3: false,
+            imports: vec![],
+            messages: vec![],
+            enums: vec![],
+        },
+    };
+    parser.parse_file();
+    parser.file
+}
+
+fn varint(mut value: u64) -> Vec<u8> {
+    let mut res = vec![];
+    while value >= 0x80 {
+        res.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    res.push(value as u8);
+    res
+}
+
+fn length_delimited(args: &[Vec<u8>]) -> Vec<u8> {
+    [varint(args[0].len() as u64), args[0].clone()].concat()
+}
+
+//Broken length prefixes for robustness testing
+fn length_too_long(args: &[Vec<u8>]) -> Vec<u8> {
+    [varint(args[0].len() as u64 + 1), args[0].clone()].concat()
+}
+
+//Empty content has no shorter length, it gets one that is too long instead
+fn length_too_short(args: &[Vec<u8>]) -> Vec<u8> {
+    let len = args[0].len() as u64;
+    if len / 2 == len {
+        return length_too_long(args);
+    }
+    [varint(len / 2), args[0].clone()].concat()
+}
+
+fn length_huge(args: &[Vec<u8>]) -> Vec<u8> {
+    [varint(u64::from(u32::MAX)), args[0].clone()].concat()
+}
+
+fn length_missing(args: &[Vec<u8>]) -> Vec<u8> {
+    args[0].clone()
+}
+
+enum Kind {
+    Message,
+    Enum,
+}
+
+struct Converter<'a> {
+    source: &'a str,
+    break_framing: bool,
+    //Fully qualified name to the kind and the nonterminal
+    types: HashMap<String, (Kind, String)>,
+    //Nonterminals that were added already
+    added: HashSet<String>,
+}
+
+impl<'a> Converter<'a> {
+    fn add_rule(&self, ctx: &mut Context, nt: &str, format: &[u8]) {
+        let rid = ctx.add_rule(nt, format);
+        ctx.set_rule_source(rid, self.source.to_string());
+    }
+
+    //Resolves a type name like protoc does, from the innermost scope outwards
+    fn resolve(&self, scope: &str, name: &str) -> Option<&(Kind, String)> {
+        if let Some(name) = name.strip_prefix('.') {
+            return self.types.get(name);
+        }
+        let mut scope = scope.to_string();
+        loop {
+            let candidate = if scope.is_empty() {
+                name.to_string()
+            } else {
+                format!("{scope}.{name}")
+            };
+            if let Some(t) = self.types.get(&candidate) {
+                return Some(t);
+            }
+            if scope.is_empty() {
+                return None;
+            }
+            scope.truncate(scope.rfind('.').unwrap_or(0));
+        }
+    }
+
+    //Adds the nonterminal of a scalar type, returns its wire type and nonterminal
+    fn scalar(&mut self, ctx: &mut Context, ty: &str) -> Option<(u64, &'static str)> {
+        let (wire_type, nt) = match ty {
+            "int32" | "int64" | "uint32" | "uint64" | "sint32" | "sint64" => (0, "_varint"),
+            "bool" => (0, "_bool"),
+            "fixed64" | "sfixed64" | "double" => (1, "_fixed64"),
+            "string" => (2, "_string"),
+            "bytes" => (2, "_bytes"),
+            "fixed32" | "sfixed32" | "float" => (5, "_fixed32"),
+            _ => return None,
+        };
+        if self.added.insert(nt.to_string()) {
+            match nt {
+                "_varint" => {
+                    //At most 9 bytes, so that the value fits into 64 bits
+                    ctx.add_regex(nt, "(?-u:[\\x80-\\xFF]{0,8}[\\x00-\\x7F])");
+                    self.add_rule(ctx, nt, b"\x00");
+                    self.add_rule(ctx, nt, &escape_braces(&varint(u64::MAX)));
+                }
+                "_bool" => {
+                    self.add_rule(ctx, nt, b"\x00");
+                    self.add_rule(ctx, nt, b"\x01");
+                }
+                "_fixed64" => {
+                    ctx.add_regex(nt, "(?-u:[\\x00-\\xFF]{8})");
+                }
+                "_fixed32" => {
+                    ctx.add_regex(nt, "(?-u:[\\x00-\\xFF]{4})");
+                }
+                "_string" => {
+                    ctx.add_regex(nt, "[\\x20-\\x7E]*");
+                }
+                _ => {
+                    ctx.add_regex(nt, "(?-u:[\\x00-\\xFF]*)");
+                }
+            }
+        }
+        Some((wire_type, nt))
+    }
+
+    //The nonterminal that produces the content of nt prefixed with its length
+    fn framed(&mut self, ctx: &mut Context, nt: &str) -> String {
+        let framed = format!("{FRAMED_PREFIX}{nt}");
+        if self.added.insert(framed.clone()) {
+            let args = [nt.to_string()];
+            let rid = ctx.add_native_script(&framed, &args, length_delimited);
+            ctx.set_rule_source(rid, self.source.to_string());
+            if self.break_framing {
+                let broken: [NativeScript; 4] = [
+                    length_too_long,
+                    length_too_short,
+                    length_huge,
+                    length_missing,
+                ];
+                for script in broken {
+                    let rid = ctx.add_native_script(&framed, &args, script);
+                    ctx.set_rule_weight(rid, BROKEN_FRAMING_WEIGHT);
+                    ctx.set_rule_source(rid, self.source.to_string());
+                }
+            }
+        }
+        framed
+    }
+
+    //The encoding of a single value of the field: its tag and a reference to the value
+    fn value(&mut self, ctx: &mut Context, scope: &str, field: &Field) -> (u64, String) {
+        let (wire_type, nt) = if let Some((wire_type, nt)) = self.scalar(ctx, &field.ty) {
+            (wire_type, nt.to_string())
+        } else {
+            match self.resolve(scope, &field.ty) {
+                Some((Kind::Message, nt)) => (2, nt.clone()),
+                Some((Kind::Enum, nt)) => (0, nt.clone()),
+                None => {
+                    eprintln!(
+                        "warning: {}: unknown type {} of field {}, it is generated as bytes",
+                        self.source, field.ty, field.name
+                    );
+                    let (wire_type, nt) = self.scalar(ctx, "bytes").expect("RAND_2755046117");
+                    (wire_type, nt.to_string())
+                }
+            }
+        };
+        if wire_type == 2 {
+            (wire_type, self.framed(ctx, &nt))
+        } else {
+            (wire_type, nt)
+        }
+    }
+
+    fn tag(ctx: &mut Context, field: &Field, wire_type: u64) -> Vec<u8> {
+        escape_terminal(ctx, &varint((field.number << 3) | wire_type), "_backslash")
+    }
+
+    //The format string of a field that is present once
+    fn entry(&mut self, ctx: &mut Context, scope: &str, field: &Field) -> Vec<u8> {
+        let (wire_type, nt) = self.value(ctx, scope, field);
+        [
+            Self::tag(ctx, field, wire_type),
+            format!("{{{nt}}}").into_bytes(),
+        ]
+        .concat()
+    }
+
+    fn add_field(&mut self, ctx: &mut Context, scope: &str, nt: &str, field: &Field, proto3: bool) {
+        match field.label {
+            Label::Required => {
+                let entry = self.entry(ctx, scope, field);
+                self.add_rule(ctx, nt, &entry);
+            }
+            Label::Optional => {
+                let entry = self.entry(ctx, scope, field);
+                self.add_rule(ctx, nt, b"");
+                self.add_rule(ctx, nt, &entry);
+            }
+            Label::Repeated => {
+                let (wire_type, value) = self.value(ctx, scope, field);
+                self.add_rule(ctx, nt, b"");
+                //Only numeric values can be packed
+                if wire_type != 2 && field.packed.unwrap_or(proto3) {
+                    let values = format!("{nt}-values");
+                    self.add_rule(ctx, &values, format!("{{{value}}}").as_bytes());
+                    self.add_rule(ctx, &values, format!("{{{value}}}{{{values}}}").as_bytes());
+                    let framed = self.framed(ctx, &values);
+                    let entry = [
+                        Self::tag(ctx, field, 2),
+                        format!("{{{framed}}}").into_bytes(),
+                    ]
+                    .concat();
+                    self.add_rule(ctx, nt, &entry);
+                } else {
+                    let entry = [
+                        Self::tag(ctx, field, wire_type),
+                        format!("{{{value}}}{{{nt}}}").into_bytes(),
+                    ]
+                    .concat();
+                    self.add_rule(ctx, nt, &entry);
+                }
+            }
+        }
+    }
+
+    fn add_message(&mut self, ctx: &mut Context, full_name: &str, nt: &str, message: &Message) {
+        let mut body = vec![];
+        for item in &message.items {
+            match item {
+                Item::Field(field) => {
+                    let field_nt = format!("{nt}-{}", field.name);
+                    self.add_field(ctx, full_name, &field_nt, field, message.proto3);
+                    body.extend(format!("{{{field_nt}}}").into_bytes());
+                }
+                Item::Oneof(name, fields) => {
+                    let oneof_nt = format!("{nt}-{name}");
+                    self.add_rule(ctx, &oneof_nt, b"");
+                    for field in fields {
+                        let entry = self.entry(ctx, full_name, field);
+                        self.add_rule(ctx, &oneof_nt, &entry);
+                    }
+                    body.extend(format!("{{{oneof_nt}}}").into_bytes());
+                }
+            }
+        }
+        self.add_rule(ctx, nt, &body);
+    }
+}
+
+//Loads the file and the files it imports, relative to the importing file
+fn load_files(path: &Path, seen: &mut HashSet<PathBuf>, files: &mut Vec<ProtoFile>) {
+    if !seen.insert(path.to_path_buf()) {
+        return;
+    }
+    let file = parse_proto_file(path);
+    for import in &file.imports {
+        let import_path = path.with_file_name(import);
+        if import_path.exists() {
+            load_files(&import_path, seen, files);
+        } else {
+            eprintln!(
+                "warning: {}: import {} not found, its types are generated as bytes",
+                path.display(),
+                import
+            );
+        }
+    }
+    files.push(file);
+}
+
+pub fn load_protobuf_grammar(grammar_path: &str, break_framing: bool) -> Context {
+    let mut files = vec![];
+    load_files(Path::new(grammar_path), &mut HashSet::new(), &mut files);
+    //The grammar's own file is loaded last, after its imports
+    let main = files.last().expect("RAND_3530990425");
+    let start = main
+        .messages
+        .iter()
+        //Nested messages are added before the message that contains them
+        .find(|(_, m)| !m.name.contains('.'))
+        .unwrap_or_else(|| panic!("{} does not contain any messages", grammar_path))
+        .0
+        .clone();
+
+    let mut converter = Converter {
+        source: grammar_path,
+        break_framing,
+        types: HashMap::new(),
+        added: HashSet::new(),
+    };
+    //Messages of different packages may have the same name, so the package is part of the
+    //nonterminal. Names that become the same (a.B_C and a.B.C) get a number appended.
+    let mut used = [
+        "START",
+        "_varint",
+        "_bool",
+        "_fixed64",
+        "_fixed32",
+        "_string",
+        "_bytes",
+        "_backslash",
+    ]
+    .iter()
+    .map(|nt| nt.to_string())
+    .collect::<HashSet<_>>();
+    let mut nt_name = |full_name: &str| {
+        let base = full_name.replace('.', "_");
+        let nt = (1..)
+            .map(|i| {
+                if i == 1 {
+                    base.clone()
+                } else {
+                    format!("{base}_{i}")
+                }
+            })
+            .find(|nt| !used.contains(nt))
+            .expect("RAND_4127209348");
+        if nt != base {
+            eprintln!("warning: {grammar_path}: {full_name} is renamed to {nt}, {base} is taken");
+        }
+        used.insert(nt.clone());
+        nt
+    };
+    for file in &files {
+        for (full_name, _) in &file.messages {
+            let nt = nt_name(full_name);
+            converter
+                .types
+                .insert(full_name.clone(), (Kind::Message, nt));
+        }
+        for (full_name, _) in &file.enums {
+            let nt = nt_name(full_name);
+            converter.types.insert(full_name.clone(), (Kind::Enum, nt));
+        }
+    }
+
+    let mut ctx = Context::new();
+    let start = &converter.types[&start].1;
+    converter.add_rule(&mut ctx, "START", format!("{{{start}}}").as_bytes());
+    for file in &files {
+        for (full_name, message) in &file.messages {
+            let (_, nt) = &converter.types[full_name];
+            let nt = nt.clone();
+            converter.add_message(&mut ctx, full_name, &nt, message);
+        }
+        for (full_name, values) in &file.enums {
+            let (_, nt) = &converter.types[full_name];
+            let nt = nt.clone();
+            //Aliases share a value, negative values are sign extended to 64 bits
+            let mut seen = HashSet::new();
+            for value in values.iter().filter(|v| seen.insert(**v)) {
+                converter.add_rule(&mut ctx, &nt, &escape_braces(&varint(*value as u64)));
+            }
+        }
+    }
+    ctx
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use grammar_loader::protobuf::{
+        length_delimited, length_huge, length_missing, length_too_long, length_too_short, varint,
+    };
+    use grammar_loader::{load_test_grammar, GrammarOptions};
+    use grammartec::context::Context;
+    use grammartec::export;
+    use grammartec::tree::TreeLike;
+
+    fn load(grammar: &str) -> Context {
+        let mut ctx = load_test_grammar("proto", grammar.as_bytes(), &GrammarOptions::default());
+        ctx.initialize(50);
+        ctx
+    }
+
+    fn samples(ctx: &Context, nt: &str) -> Vec<Vec<u8>> {
+        (0..200)
+            .map(|_| {
+                let tree = ctx.generate_tree_from_nt(ctx.nt_id(nt), 50);
+                tree.unparse_to_vec(ctx)
+            })
+            .collect()
+    }
+
+    fn read_varint(data: &[u8], pos: &mut usize) -> u64 {
+        let mut value = 0;
+        for shift in 0..10 {
+            let byte = data[*pos];
+            *pos += 1;
+            value |= u64::from(byte & 0x7f) << (7 * shift);
+            if byte < 0x80 {
+                return value;
+            }
+        }
+        panic!("varint too long in {:?}", data);
+    }
+
+    //The field numbers and wire types of a message, panics if it is malformed
+    fn fields(data: &[u8]) -> Vec<(u64, u64)> {
+        let mut res = vec![];
+        let mut pos = 0;
+        while pos < data.len() {
+            let tag = read_varint(data, &mut pos);
+            match tag & 7 {
+                0 => {
+                    read_varint(data, &mut pos);
+                }
+                1 => pos += 8,
+                2 => pos += read_varint(data, &mut pos) as usize,
+                5 => pos += 4,
+                wire_type => panic!("wire type {} in {:?}", wire_type, data),
+            }
+            assert!(pos <= data.len(), "{:?}", data);
+            res.push((tag >> 3, tag & 7));
+        }
+        res
+    }
+
+    #[test]
+    fn wire_format() {
+        let ctx = load(
+            r#"
+            syntax = "proto3";
+            message Outer {
+                message Inner { string name = 1; }
+                enum Kind { A = 0; B = -1; }
+                Inner inner = 1;
+                repeated int32 values = 2;
+                Kind kind = 3;
+                map<string, fixed32> entries = 4;
+                oneof choice { double d = 5; bytes b = 6; }
+            }
+            "#,
+        );
+        let python = export::to_python(&ctx);
+        assert!(
+            python.contains("ctx.rule(\"START\", b\"{Outer}\")"),
+            "{}",
+            python
+        );
+        assert!(
+            python.contains("ctx.rule(\"Outer_Inner\", b\"{Outer_Inner-name}\")"),
+            "{}",
+            python
+        );
+        let mut seen = vec![];
+        for message in samples(&ctx, "START") {
+            for (number, wire_type) in fields(&message) {
+                let expected = match number {
+                    1 | 2 | 4 | 6 => 2,
+                    3 => 0,
+                    5 => 1,
+                    _ => panic!("field {} in {:?}", number, message),
+                };
+                assert_eq!(wire_type, expected, "{:?}", message);
+                seen.push(number);
+            }
+        }
+        for number in 1..=6 {
+            assert!(
+                seen.contains(&number),
+                "field {} is never generated",
+                number
+            );
+        }
+    }
+
+    #[test]
+    fn tags_ending_in_backslash() {
+        //The tags of these fields end in 0x5c
+        let ctx = load("message M { int32 a = 1480; string s = 1487; }");
+        let mut seen = vec![];
+        for message in samples(&ctx, "M") {
+            for field in fields(&message) {
+                assert!(field == (1480, 0) || field == (1487, 2), "{:?}", message);
+                seen.push(field.0);
+            }
+        }
+        assert!(seen.contains(&1480) && seen.contains(&1487));
+    }
+
+    #[test]
+    fn same_names_in_different_packages() {
+        let import = format!("nautilus-import-{}.proto", process::id());
+        let import_path = std::env::temp_dir().join(&import);
+        fs::write(&import_path, "package b; message M { int32 x = 1; }").expect("RAND_1867432290");
+        let ctx = load(&format!(
+            "package a; import \"{import}\"; message M {{ b.M other = 2; string s = 3; }}"
+        ));
+        fs::remove_file(&import_path).expect("RAND_1867432290");
+        let python = export::to_python(&ctx);
+        assert!(
+            python.contains("ctx.rule(\"START\", b\"{a_M}\")"),
+            "{}",
+            python
+        );
+        assert!(
+            python.contains("ctx.rule(\"a_M\", b\"{a_M-other}{a_M-s}\")"),
+            "{}",
+            python
+        );
+        assert!(
+            python.contains("ctx.rule(\"b_M\", b\"{b_M-x}\")"),
+            "{}",
+            python
+        );
+        for other in samples(&ctx, "b_M") {
+            assert!(fields(&other).iter().all(|f| *f == (1, 0)), "{:?}", other);
+        }
+    }
+
+    #[test]
+    fn names_that_collide() {
+        let ctx =
+            load("message A_B { int32 x = 1; } message A { message B { int32 y = 2; } B b = 3; }");
+        let python = export::to_python(&ctx);
+        assert!(
+            python.contains("ctx.rule(\"A_B\", b\"{A_B-x}\")"),
+            "{}",
+            python
+        );
+        assert!(
+            python.contains("ctx.rule(\"A_B_2\", b\"{A_B_2-y}\")"),
+            "{}",
+            python
+        );
+        for b in samples(&ctx, "A_B_2") {
+            assert!(fields(&b).iter().all(|f| *f == (2, 0)), "{:?}", b);
+        }
+    }
+
+    #[test]
+    fn broken_lengths() {
+        for content in [vec![], vec![1], vec![1, 2, 3]] {
+            let args = [content.clone()];
+            let mut lengths = vec![];
+            for script in [
+                length_delimited,
+                length_too_long,
+                length_too_short,
+                length_huge,
+                length_missing,
+            ] {
+                let data = script(&args);
+                assert!(data.ends_with(&content));
+                lengths.push(data[..data.len() - content.len()].to_vec());
+            }
+            assert_eq!(lengths[0], varint(content.len() as u64));
+            //Every broken prefix differs from the correct one
+            assert!(
+                lengths[1..].iter().all(|l| *l != lengths[0]),
+                "{:?}",
+                lengths
+            );
+        }
+    }
+}
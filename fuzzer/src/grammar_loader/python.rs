@@ -0,0 +1,157 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use pyo3::prelude::*;
+use pyo3::types::{IntoPyDict, PyBytes, PyString};
+
+use grammartec::context::Context;
+use grammartec::dictionary::load_dictionary;
+use grammartec::newtypes::RuleID;
+use grammartec::rule::{Rule, DEFAULT_WEIGHT};
+
+#[pyclass]
+struct PyContext {
+    ctx: Context,
+    //The grammar file, rules are annotated with their line in it
+    path: String,
+}
+impl PyContext {
+    fn get_context(&self) -> Context {
+        self.ctx.clone()
+    }
+
+    //Rust methods have no frame of their own, so frame 0 is the grammar code calling us
+    fn set_source(&mut self, py: Python, rid: RuleID) {
+        let line = py
+            .import("sys")
+            .and_then(|sys| sys.call_method1("_getframe", (0,)))
+            .and_then(|frame| frame.getattr("f_lineno"))
+            .and_then(|line| line.extract::<usize>());
+        if let Ok(line) = line {
+            self.ctx
+                .set_rule_source(rid, format!("{}:{line}", self.path));
+        }
+    }
+}
+
+#[pymethods]
+impl PyContext {
+    #[new]
+    fn new() -> Self {
+        PyContext {
+            ctx: Context::new(),
+            path: "<grammar>".to_string(),
+        }
+    }
+
+    #[pyo3(signature = (nt, format, weight = DEFAULT_WEIGHT))]
+    fn rule(&mut self, py: Python, nt: &str, format: &PyAny, weight: f64) -> PyResult<()> {
+        check_weight(weight)?;
+        let format = if format.is_instance_of::<PyString>()? {
+            let pystr = <&PyString>::extract(format)?;
+            pystr.to_string_lossy().as_bytes().to_vec()
+        } else if format.is_instance_of::<PyBytes>()? {
+            let pybytes = <&PyBytes>::extract(format)?;
+            pybytes.as_bytes().to_vec()
+        } else {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "format argument should be string or bytes",
+            ));
+        };
+        Rule::check_format(&format).map_err(pyo3::exceptions::PyValueError::new_err)?;
+        let rid = self.ctx.add_rule(nt, &format);
+        self.ctx.set_rule_weight(rid, weight);
+        self.set_source(py, rid);
+        Ok(())
+    }
+
+    #[pyo3(signature = (nt, nts, script, weight = DEFAULT_WEIGHT))]
+    fn script(
+        &mut self,
+        py: Python,
+        nt: &str,
+        nts: Vec<String>,
+        script: PyObject,
+        weight: f64,
+    ) -> PyResult<()> {
+        check_weight(weight)?;
+        let rid = self.ctx.add_script(nt, &nts, script);
+        self.ctx.set_rule_weight(rid, weight);
+        self.set_source(py, rid);
+        Ok(())
+    }
+
+    #[pyo3(signature = (nt, regex, weight = DEFAULT_WEIGHT))]
+    fn regex(&mut self, py: Python, nt: &str, regex: &str, weight: f64) -> PyResult<()> {
+        check_weight(weight)?;
+        Rule::parse_regex(regex).map_err(pyo3::exceptions::PyValueError::new_err)?;
+        let rid = self.ctx.add_regex(nt, regex);
+        self.ctx.set_rule_weight(rid, weight);
+        self.set_source(py, rid);
+        Ok(())
+    }
+
+    #[pyo3(signature = (nt, path))]
+    fn dictionary(&mut self, nt: Option<&str>, path: &str) -> PyResult<()> {
+        let tokens = load_dictionary(path).map_err(pyo3::exceptions::PyValueError::new_err)?;
+        self.ctx
+            .add_dictionary(nt, &tokens)
+            .map_err(pyo3::exceptions::PyValueError::new_err)
+    }
+}
+
+fn check_weight(weight: f64) -> PyResult<()> {
+    if weight.is_finite() && weight >= 0.0 {
+        Ok(())
+    } else {
+        Err(pyo3::exceptions::PyValueError::new_err(
+            "weight should be a non-negative number",
+        ))
+    }
+}
+
+fn main_(py: Python, grammar_path: &str) -> PyResult<Context> {
+    let py_ctx = PyCell::new(
+        py,
+        PyContext {
+            ctx: Context::new(),
+            path: grammar_path.to_string(),
+        },
+    )
+    .unwrap();
+    let globals = [("ctx", py_ctx)].into_py_dict(py);
+    //Compiled with the path as file name, so that tracebacks point into the grammar
+    let builtins = py.import("builtins")?;
+    let code = builtins.call_method1(
+        "compile",
+        (
+            std::fs::read_to_string(grammar_path).expect("couldn't read grammar file"),
+            grammar_path,
+            "exec",
+        ),
+    )?;
+    builtins.call_method1("exec", (code, globals))?;
+    return Ok(py_ctx.borrow().get_context());
+}
+
+pub fn load_python_grammar(grammar_path: &str) -> Context {
+    pyo3::prepare_freethreaded_python();
+    Python::with_gil(|py| {
+        main_(py, grammar_path)
+            .map_err(|e| e.print_and_set_sys_last_vars(py))
+            .unwrap()
+    })
+}
@@ -0,0 +1,602 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashSet;
+
+use grammartec::context::Context;
+use grammartec::export::escape_terminal;
+use grammartec::rule::Rule;
+use grammartec::validation::regex_problem;
+use regex_syntax::hir::{Class, ClassUnicode, ClassUnicodeRange, Group, Hir, HirKind, Repetition};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+//Loads the src/grammar.json that tree-sitter generates from grammar.js. Every rule becomes a
+//nonterminal of the same name, the first rule is the start symbol. Tokens and patterns become
+//regex rules, choices and repetitions inside of rules become helper nonterminals named RULE_N.
+//Tree-sitter skips whitespace (extras) between tokens, so the elements of a sequence are
+//separated by a space unless the next one is an immediate token. External scanner tokens can't be
+//generated, they are reported and produce nothing.
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum Node {
+    #[serde(rename = "BLANK")]
+    Blank,
+    #[serde(rename = "STRING")]
+    Str { value: String },
+    #[serde(rename = "PATTERN")]
+    Pattern {
+        value: String,
+        #[serde(default)]
+        flags: Option<String>,
+    },
+    #[serde(rename = "SYMBOL")]
+    Symbol { name: String },
+    #[serde(rename = "SEQ")]
+    Seq { members: Vec<Node> },
+    #[serde(rename = "CHOICE")]
+    Choice { members: Vec<Node> },
+    #[serde(rename = "REPEAT")]
+    Repeat { content: Box<Node> },
+    #[serde(rename = "REPEAT1")]
+    Repeat1 { content: Box<Node> },
+    #[serde(rename = "OPTIONAL")]
+    Optional { content: Box<Node> },
+    #[serde(rename = "TOKEN")]
+    Token { content: Box<Node> },
+    #[serde(rename = "IMMEDIATE_TOKEN")]
+    ImmediateToken { content: Box<Node> },
+    //Nodes that only matter for parsing, they generate their content
+    #[serde(
+        rename = "PREC",
+        alias = "PREC_LEFT",
+        alias = "PREC_RIGHT",
+        alias = "PREC_DYNAMIC",
+        alias = "ALIAS",
+        alias = "FIELD",
+        alias = "RESERVED"
+    )]
+    Wrapper { content: Box<Node> },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize)]
+struct TreeSitterGrammar {
+    rules: Map<String, Value>,
+    #[serde(default)]
+    externals: Vec<Value>,
+}
+
+//A converted part of a sequence, immediate tokens are not preceded by a space
+struct Part {
+    data: Vec<u8>,
+    immediate: bool,
+}
+
+impl Part {
+    fn new(data: Vec<u8>) -> Self {
+        Part {
+            data,
+            immediate: false,
+        }
+    }
+}
+
+fn join(parts: Vec<Part>) -> Vec<u8> {
+    let mut res = vec![];
+    for part in parts {
+        if part.data.is_empty() {
+            continue;
+        }
+        if !res.is_empty() && !part.immediate {
+            res.push(b' ');
+        }
+        res.extend(part.data);
+    }
+    res
+}
+
+//Tree-sitter patterns are JavaScript regexes. Escaped punctuation like \/ is not allowed by
+//regex_syntax, so it is written as a hex escape instead.
+fn translate_pattern(pattern: &str) -> String {
+    let mut res = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(c) if c.is_ascii_punctuation() => res.push_str(&format!("\\x{{{:x}}}", c as u32)),
+            Some('0') => res.push_str("\\x00"),
+            Some(c) => {
+                res.push('\\');
+                res.push(c);
+            }
+            None => res.push_str("\\\\"),
+        }
+    }
+    res
+}
+
+//The regex generator only handles ASCII classes. Classes are restricted to ASCII, classes without
+//ASCII characters are dropped.
+fn restrict_to_ascii(hir: Hir, dropped: &mut bool) -> Hir {
+    match hir.into_kind() {
+        HirKind::Class(Class::Unicode(mut cls)) => {
+            cls.intersect(&ClassUnicode::new(vec![ClassUnicodeRange::new(
+                '\0', '\x7F',
+            )]));
+            if cls.ranges().is_empty() {
+                *dropped = true;
+                Hir::empty()
+            } else {
+                Hir::class(Class::Unicode(cls))
+            }
+        }
+        HirKind::Repetition(rep) => Hir::repetition(Repetition {
+            kind: rep.kind,
+            greedy: rep.greedy,
+            hir: Box::new(restrict_to_ascii(*rep.hir, dropped)),
+        }),
+        HirKind::Group(group) => Hir::group(Group {
+            kind: group.kind,
+            hir: Box::new(restrict_to_ascii(*group.hir, dropped)),
+        }),
+        HirKind::Concat(hirs) => Hir::concat(
+            hirs.into_iter()
+                .map(|h| restrict_to_ascii(h, dropped))
+                .collect(),
+        ),
+        HirKind::Alternation(hirs) => Hir::alternation(
+            hirs.into_iter()
+                .map(|h| restrict_to_ascii(h, dropped))
+                .collect(),
+        ),
+        HirKind::Class(cls) => Hir::class(cls),
+        HirKind::Literal(lit) => Hir::literal(lit),
+        //Anchors and word boundaries don't generate anything
+        HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) => Hir::empty(),
+    }
+}
+
+fn is_immediate_token(node: &Node) -> bool {
+    match node {
+        Node::ImmediateToken { .. } => true,
+        Node::Wrapper { content } => is_immediate_token(content),
+        _ => false,
+    }
+}
+
+struct Converter<'a> {
+    source: &'a str,
+    //Names of rules and helpers
+    names: HashSet<String>,
+    externals: HashSet<String>,
+    reported_externals: HashSet<String>,
+    //Rules that are immediate tokens, they are not preceded by a space either
+    immediate_rules: HashSet<String>,
+    //The helper that produces a backslash at the end of a string, see escape_terminal
+    backslash: String,
+}
+
+impl<'a> Converter<'a> {
+    fn report(&self, rule: &str, msg: &str) {
+        eprintln!("warning: {}: {}: {}", self.source, rule, msg);
+    }
+
+    fn helper_name(&mut self, rule: &str) -> String {
+        let name = (1..)
+            .map(|i| format!("{rule}_{i}"))
+            .find(|name| !self.names.contains(name))
+            .expect("RAND_1028374651");
+        self.names.insert(name.clone());
+        name
+    }
+
+    fn add_rule(&self, ctx: &mut Context, nt: &str, format: &[u8], rule: &str) {
+        let rid = ctx.add_rule(nt, format);
+        ctx.set_rule_source(rid, format!("{}: {}", self.source, rule));
+    }
+
+    fn add_regex(&self, ctx: &mut Context, nt: &str, regex: &str, rule: &str) {
+        let rid = ctx.add_regex(nt, regex);
+        ctx.set_rule_source(rid, format!("{}: {}", self.source, rule));
+    }
+
+    //The regex syntax of a token, tokens can't contain symbols
+    fn token_regex(&self, node: &Node) -> Option<String> {
+        match node {
+            Node::Blank => Some(String::new()),
+            Node::Str { value } => Some(regex_syntax::escape(value)),
+            Node::Pattern { value, flags } => {
+                let flags = flags.as_deref().unwrap_or("").replace(['g', 'u', 'y'], "");
+                let pattern = translate_pattern(value);
+                if flags.is_empty() {
+                    Some(format!("(?:{pattern})"))
+                } else {
+                    Some(format!("(?{flags}:{pattern})"))
+                }
+            }
+            Node::Seq { members } => members.iter().map(|m| self.token_regex(m)).collect(),
+            Node::Choice { members } => {
+                let alts = members
+                    .iter()
+                    .map(|m| self.token_regex(m))
+                    .collect::<Option<Vec<_>>>()?;
+                Some(format!("(?:{})", alts.join("|")))
+            }
+            Node::Repeat { content } => Some(format!("(?:{})*", self.token_regex(content)?)),
+            Node::Repeat1 { content } => Some(format!("(?:{})+", self.token_regex(content)?)),
+            Node::Optional { content } => Some(format!("(?:{})?", self.token_regex(content)?)),
+            Node::Token { content }
+            | Node::ImmediateToken { content }
+            | Node::Wrapper { content } => self.token_regex(content),
+            Node::Symbol { .. } | Node::Unknown => None,
+        }
+    }
+
+    //Turns a token into a regex that can be generated
+    fn generatable_regex(&self, rule: &str, node: &Node) -> Option<String> {
+        let regex = self.token_regex(node)?;
+        let hir = match Rule::parse_regex(&regex) {
+            Ok(hir) => hir,
+            Err(e) => {
+                self.report(rule, &e);
+                return None;
+            }
+        };
+        let mut dropped = false;
+        let hir = restrict_to_ascii(hir, &mut dropped);
+        if dropped {
+            self.report(
+                rule,
+                "character classes without ASCII characters generate nothing",
+            );
+        }
+        if let Some(problem) = regex_problem(&hir) {
+            self.report(rule, &format!("regex can't be generated: {problem}"));
+            return None;
+        }
+        Some(hir.to_string())
+    }
+
+    fn token(&mut self, ctx: &mut Context, rule: &str, content: &Node, immediate: bool) -> Part {
+        if let Node::Str { value } = content {
+            return Part {
+                data: escape_terminal(ctx, value.as_bytes(), &self.backslash),
+                immediate,
+            };
+        }
+        match self.generatable_regex(rule, content) {
+            Some(regex) => {
+                let helper = self.helper_name(rule);
+                self.add_regex(ctx, &helper, &regex, rule);
+                Part {
+                    data: format!("{{{helper}}}").into_bytes(),
+                    immediate,
+                }
+            }
+            None => self.token_fallback(ctx, rule, content, immediate),
+        }
+    }
+
+    //A token that can't be a regex is generated like a rule. Patterns were reported by
+    //generatable_regex already, they generate nothing.
+    fn token_fallback(
+        &mut self,
+        ctx: &mut Context,
+        rule: &str,
+        content: &Node,
+        immediate: bool,
+    ) -> Part {
+        match content {
+            Node::Pattern { .. } => Part {
+                data: vec![],
+                immediate,
+            },
+            Node::Token { content }
+            | Node::ImmediateToken { content }
+            | Node::Wrapper { content } => self.token_fallback(ctx, rule, content, immediate),
+            _ => {
+                self.report(
+                    rule,
+                    "token can't be turned into a regex, it is generated like a rule",
+                );
+                let mut part = self.part(ctx, rule, content);
+                part.immediate = immediate;
+                part
+            }
+        }
+    }
+
+    //Adds the alternatives to a helper nonterminal and returns a reference to it
+    fn helper(
+        &mut self,
+        ctx: &mut Context,
+        rule: &str,
+        alts: Vec<Vec<u8>>,
+        immediate: bool,
+    ) -> Part {
+        let helper = self.helper_name(rule);
+        for alt in alts {
+            self.add_rule(ctx, &helper, &alt, rule);
+        }
+        Part {
+            data: format!("{{{helper}}}").into_bytes(),
+            immediate,
+        }
+    }
+
+    fn part(&mut self, ctx: &mut Context, rule: &str, node: &Node) -> Part {
+        match node {
+            Node::Blank => Part::new(vec![]),
+            Node::Str { value } => {
+                Part::new(escape_terminal(ctx, value.as_bytes(), &self.backslash))
+            }
+            Node::Pattern { .. } => self.token(ctx, rule, node, false),
+            Node::Symbol { name } => {
+                if !self.names.contains(name)
+                    && self.externals.contains(name)
+                    && self.reported_externals.insert(name.clone())
+                {
+                    self.report(
+                        rule,
+                        &format!("{name} comes from the external scanner and generates nothing"),
+                    );
+                    self.add_rule(ctx, name, b"", name);
+                }
+                Part {
+                    data: format!("{{{name}}}").into_bytes(),
+                    immediate: self.immediate_rules.contains(name),
+                }
+            }
+            Node::Seq { members } => {
+                let parts = members
+                    .iter()
+                    .map(|m| self.part(ctx, rule, m))
+                    .collect::<Vec<_>>();
+                let immediate = parts
+                    .iter()
+                    .find(|p| !p.data.is_empty())
+                    .is_some_and(|p| p.immediate);
+                Part {
+                    data: join(parts),
+                    immediate,
+                }
+            }
+            Node::Choice { members } => {
+                let parts = members
+                    .iter()
+                    .map(|m| self.part(ctx, rule, m))
+                    .collect::<Vec<_>>();
+                let immediate = parts.iter().all(|p| p.data.is_empty() || p.immediate);
+                let alts = parts.into_iter().map(|p| p.data).collect();
+                self.helper(ctx, rule, alts, immediate)
+            }
+            Node::Optional { content } => {
+                let content = self.part(ctx, rule, content);
+                self.helper(ctx, rule, vec![vec![], content.data], content.immediate)
+            }
+            Node::Repeat { content } | Node::Repeat1 { content } => {
+                let content = self.part(ctx, rule, content);
+                let helper = self.helper_name(rule);
+                let reference = format!("{{{helper}}}").into_bytes();
+                let more = join(vec![
+                    Part::new(content.data.clone()),
+                    Part {
+                        data: reference.clone(),
+                        immediate: content.immediate,
+                    },
+                ]);
+                let once = if let Node::Repeat { .. } = node {
+                    vec![]
+                } else {
+                    content.data
+                };
+                self.add_rule(ctx, &helper, &once, rule);
+                self.add_rule(ctx, &helper, &more, rule);
+                Part {
+                    data: reference,
+                    immediate: content.immediate,
+                }
+            }
+            Node::Token { content } => self.token(ctx, rule, content, false),
+            Node::ImmediateToken { content } => self.token(ctx, rule, content, true),
+            Node::Wrapper { content } => self.part(ctx, rule, content),
+            Node::Unknown => {
+                self.report(rule, "unsupported rule type generates nothing");
+                Part::new(vec![])
+            }
+        }
+    }
+
+    fn add_grammar_rule(&mut self, ctx: &mut Context, name: &str, node: &Node) {
+        match node {
+            Node::Wrapper { content } => self.add_grammar_rule(ctx, name, content),
+            //The alternatives of a rule don't need a helper
+            Node::Choice { members } => {
+                for member in members {
+                    let data = self.part(ctx, name, member).data;
+                    self.add_rule(ctx, name, &data, name);
+                }
+            }
+            Node::Pattern { .. } | Node::Token { .. } | Node::ImmediateToken { .. } => {
+                match self.generatable_regex(name, node) {
+                    Some(regex) => self.add_regex(ctx, name, &regex, name),
+                    None => {
+                        let data = self.token_fallback(ctx, name, node, false).data;
+                        self.add_rule(ctx, name, &data, name);
+                    }
+                }
+            }
+            _ => {
+                let data = self.part(ctx, name, node).data;
+                self.add_rule(ctx, name, &data, name);
+            }
+        }
+    }
+}
+
+pub fn load_tree_sitter_grammar(grammar_path: &str, grammar: Value) -> Context {
+    let grammar: TreeSitterGrammar = serde_json::from_value(grammar)
+        .unwrap_or_else(|e| panic!("{}: cannot parse tree-sitter grammar: {}", grammar_path, e));
+    let rules = grammar
+        .rules
+        .into_iter()
+        .map(|(name, node)| {
+            let node: Node = serde_json::from_value(node)
+                .unwrap_or_else(|e| panic!("{}: cannot parse rule {}: {}", grammar_path, name, e));
+            (name, node)
+        })
+        .collect::<Vec<_>>();
+    let start = &rules
+        .first()
+        .unwrap_or_else(|| panic!("{} does not contain any rules", grammar_path))
+        .0;
+    let mut converter = Converter {
+        source: grammar_path,
+        names: rules.iter().map(|(name, _)| name.clone()).collect(),
+        externals: grammar
+            .externals
+            .iter()
+            .filter_map(|e| e.get("name").and_then(Value::as_str))
+            .map(String::from)
+            .collect(),
+        reported_externals: HashSet::new(),
+        immediate_rules: rules
+            .iter()
+            .filter(|(_, node)| is_immediate_token(node))
+            .map(|(name, _)| name.clone())
+            .collect(),
+        backslash: String::new(),
+    };
+    converter.names.insert("START".to_string());
+    converter.backslash = converter.helper_name("backslash");
+
+    let mut ctx = Context::new();
+    converter.add_rule(&mut ctx, "START", format!("{{{start}}}").as_bytes(), start);
+    for (name, node) in &rules {
+        converter.add_grammar_rule(&mut ctx, name, node);
+    }
+    ctx
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use grammar_loader::{load_test_grammar, GrammarOptions};
+    use grammartec::context::Context;
+    use grammartec::tree::TreeLike;
+
+    fn load(grammar: &str) -> Context {
+        let mut ctx = load_test_grammar("json", grammar.as_bytes(), &GrammarOptions::default());
+        ctx.initialize(30);
+        ctx
+    }
+
+    fn samples(ctx: &Context, nt: &str) -> HashSet<Vec<u8>> {
+        (0..200)
+            .map(|_| {
+                let tree = ctx.generate_tree_from_nt(ctx.nt_id(nt), 30);
+                tree.unparse_to_vec(ctx)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rules_and_tokens() {
+        let ctx = load(
+            r#"{"name": "calc", "rules": {
+                "program": {"type": "REPEAT1", "content": {"type": "SYMBOL", "name": "statement"}},
+                "statement": {"type": "SEQ", "members": [
+                    {"type": "SYMBOL", "name": "number"},
+                    {"type": "OPTIONAL", "content": {"type": "STRING", "value": "{"}},
+                    {"type": "IMMEDIATE_TOKEN", "content": {"type": "STRING", "value": ";"}}
+                ]},
+                "number": {"type": "TOKEN", "content": {"type": "PATTERN", "value": "[0-9]+"}}
+            }}"#,
+        );
+        for number in samples(&ctx, "number") {
+            assert!(!number.is_empty() && number.iter().all(u8::is_ascii_digit));
+        }
+        for statement in samples(&ctx, "statement") {
+            let statement = String::from_utf8(statement).expect("RAND_2284617350");
+            let number = statement
+                .strip_suffix(" {;")
+                .or_else(|| statement.strip_suffix(" ;"))
+                .unwrap_or_else(|| panic!("{:?}", statement));
+            assert!(
+                number.chars().all(|c| c.is_ascii_digit()),
+                "{:?}",
+                statement
+            );
+        }
+    }
+
+    #[test]
+    fn unconvertible_pattern() {
+        let ctx = load(
+            r#"{"name": "bad", "rules": {
+                "source": {"type": "SEQ", "members": [
+                    {"type": "STRING", "value": "a"},
+                    {"type": "PATTERN", "value": "a(?!b)"},
+                    {"type": "SYMBOL", "name": "word"},
+                    {"type": "SYMBOL", "name": "token"}
+                ]},
+                "word": {"type": "PATTERN", "value": "a(?!b)"},
+                "token": {"type": "TOKEN", "content": {"type": "SEQ", "members": [
+                    {"type": "STRING", "value": "b"},
+                    {"type": "PREC", "value": 1, "content": {"type": "PATTERN", "value": "(?<=a)c"}}
+                ]}}
+            }}"#,
+        );
+        assert_eq!(samples(&ctx, "word"), HashSet::from([vec![]]));
+        assert_eq!(samples(&ctx, "token"), HashSet::from([b"b".to_vec()]));
+        //The space before word is added when the grammar is converted
+        assert_eq!(samples(&ctx, "START"), HashSet::from([b"a  b".to_vec()]));
+    }
+
+    #[test]
+    fn backslash_before_nonterminal() {
+        //seq("\\", token.immediate(/[a-z]/)) and seq("\\", $.escape)
+        let ctx = load(
+            r#"{"name": "esc", "rules": {
+                "source": {"type": "SEQ", "members": [
+                    {"type": "STRING", "value": "\\"},
+                    {"type": "IMMEDIATE_TOKEN", "content": {"type": "PATTERN", "value": "[a-z]"}}
+                ]},
+                "escape": {"type": "SEQ", "members": [
+                    {"type": "STRING", "value": "\\"},
+                    {"type": "SYMBOL", "name": "source"}
+                ]}
+            }}"#,
+        );
+        for source in samples(&ctx, "source") {
+            assert!(
+                source.len() == 2 && source[0] == b'\\' && source[1].is_ascii_lowercase(),
+                "{:?}",
+                String::from_utf8_lossy(&source)
+            );
+        }
+        for escape in samples(&ctx, "escape") {
+            assert!(escape.starts_with(b"\\ \\"), "{:?}", escape);
+        }
+    }
+}
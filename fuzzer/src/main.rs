@@ -18,23 +18,28 @@ extern crate chrono;
 extern crate clap;
 extern crate forksrv;
 extern crate grammartec;
-extern crate pyo3;
+extern crate nautilus;
 extern crate ron;
 extern crate serde;
-extern crate serde_json;
 
 mod config;
+mod dedup;
 mod fuzzer;
-mod python_grammar_loader;
 mod queue;
 mod shared_state;
 mod state;
 
 use config::Config;
+use dedup::DedupCache;
 use forksrv::newtypes::SubprocessError;
-use fuzzer::Fuzzer;
+use fuzzer::{ExecutionReason, Fuzzer};
 use grammartec::chunkstore::ChunkStoreWrapper;
-use grammartec::context::Context;
+use grammartec::context::SharedContext;
+use grammartec::dictionary::load_dictionary;
+use grammartec::size_distribution::SizeStats;
+use grammartec::validation::Severity;
+use nautilus::grammar_loader::{self, GrammarOptions};
+use nautilus::rule_weights;
 use queue::{InputState, QueueItem};
 use shared_state::GlobalSharedState;
 use state::FuzzingState;
@@ -45,8 +50,9 @@ use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::process;
 use std::sync::atomic::Ordering;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::time::Instant;
 use std::{thread, time};
 
@@ -55,11 +61,14 @@ fn process_input(
     inp: &mut QueueItem,
     config: &Config,
 ) -> Result<(), SubprocessError> {
+    //The input may use rules that other threads added
+    state.refresh_context();
     match inp.state {
         InputState::Init(start_index) => {
             let end_index = start_index + 200;
 
             if state.minimize(inp, start_index, end_index)? {
+                state.input_to_state(inp)?;
                 inp.state = InputState::Det((0, 0));
             } else {
                 inp.state = InputState::Init(end_index);
@@ -79,99 +88,129 @@ fn process_input(
             state.splice(inp)?;
             state.havoc(inp)?;
             state.havoc_recursion(inp)?;
+            state.leaf_havoc(inp)?;
+            state.dictionary_insertion(inp)?;
+            state.raw_havoc(inp)?;
         }
         InputState::Random => {
             state.splice(inp)?;
             state.havoc(inp)?;
             state.havoc_recursion(inp)?;
+            state.leaf_havoc(inp)?;
+            state.dictionary_insertion(inp)?;
+            state.raw_havoc(inp)?;
         }
     }
     Ok(())
 }
 
-fn fuzzing_thread(
-    global_state: &Arc<Mutex<GlobalSharedState>>,
+//Called after the forkserver died while executing an input. Waits with exponential backoff
+//before starting the target again, so that a target that dies immediately (e.g. because the
+//system ran out of resources) does not burn the CPU. Gives up after too many failures in a row.
+fn restart_forkserver(
+    state: &mut FuzzingState,
+    global_state: &Arc<GlobalSharedState>,
     config: &Config,
-    ctx: &Context,
-    cks: &Arc<ChunkStoreWrapper>,
+    error: &SubprocessError,
+    consecutive_failures: &mut u32,
 ) {
-    let path_to_bin_target = config.path_to_bin_target.clone();
-    let args = config.arguments.clone();
+    let mut error = error.to_string();
+    loop {
+        *consecutive_failures += 1;
+        {
+            let mut stats = global_state.stats.lock().expect("RAND_1201339514");
+            stats.forkserver_restarts += 1;
+            stats.last_restart =
+                format!("{} ({error})", Local::now().format("[%Y-%m-%d] %H:%M:%S"));
+        }
+        if *consecutive_failures > config.max_consecutive_restarts {
+            exit_with_diagnostic(&format!(
+                "The forkserver died {consecutive_failures} times in a row, last error: {error}\n\
+                 The inputs that killed it are stored in {}/outputs/restarts",
+                config.path_to_workdir
+            ));
+        }
+        let backoff = 10u64 << (*consecutive_failures - 1).min(10);
+        thread::sleep(time::Duration::from_millis(backoff));
+        match state.fuzzer.restart() {
+            Ok(()) => return,
+            Err(e) => error = e.to_string(),
+        }
+    }
+}
 
-    let fuzzer = Fuzzer::new(
-        path_to_bin_target.clone(),
-        args,
-        global_state.clone(),
-        config.path_to_workdir.clone(),
-        config.hide_output,
-        config.timeout_in_millis,
-        config.bitmap_size,
-        config.extension.clone(),
+//Worker threads cannot hand errors to main, print what went wrong and stop the whole fuzzer
+fn exit_with_diagnostic(msg: &str) -> ! {
+    eprintln!(
+        "[{}] {msg}",
+        thread::current().name().unwrap_or("unnamed thread")
     );
-    let mut state = FuzzingState::new(fuzzer, config.clone(), cks.clone());
-    state.ctx = ctx.clone();
+    process::exit(1);
+}
+
+fn fuzzing_thread(
+    global_state: &Arc<GlobalSharedState>,
+    dedup: &Arc<DedupCache>,
+    config: &Config,
+    shared_ctx: &Arc<SharedContext>,
+    cks: &Arc<ChunkStoreWrapper>,
+) {
+    let fuzzer = match Fuzzer::new(config, global_state.clone(), dedup.clone()) {
+        Ok(fuzzer) => fuzzer,
+        Err(e) => exit_with_diagnostic(&format!("Could not start the target: {e}")),
+    };
+    let mut state = FuzzingState::new(fuzzer, config.clone(), cks.clone(), shared_ctx.clone());
     let mut old_execution_count = 0;
     let mut old_executions_per_sec = 0;
+    let mut consecutive_failures = 0;
     //Normal mode
     loop {
-        let inp = global_state.lock().expect("RAND_2191486322").queue.pop();
+        let inp = global_state.queue.lock().expect("RAND_2191486322").pop();
         if let Some(mut inp) = inp {
             //If subprocess died restart forkserver
-            if process_input(&mut state, &mut inp, config).is_err() {
-                let args = vec![];
-                let fuzzer = Fuzzer::new(
-                    path_to_bin_target.clone(),
-                    args,
-                    global_state.clone(),
-                    config.path_to_workdir.clone(),
-                    config.hide_output,
-                    config.timeout_in_millis,
-                    config.bitmap_size,
-                    config.extension.clone(),
-                );
-                state = FuzzingState::new(fuzzer, config.clone(), cks.clone());
-                state.ctx = ctx.clone();
-                old_execution_count = 0;
-                old_executions_per_sec = 0;
+            match process_input(&mut state, &mut inp, config) {
+                Ok(()) => consecutive_failures = 0,
+                Err(e) => restart_forkserver(
+                    &mut state,
+                    global_state,
+                    config,
+                    &e,
+                    &mut consecutive_failures,
+                ),
             }
             global_state
+                .queue
                 .lock()
                 .expect("RAND_788470278")
-                .queue
                 .finished(inp);
         } else {
             for _ in 0..config.number_of_generate_inputs {
                 //If subprocess dies restart forkserver
-                if state.generate_random("START").is_err() {
-                    let args = vec![];
-                    let fuzzer = Fuzzer::new(
-                        path_to_bin_target.clone(),
-                        args,
-                        global_state.clone(),
-                        config.path_to_workdir.clone(),
-                        config.hide_output,
-                        config.timeout_in_millis,
-                        config.bitmap_size,
-                        config.extension.clone(),
-                    );
-                    state = FuzzingState::new(fuzzer, config.clone(), cks.clone());
-                    state.ctx = ctx.clone();
-                    old_execution_count = 0;
-                    old_executions_per_sec = 0;
+                match state.generate_random("START") {
+                    Ok(()) => consecutive_failures = 0,
+                    Err(e) => restart_forkserver(
+                        &mut state,
+                        global_state,
+                        config,
+                        &e,
+                        &mut consecutive_failures,
+                    ),
                 }
             }
             global_state
+                .queue
                 .lock()
                 .expect("RAND_2035137253")
-                .queue
                 .new_round();
         }
-        let mut stats = global_state.lock().expect("RAND_2403514078");
+        let mut stats = global_state.stats.lock().expect("RAND_2403514078");
         stats.execution_count += state.fuzzer.execution_count - old_execution_count;
         old_execution_count = state.fuzzer.execution_count;
         stats.average_executions_per_sec += state.fuzzer.average_executions_per_sec as u32;
         stats.average_executions_per_sec -= old_executions_per_sec;
         old_executions_per_sec = state.fuzzer.average_executions_per_sec as u32;
+        stats.tree_sizes.merge(&state.fuzzer.tree_sizes);
+        state.fuzzer.tree_sizes = SizeStats::new();
         if state.fuzzer.bits_found_by_havoc > 0 {
             stats.bits_found_by_havoc += state.fuzzer.bits_found_by_havoc;
             state.fuzzer.bits_found_by_havoc = 0;
@@ -200,6 +239,22 @@ fn fuzzing_thread(
             stats.bits_found_by_min_rec += state.fuzzer.bits_found_by_min_rec;
             state.fuzzer.bits_found_by_min_rec = 0;
         }
+        if state.fuzzer.bits_found_by_cmplog > 0 {
+            stats.bits_found_by_cmplog += state.fuzzer.bits_found_by_cmplog;
+            state.fuzzer.bits_found_by_cmplog = 0;
+        }
+        if state.fuzzer.bits_found_by_leaf_havoc > 0 {
+            stats.bits_found_by_leaf_havoc += state.fuzzer.bits_found_by_leaf_havoc;
+            state.fuzzer.bits_found_by_leaf_havoc = 0;
+        }
+        if state.fuzzer.bits_found_by_raw > 0 {
+            stats.bits_found_by_raw += state.fuzzer.bits_found_by_raw;
+            state.fuzzer.bits_found_by_raw = 0;
+        }
+        if state.fuzzer.bits_found_by_dict > 0 {
+            stats.bits_found_by_dict += state.fuzzer.bits_found_by_dict;
+            state.fuzzer.bits_found_by_dict = 0;
+        }
     }
 }
 
@@ -278,15 +333,17 @@ fn main() {
         config.path_to_bin_target
     );
 
-    let shared = Arc::new(Mutex::new(GlobalSharedState::new(
+    let shared = Arc::new(GlobalSharedState::new(
         config.path_to_workdir.clone(),
         config.bitmap_size,
         config.extension.clone(),
-    )));
+        config.timeout_in_millis,
+    ));
     let shared_chunkstore = Arc::new(ChunkStoreWrapper::new(
         config.path_to_workdir.clone(),
         config.extension.clone(),
     ));
+    let shared_dedup = Arc::new(DedupCache::new(config.dedup_cache_size_in_mb));
 
     let mut my_context;
     let grammar_path = matches
@@ -300,54 +357,84 @@ fn main() {
     }
 
     //Generate rules using a grammar
-    my_context = Context::new();
-    if grammar_path.ends_with(".json") {
-        let gf = File::open(grammar_path).expect("cannot read grammar file");
-        let rules: Vec<Vec<String>> =
-            serde_json::from_reader(&gf).expect("cannot parse grammar file");
-        let root = "{".to_string() + &rules[0][0] + "}";
-        my_context.add_rule("START", root.as_bytes());
-        for rule in rules {
-            my_context.add_rule(&rule[0], rule[1].as_bytes());
+    my_context = grammar_loader::load_grammar(
+        &grammar_path,
+        &GrammarOptions {
+            break_framing: config.break_protobuf_framing,
+        },
+    );
+
+    //Report broken grammars here, initialize would only panic on them
+    let errors = my_context
+        .validate()
+        .into_iter()
+        .filter(|f| f.severity == Severity::Error)
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        for error in &errors {
+            eprintln!("{error}");
         }
-    } else if grammar_path.ends_with(".py") {
-        my_context = python_grammar_loader::load_python_grammar(&grammar_path);
-    } else {
-        panic!("Unknown grammar type");
+        process::exit(1);
     }
 
-    my_context.initialize(config.max_tree_size);
-
     //Create output folder
     let folders = [
         "/outputs/signaled",
         "/outputs/queue",
         "/outputs/timeout",
         "/outputs/chunks",
+        "/outputs/restarts",
+        "/outputs/sanitizer_logs",
+        "/outputs/trees",
     ];
     for f in &folders {
         fs::create_dir_all(format!("{}/{f}", config.path_to_workdir))
             .expect("Could not create folder in workdir");
     }
 
+    rule_weights::apply(
+        &mut my_context,
+        config.learn_rule_weights_from.as_deref(),
+        config.rule_weights.as_deref(),
+        Some(&format!("{}/rule_weights.ron", config.path_to_workdir)),
+        config.invert_rule_weights,
+    );
+    //After the rule weights, which only cover the rules of the grammar
+    for dictionary in &config.dictionaries {
+        let tokens = load_dictionary(&dictionary.path)
+            .unwrap_or_else(|e| panic!("Could not load dictionary: {}", e));
+        my_context
+            .add_dictionary(dictionary.nonterminal.as_deref(), &tokens)
+            .unwrap_or_else(|e| panic!("Could not load dictionary: {}", e));
+    }
+    my_context.initialize(config.max_tree_size);
+    my_context.set_size_distribution(config.size_distribution);
+    let has_dictionary = !my_context.get_dictionary().is_empty();
+    let shared_context = Arc::new(SharedContext::new(my_context));
+
     //Start fuzzing threads
     let mut thread_number = 0;
     let threads = (0..config.number_of_threads).map(|_| {
         let state = shared.clone();
+        let dedup = shared_dedup.clone();
         let config = config.clone();
-        let ctx = my_context.clone();
+        let ctx = shared_context.clone();
         let cks = shared_chunkstore.clone();
         thread_number += 1;
         thread::Builder::new()
             .name(format!("fuzzer_{thread_number}"))
             .stack_size(config.thread_size)
-            .spawn(move || fuzzing_thread(&state, &config, &ctx, &cks))
+            .spawn(move || fuzzing_thread(&state, &dedup, &config, &ctx, &cks))
     });
 
     //Start status thread
     let status_thread = {
         let global_state = shared.clone();
         let shared_cks = shared_chunkstore.clone();
+        let shared_dedup = shared_dedup.clone();
+        let has_sanitizer = config.path_to_sanitizer_bin.is_some();
+        let has_cmplog = config.path_to_cmplog_bin.is_some();
+        let has_raw = config.number_of_raw_mutations > 0;
         thread::Builder::new()
             .name("status_thread".to_string())
             .spawn(move || {
@@ -360,34 +447,56 @@ fn main() {
                     let average_executions_per_sec;
                     let queue_len;
                     let bits_found_by_gen;
+                    let bits_found_by_cmplog;
+                    let learned_terms;
+                    let bits_found_by_dict;
                     let bits_found_by_min;
                     let bits_found_by_min_rec;
                     let bits_found_by_det;
                     let bits_found_by_splice;
                     let bits_found_by_havoc;
                     let bits_found_by_havoc_rec;
+                    let bits_found_by_leaf_havoc;
+                    let bits_found_by_raw;
                     let last_found_asan;
                     let last_found_sig;
                     let last_timeout;
+                    let total_found_timeouts;
+                    let last_found_sanitizer;
+                    let total_found_sanitizer;
                     let total_found_asan;
                     let total_found_sig;
+                    let forkserver_restarts;
+                    let tree_sizes;
+                    let last_restart;
                     {
-                        let shared_state = global_state.lock().expect("RAND_597319831");
+                        queue_len = global_state.queue.lock().expect("RAND_597319831").len();
+                        let shared_state = global_state.stats.lock().expect("RAND_597319831");
                         execution_count = shared_state.execution_count;
                         average_executions_per_sec = shared_state.average_executions_per_sec;
-                        queue_len = shared_state.queue.len();
                         bits_found_by_gen = shared_state.bits_found_by_gen;
+                        bits_found_by_cmplog = shared_state.bits_found_by_cmplog;
+                        learned_terms = shared_state.learned_terms;
+                        bits_found_by_dict = shared_state.bits_found_by_dict;
                         bits_found_by_min = shared_state.bits_found_by_min;
                         bits_found_by_min_rec = shared_state.bits_found_by_min_rec;
                         bits_found_by_det = shared_state.bits_found_by_det;
                         bits_found_by_splice = shared_state.bits_found_by_splice;
                         bits_found_by_havoc = shared_state.bits_found_by_havoc;
                         bits_found_by_havoc_rec = shared_state.bits_found_by_havoc_rec;
+                        bits_found_by_leaf_havoc = shared_state.bits_found_by_leaf_havoc;
+                        bits_found_by_raw = shared_state.bits_found_by_raw;
                         last_found_asan = shared_state.last_found_asan.clone();
                         last_found_sig = shared_state.last_found_sig.clone();
                         last_timeout = shared_state.last_timeout.clone();
+                        total_found_timeouts = shared_state.total_found_timeouts;
+                        last_found_sanitizer = shared_state.last_found_sanitizer.clone();
+                        total_found_sanitizer = shared_state.total_found_sanitizer;
                         total_found_asan = shared_state.total_found_asan;
                         total_found_sig = shared_state.total_found_sig;
+                        forkserver_restarts = shared_state.forkserver_restarts;
+                        tree_sizes = shared_state.tree_sizes.clone();
+                        last_restart = shared_state.last_restart.clone();
                     }
                     let secs = start_time.elapsed().as_secs();
                     let minutes = secs / 60;
@@ -424,19 +533,17 @@ fn main() {
                         "Left in queue:            {}                              ",
                         queue_len
                     );
-                    let now = Instant::now();
-                    while shared_cks.is_locked.load(Ordering::SeqCst) {
-                        if now.elapsed().as_secs() > 30 {
-                            panic!("Printing thread starved!");
-                        }
-                    }
                     println!(
                         "Trees in Chunkstore:      {}                              ",
-                        shared_cks
-                            .chunkstore
-                            .read()
-                            .expect("RAND_351823021")
-                            .trees()
+                        shared_cks.snapshot().trees()
+                    );
+                    println!(
+                        "Timeout:                  {} ms                           ",
+                        global_state.timeout_in_millis.load(Ordering::Relaxed)
+                    );
+                    println!(
+                        "Tree sizes:               {}                              ",
+                        tree_sizes
                     );
                     println!("------------------------------------------------------    ");
                     println!(
@@ -451,6 +558,12 @@ fn main() {
                         "Last Timeout:             {}                              ",
                         last_timeout
                     );
+                    if has_sanitizer {
+                        println!(
+                            "Last Sanitizer crash:     {}                              ",
+                            last_found_sanitizer
+                        );
+                    }
                     println!(
                         "Total ASAN crashes:       {}                              ",
                         total_found_asan
@@ -459,6 +572,24 @@ fn main() {
                         "Total SIG crashes:        {}                              ",
                         total_found_sig
                     );
+                    println!(
+                        "Total Timeouts:           {}                              ",
+                        total_found_timeouts
+                    );
+                    if has_sanitizer {
+                        println!(
+                            "Total Sanitizer crashes:  {}                              ",
+                            total_found_sanitizer
+                        );
+                    }
+                    println!(
+                        "Forkserver restarts:      {}                              ",
+                        forkserver_restarts
+                    );
+                    println!(
+                        "Last restart:             {}                              ",
+                        last_restart
+                    );
                     println!("------------------------------------------------------    ");
                     println!(
                         "New paths found by Gen:          {}                       ",
@@ -488,6 +619,48 @@ fn main() {
                         "New paths found by Havoc Rec:    {}                       ",
                         bits_found_by_havoc_rec
                     );
+                    println!(
+                        "New paths found by Leaf Havoc:   {}                       ",
+                        bits_found_by_leaf_havoc
+                    );
+                    if has_cmplog {
+                        println!(
+                            "New paths found by CmpLog:       {}                       ",
+                            bits_found_by_cmplog
+                        );
+                        println!(
+                            "Learned terminals:               {}                       ",
+                            learned_terms
+                        );
+                    }
+                    if has_raw {
+                        println!(
+                            "New paths found by Raw Havoc:    {}                       ",
+                            bits_found_by_raw
+                        );
+                    }
+                    if has_dictionary {
+                        println!(
+                            "New paths found by Dictionary:   {}                       ",
+                            bits_found_by_dict
+                        );
+                    }
+                    println!("------------------------------------------------------    ");
+                    for exec_reason in &ExecutionReason::ALL {
+                        let (lookups, duplicates) = shared_dedup.stats(*exec_reason);
+                        let rate = if lookups == 0 {
+                            0.0
+                        } else {
+                            duplicates as f64 * 100.0 / lookups as f64
+                        };
+                        println!(
+                            "Duplicates in {:<10}        {:.2}% ({}/{})              ",
+                            format!("{exec_reason:?}:"),
+                            rate,
+                            duplicates,
+                            lookups
+                        );
+                    }
                     println!("------------------------------------------------------    ");
                     //println!("Global bitmap: {:?}", global_state.lock().expect("RAND_1887203473").bitmaps.get(&false).expect("RAND_1887203473"));
                     thread::sleep(time::Duration::from_secs(1));
@@ -15,13 +15,14 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 extern crate grammartec;
+extern crate nautilus;
 extern crate ron;
-extern crate serde_json;
 
 use grammartec::chunkstore::ChunkStore;
 use grammartec::context::Context;
 use grammartec::mutator::Mutator;
 use grammartec::tree::{Tree, TreeLike, TreeMutation};
+use nautilus::grammar_loader::{self, GrammarOptions};
 
 use std::env;
 use std::fs::File;
@@ -51,21 +52,8 @@ fn main() {
                 panic!("Please use havoc, rec, or splice");
             }
         };
-        let mut ctx = Context::new();
-
-        //Generate rules using an antlr grammar:
-        if grammar_path.ends_with(".json") {
-            let gf = File::open(grammar_path).expect("cannot read grammar file");
-            let rules: Vec<(String, String)> =
-                serde_json::from_reader(&gf).expect("cannot parse grammar file");
-            let root = "{".to_string() + &rules[0].0 + "}";
-            ctx.add_rule("START", root.as_bytes());
-            for rule in rules {
-                ctx.add_rule(&rule.0, rule.1.as_bytes());
-            }
-        } else {
-            panic!("Unknown grammar type");
-        }
+        //Generate rules using a grammar:
+        let mut ctx = grammar_loader::load_grammar(&grammar_path, &GrammarOptions::default());
 
         //Deserialize tree
         let mut sf = File::open(&tree_path).expect("cannot read tree file");
@@ -114,7 +102,7 @@ fn main() {
                 }
             }
             MutationMethods::Splice => {
-                let mut cks = ChunkStore::new("/tmp/".to_string());
+                let mut cks = ChunkStore::new("/tmp/".to_string(), String::new());
                 cks.add_tree(tree.clone(), &ctx);
                 mutator
                     .mut_splice(&tree, &ctx, &cks, &mut tester)
@@ -19,6 +19,7 @@ use std::collections::HashSet;
 use std::fs;
 use std::fs::File;
 use std::io::ErrorKind;
+use std::time::Duration;
 
 use forksrv::exitreason::ExitReason;
 use grammartec::context::Context;
@@ -42,7 +43,7 @@ pub struct QueueItem {
     pub exitreason: ExitReason,
     pub state: InputState,
     pub recursions: Option<Vec<RecursionInfo>>,
-    pub execution_time: u32,
+    pub execution_time: Duration,
 }
 
 impl QueueItem {
@@ -52,7 +53,7 @@ impl QueueItem {
         fresh_bits: HashSet<usize>,
         all_bits: Vec<u8>,
         exitreason: ExitReason,
-        execution_time: u32,
+        execution_time: Duration,
     ) -> Self {
         QueueItem {
             id,
@@ -83,7 +84,7 @@ impl Queue {
         all_bits: Vec<u8>,
         exitreason: ExitReason,
         ctx: &Context,
-        execution_time: u32,
+        execution_time: Duration,
     ) {
         if all_bits
             .iter()
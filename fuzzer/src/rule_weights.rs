@@ -0,0 +1,133 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use grammartec::context::Context;
+use grammartec::probabilities::RuleProbabilities;
+use grammartec::tree::Tree;
+
+//Smoothing used when counting rules, see RuleProbabilities::learn
+const SMOOTHING: f64 = 1.0;
+
+//Reads the RON trees in path, which is either a single tree or a directory (e.g. outputs/trees
+//or the queue of an earlier campaign). Files that don't contain a tree, like raw inputs, are
+//skipped.
+fn load_trees(path: &Path) -> Vec<Tree> {
+    if path.is_dir() {
+        let mut trees = vec![];
+        for entry in fs::read_dir(path).expect("cannot read tree directory") {
+            let entry = entry.expect("cannot read tree directory");
+            if entry.path().is_file() {
+                trees.extend(load_trees(&entry.path()));
+            }
+        }
+        trees
+    } else {
+        match fs::read(path) {
+            Ok(content) => ron::de::from_bytes(&content).into_iter().collect(),
+            Err(e) => {
+                eprintln!("warning: cannot read {}: {e}", path.display());
+                vec![]
+            }
+        }
+    }
+}
+
+//Estimates the probabilities of the rules of ctx from the trees in corpus_path
+pub fn learn(corpus_path: &str, ctx: &Context) -> RuleProbabilities {
+    let trees = load_trees(Path::new(corpus_path));
+    if trees.is_empty() {
+        eprintln!("No trees found in {corpus_path}");
+        process::exit(1);
+    }
+    RuleProbabilities::learn(&trees, ctx, SMOOTHING)
+}
+
+pub fn load(path: &str) -> RuleProbabilities {
+    let content = fs::read_to_string(path).expect("cannot read rule weights");
+    ron::de::from_str(&content).expect("cannot parse rule weights")
+}
+
+pub fn save(probabilities: &RuleProbabilities, path: &str) {
+    let content = ron::ser::to_string(probabilities).expect("RAND_3541206687");
+    fs::write(path, content).expect("cannot write rule weights");
+}
+
+//Learns the weights from corpus_path or loads them from weights_path, optionally inverts them
+//(uncommon derivations) and applies them to ctx. Learned weights are saved to save_path. ctx must
+//only contain the rules of the grammar, so call this before adding dictionaries.
+pub fn apply(
+    ctx: &mut Context,
+    corpus_path: Option<&str>,
+    weights_path: Option<&str>,
+    save_path: Option<&str>,
+    invert: bool,
+) {
+    let probabilities = match (corpus_path, weights_path) {
+        (Some(corpus_path), _) => {
+            let probabilities = learn(corpus_path, ctx);
+            if let Some(save_path) = save_path {
+                save(&probabilities, save_path);
+            }
+            probabilities
+        }
+        (None, Some(weights_path)) => load(weights_path),
+        (None, None) => return,
+    };
+    let probabilities = if invert {
+        probabilities.inverted()
+    } else {
+        probabilities
+    };
+    if let Err(e) = probabilities.apply(ctx) {
+        eprintln!("Could not apply rule weights: {e}");
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use grammartec::context::Context;
+    use rule_weights::load_trees;
+
+    #[test]
+    fn skips_files_without_trees() {
+        let mut ctx = Context::new();
+        ctx.add_rule("START", b"a{B}");
+        ctx.add_rule("B", b"b");
+        ctx.initialize(10);
+        let tree = ctx.generate_tree_from_nt(ctx.nt_id("START"), 10);
+        let dir = std::env::temp_dir().join(format!("nautilus-trees-{}", process::id()));
+        fs::create_dir_all(&dir).expect("RAND_3306121457");
+        fs::write(
+            dir.join("tree"),
+            ron::ser::to_string(&tree).expect("RAND_3306121457"),
+        )
+        .expect("RAND_3306121457");
+        //A raw input of the queue that is not UTF-8
+        fs::write(dir.join("input"), b"\xff\xfe\x00").expect("RAND_3306121457");
+        let trees = load_trees(&dir);
+        fs::remove_dir_all(&dir).expect("RAND_3306121457");
+        assert_eq!(trees.len(), 1);
+        assert_eq!(trees[0].rules, tree.rules);
+    }
+}
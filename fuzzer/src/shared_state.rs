@@ -14,13 +14,33 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use grammartec::size_distribution::SizeStats;
 use queue::Queue;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 
+//The calibrated timeout is this multiple of the slowest calibration run
+const TIMEOUT_MULTIPLIER: u64 = 5;
+const MIN_TIMEOUT_IN_MILLIS: u64 = 20;
+
+//The queue, the coverage maps and the statistics are synchronized independently, so that
+//threads that only need one of them never wait for threads working on another one.
 pub struct GlobalSharedState {
-    pub queue: Queue,
+    pub queue: Mutex<Queue>,
     //false for not crashing input. True for crashing inputs
-    pub bitmaps: HashMap<bool, Vec<u8>>,
+    //Bits are only ever set (using fetch_or), therefore no lock is needed
+    pub bitmaps: HashMap<bool, Vec<AtomicU8>>,
+    //Coverage of confirmed hangs, a hang is only saved if it sets a new bit in here
+    pub timeout_bitmap: Vec<AtomicU8>,
+    //Timeout used by all threads, starts with the configured value and is lowered by calibration
+    pub timeout_in_millis: AtomicU64,
+    calibration_samples: Mutex<Vec<Duration>>,
+    pub stats: Mutex<Stats>,
+}
+
+pub struct Stats {
     pub execution_count: u64,
     pub average_executions_per_sec: u32,
     pub bits_found_by_havoc: u64,
@@ -30,6 +50,11 @@ pub struct GlobalSharedState {
     pub bits_found_by_splice: u64,
     pub bits_found_by_det: u64,
     pub bits_found_by_gen: u64,
+    pub bits_found_by_cmplog: u64,
+    pub bits_found_by_dict: u64,
+    pub bits_found_by_leaf_havoc: u64,
+    pub bits_found_by_raw: u64,
+    pub learned_terms: u64,
     pub asan_found_by_havoc: u64,
     pub asan_found_by_havoc_rec: u64,
     pub asan_found_by_min: u64,
@@ -40,21 +65,66 @@ pub struct GlobalSharedState {
     pub last_found_asan: String,
     pub last_found_sig: String,
     pub last_timeout: String,
+    pub last_found_sanitizer: String,
+    pub total_found_timeouts: u64,
     pub state_saved: String,
     pub total_found_asan: u64,
     pub total_found_sig: u64,
+    pub total_found_sanitizer: u64,
+    pub forkserver_restarts: u64,
+    pub last_restart: String,
+    //Sizes of the trees of all executed inputs
+    pub tree_sizes: SizeStats,
 }
 
 impl GlobalSharedState {
-    pub fn new(work_dir: String, bitmap_size: usize, extension: String) -> Self {
+    pub fn new(
+        work_dir: String,
+        bitmap_size: usize,
+        extension: String,
+        timeout_in_millis: u64,
+    ) -> Self {
         let queue = Queue::new(work_dir, extension);
         //Initialize Empty bitmaps for crashes and normal executions
         let mut bitmaps = HashMap::new();
-        bitmaps.insert(false, vec![0; bitmap_size]);
-        bitmaps.insert(true, vec![0; bitmap_size]);
+        bitmaps.insert(false, (0..bitmap_size).map(|_| AtomicU8::new(0)).collect());
+        bitmaps.insert(true, (0..bitmap_size).map(|_| AtomicU8::new(0)).collect());
         GlobalSharedState {
-            queue,
+            queue: Mutex::new(queue),
             bitmaps,
+            timeout_bitmap: (0..bitmap_size).map(|_| AtomicU8::new(0)).collect(),
+            timeout_in_millis: AtomicU64::new(timeout_in_millis),
+            calibration_samples: Mutex::new(vec![]),
+            stats: Mutex::new(Stats::new()),
+        }
+    }
+
+    //Collects the execution times of the first queue entries. Once enough of them were measured
+    //the timeout is derived from the slowest one, it never exceeds the configured timeout.
+    pub fn add_calibration_sample(
+        &self,
+        execution_time: Duration,
+        calibration_inputs: usize,
+        max_timeout_in_millis: u64,
+    ) {
+        let mut samples = self.calibration_samples.lock().expect("RAND_2746208451");
+        if samples.len() >= calibration_inputs {
+            return;
+        }
+        samples.push(execution_time);
+        if samples.len() == calibration_inputs {
+            let slowest = samples.iter().max().expect("RAND_1480137985");
+            let timeout = (slowest.as_millis() as u64 * TIMEOUT_MULTIPLIER)
+                .max(MIN_TIMEOUT_IN_MILLIS)
+                .min(max_timeout_in_millis);
+            self.timeout_in_millis.store(timeout, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
             execution_count: 0,
             average_executions_per_sec: 0,
             bits_found_by_havoc: 0,
@@ -64,6 +134,11 @@ impl GlobalSharedState {
             bits_found_by_splice: 0,
             bits_found_by_det: 0,
             bits_found_by_gen: 0,
+            bits_found_by_cmplog: 0,
+            bits_found_by_dict: 0,
+            bits_found_by_leaf_havoc: 0,
+            bits_found_by_raw: 0,
+            learned_terms: 0,
             asan_found_by_havoc: 0,
             asan_found_by_havoc_rec: 0,
             asan_found_by_min: 0,
@@ -74,9 +149,15 @@ impl GlobalSharedState {
             last_found_asan: String::from("Not found yet."),
             last_found_sig: String::from("Not found yet."),
             last_timeout: String::from("No Timeout yet."),
+            last_found_sanitizer: String::from("Not found yet."),
+            total_found_timeouts: 0,
             state_saved: String::from("State not saved yet."),
             total_found_asan: 0,
             total_found_sig: 0,
+            total_found_sanitizer: 0,
+            forkserver_restarts: 0,
+            last_restart: String::from("No restart yet."),
+            tree_sizes: SizeStats::new(),
         }
     }
 }
@@ -15,36 +15,51 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::collections::HashSet;
+use std::fs;
 use std::fs::File;
-use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Instant;
 
 use grammartec::chunkstore::ChunkStoreWrapper;
-use grammartec::context::Context;
+use grammartec::context::{Context, SharedContext};
 use grammartec::mutator::Mutator;
+use grammartec::newtypes::NodeID;
 use grammartec::tree::{TreeLike, TreeMutation};
 
 use config::Config;
+use forksrv::cmplog::CmpValues;
 use forksrv::newtypes::SubprocessError;
 use fuzzer::{ExecutionReason, Fuzzer};
 use queue::QueueItem;
 
 pub struct FuzzingState {
     pub cks: Arc<ChunkStoreWrapper>,
-    pub ctx: Context,
+    //Snapshot of shared_ctx, refreshed with refresh_context
+    pub ctx: Arc<Context>,
+    shared_ctx: Arc<SharedContext>,
+    ctx_version: usize,
     pub config: Config,
     pub fuzzer: Fuzzer,
     pub mutator: Mutator,
 }
 
+//Upper bound for the executions of the input-to-state stage for one queue entry
+const MAX_INPUT_TO_STATE_MUTATIONS: usize = 2048;
+
 impl FuzzingState {
-    pub fn new(fuzzer: Fuzzer, config: Config, cks: Arc<ChunkStoreWrapper>) -> Self {
-        let ctx = Context::new();
+    pub fn new(
+        fuzzer: Fuzzer,
+        config: Config,
+        cks: Arc<ChunkStoreWrapper>,
+        shared_ctx: Arc<SharedContext>,
+    ) -> Self {
+        let ctx_version = shared_ctx.version();
+        let ctx = shared_ctx.snapshot();
         let mutator = Mutator::new(&ctx);
         FuzzingState {
             cks,
             ctx,
+            shared_ctx,
+            ctx_version,
             config,
             fuzzer,
             mutator,
@@ -58,7 +73,7 @@ impl FuzzingState {
         start_index: usize,
         end_index: usize,
     ) -> Result<bool, SubprocessError> {
-        let ctx = &mut self.ctx;
+        let ctx = &self.ctx;
         let fuzzer = &mut self.fuzzer;
 
         let min_simple = self.mutator.minimize_tree(
@@ -87,23 +102,7 @@ impl FuzzingState {
 
         if min_simple && min_rec {
             //Only do this when minimization is completely done
-            let now = Instant::now();
-            while self
-                .cks
-                .is_locked
-                .compare_exchange(false, true, Ordering::Acquire, Ordering::Acquire)
-                .is_err()
-            {
-                if now.elapsed().as_secs() > 30 {
-                    panic!("minimize starved!");
-                }
-            }
-            self.cks
-                .chunkstore
-                .write()
-                .expect("RAND_1217841466")
-                .add_tree(input.tree.clone(), ctx);
-            self.cks.is_locked.store(false, Ordering::Release);
+            self.cks.add_tree(input.tree.clone(), ctx);
 
             input.recursions = input.tree.calc_recursions(ctx);
 
@@ -114,6 +113,16 @@ impl FuzzingState {
             ))
             .expect("Could not create queue entry, are you sure $workdir/outputs exists?");
             input.tree.unparse_to(ctx, &mut file);
+
+            //Keep the tree as well, e.g. to learn rule weights from it in a later campaign
+            fs::write(
+                format!(
+                    "{}/outputs/trees/id:{:09}.ron",
+                    &self.config.path_to_workdir, input.id
+                ),
+                ron::ser::to_string(&input.tree).expect("RAND_2017434513"),
+            )
+            .expect("Could not create tree file, are you sure $workdir/outputs exists?");
             return Ok(true);
         }
 
@@ -126,7 +135,7 @@ impl FuzzingState {
         start_index: usize,
         end_index: usize,
     ) -> Result<bool, SubprocessError> {
-        let ctx = &mut self.ctx;
+        let ctx = &self.ctx;
         let fuzzer = &mut self.fuzzer;
         let done = self.mutator.mut_rules(
             &input.tree,
@@ -143,7 +152,7 @@ impl FuzzingState {
     }
 
     pub fn havoc(&mut self, input: &mut QueueItem) -> Result<(), SubprocessError> {
-        let ctx = &mut self.ctx;
+        let ctx = &self.ctx;
         let fuzzer = &mut self.fuzzer;
         for _i in 0..100 {
             self.mutator
@@ -161,7 +170,7 @@ impl FuzzingState {
         /* input.tree.calc_recursions() */
         {
             for _i in 0..20 {
-                let ctx = &mut self.ctx;
+                let ctx = &self.ctx;
                 let fuzzer = &mut self.fuzzer;
                 self.mutator.mut_random_recursion(
                     &input.tree,
@@ -178,20 +187,69 @@ impl FuzzingState {
         Ok(())
     }
 
+    pub fn leaf_havoc(&mut self, input: &mut QueueItem) -> Result<(), SubprocessError> {
+        let ctx = &self.ctx;
+        let fuzzer = &mut self.fuzzer;
+        for _i in 0..100 {
+            Mutator::mut_leaf_havoc(&input.tree, ctx, &mut |t: &TreeMutation, ctx: &Context| {
+                fuzzer
+                    .run_on_with_dedup(t, ExecutionReason::LeafHavoc, ctx)
+                    .map(|_| ())
+            })?;
+        }
+        Ok(())
+    }
+
+    //Results are queued as opaque trees, unless they can be parsed back into real trees
+    pub fn raw_havoc(&mut self, input: &mut QueueItem) -> Result<(), SubprocessError> {
+        if self.config.number_of_raw_mutations == 0 {
+            return Ok(());
+        }
+        let ctx = &self.ctx;
+        let fuzzer = &mut self.fuzzer;
+        let code = input.tree.unparse_to_vec(ctx);
+        for _i in 0..self.config.number_of_raw_mutations {
+            Mutator::mut_raw_havoc(
+                &input.tree,
+                &code,
+                ctx,
+                &mut |t: &TreeMutation, ctx: &Context| {
+                    fuzzer
+                        .run_on_with_dedup(t, ExecutionReason::Raw, ctx)
+                        .map(|_| ())
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn dictionary_insertion(&mut self, input: &mut QueueItem) -> Result<(), SubprocessError> {
+        if self.ctx.get_dictionary().is_empty() {
+            return Ok(());
+        }
+        let ctx = &self.ctx;
+        let fuzzer = &mut self.fuzzer;
+        for _i in 0..100 {
+            Mutator::mut_dict_insert(&input.tree, ctx, &mut |t: &TreeMutation, ctx: &Context| {
+                fuzzer
+                    .run_on_with_dedup(t, ExecutionReason::Dict, ctx)
+                    .map(|_| ())
+            })?;
+        }
+        Ok(())
+    }
+
     pub fn splice(&mut self, input: &mut QueueItem) -> Result<(), SubprocessError> {
-        let ctx = &mut self.ctx;
+        let cks = self.cks.snapshot();
+        //Trees in the snapshot may use rules that were added after this input was popped
+        self.refresh_context();
+        let ctx = &self.ctx;
         let fuzzer = &mut self.fuzzer;
         for _i in 0..100 {
-            let now = Instant::now();
-            while self.cks.is_locked.load(Ordering::SeqCst) {
-                if now.elapsed().as_secs() > 30 {
-                    panic!("splice starved!");
-                }
-            }
             self.mutator.mut_splice(
                 &input.tree,
                 ctx,
-                &self.cks.chunkstore.read().expect("RAND_1290117799"),
+                &cks,
                 &mut |t: &TreeMutation, ctx: &Context| {
                     fuzzer
                         .run_on_with_dedup(t, ExecutionReason::Splice, ctx)
@@ -202,6 +260,79 @@ impl FuzzingState {
         Ok(())
     }
 
+    //Replaces the operands of comparisons the target executed in the leaves of the tree
+    pub fn input_to_state(&mut self, input: &mut QueueItem) -> Result<(), SubprocessError> {
+        if !self.fuzzer.has_cmplog() {
+            return Ok(());
+        }
+        let code = input.tree.unparse_to_vec(&self.ctx);
+        let mut replacements = HashSet::new();
+        for value in self.fuzzer.cmplog_values(&code)? {
+            for (v0, v1) in cmp_value_encodings(&value) {
+                if v0 == v1 {
+                    continue;
+                }
+                //We don't know which operand came from the input
+                if contains(&code, &v0) {
+                    replacements.insert((v0.clone(), v1.clone()));
+                }
+                if contains(&code, &v1) {
+                    replacements.insert((v1, v0));
+                }
+            }
+        }
+
+        let ctx = &self.ctx;
+        let fuzzer = &mut self.fuzzer;
+        let learn_terms = self.config.cmplog_learn_terms;
+        let mut learned = vec![];
+        let mut budget = MAX_INPUT_TO_STATE_MUTATIONS;
+        for (from, to) in &replacements {
+            Mutator::mut_replace_in_leaves(
+                &input.tree,
+                ctx,
+                from,
+                to,
+                &mut |t: &TreeMutation, n: NodeID, data: &[u8], ctx: &Context| {
+                    if budget == 0 {
+                        return Ok(());
+                    }
+                    budget -= 1;
+                    let bits_before = fuzzer.bits_found_by_cmplog;
+                    fuzzer.run_on_with_dedup(t, ExecutionReason::CmpLog, ctx)?;
+                    if learn_terms && fuzzer.bits_found_by_cmplog > bits_before {
+                        let nt = ctx.nt_id_to_s(t.get_nonterm_id(n, ctx));
+                        learned.push((nt, data.to_vec()));
+                    }
+                    Ok(())
+                },
+            )?;
+        }
+
+        if !learned.is_empty() {
+            let added = self.shared_ctx.add_term_rules(&learned);
+            self.fuzzer
+                .global_state
+                .stats
+                .lock()
+                .expect("RAND_3892775214")
+                .learned_terms += added as u64;
+            self.refresh_context();
+        }
+        Ok(())
+    }
+
+    //Switches to the newest version of the shared context if rules were added. The scratchpad
+    //of the mutator is rebuilt, so that it never holds nodes created with an older context.
+    pub fn refresh_context(&mut self) {
+        let version = self.shared_ctx.version();
+        if version != self.ctx_version {
+            self.ctx = self.shared_ctx.snapshot();
+            self.ctx_version = version;
+            self.mutator = Mutator::new(&self.ctx);
+        }
+    }
+
     pub fn generate_random(&mut self, nt: &str) -> Result<(), SubprocessError> {
         let nonterm = self.ctx.nt_id(nt);
         let len = self.ctx.get_random_len_for_nt(&nonterm);
@@ -215,3 +346,35 @@ impl FuzzingState {
         return String::from_utf8_lossy(&input.tree.unparse_to_vec(&self.ctx)).into_owned();
     }
 }
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    !needle.is_empty() && haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+//The representations in which the operands of a comparison may appear in the input
+fn cmp_value_encodings(value: &CmpValues) -> Vec<(Vec<u8>, Vec<u8>)> {
+    match value {
+        CmpValues::Ins { size, v0, v1 } => {
+            let size = (*size).min(16);
+            let mut res = vec![
+                (
+                    v0.to_le_bytes()[..size].to_vec(),
+                    v1.to_le_bytes()[..size].to_vec(),
+                ),
+                (v0.to_string().into_bytes(), v1.to_string().into_bytes()),
+            ];
+            if size > 1 {
+                res.push((
+                    v0.to_be_bytes()[16 - size..].to_vec(),
+                    v1.to_be_bytes()[16 - size..].to_vec(),
+                ));
+            }
+            res
+        }
+        CmpValues::Rtn { v0, v1 } => {
+            //str* functions stop at the first null byte
+            let until_null = |v: &Vec<u8>| v.split(|b| *b == 0).next().unwrap_or(&[]).to_vec();
+            vec![(v0.clone(), v1.clone()), (until_null(v0), until_null(v1))]
+        }
+    }
+}
@@ -0,0 +1,186 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use rand::Rng;
+
+//Values that often trigger edge cases, taken from AFL
+const INTERESTING_8: [i8; 9] = [-128, -1, 0, 1, 16, 32, 64, 100, 127];
+const INTERESTING_16: [i16; 10] = [-32768, -129, 128, 255, 256, 512, 1000, 1024, 4096, 32767];
+const INTERESTING_32: [i32; 8] = [
+    -2_147_483_648,
+    -100_663_046,
+    -32769,
+    32768,
+    65535,
+    65536,
+    100_663_045,
+    2_147_483_647,
+];
+const ARITH_MAX: u32 = 35;
+const MAX_BLOCK_LEN: usize = 32;
+const NUMBER_OF_OPERATIONS: usize = 8;
+
+//AFL style havoc: applies a random stack of byte level mutations to data. The result is never
+//longer than max_len bytes.
+pub fn havoc<R: Rng>(data: &mut Vec<u8>, max_len: usize, rng: &mut R) {
+    let stack = 1 << rng.gen_range(1..6);
+    for _ in 0..stack {
+        mutate_once(data, max_len, rng.gen_range(0..NUMBER_OF_OPERATIONS), rng);
+    }
+}
+
+fn mutate_once<R: Rng>(data: &mut Vec<u8>, max_len: usize, operation: usize, rng: &mut R) {
+    //Only insertions can work on empty data
+    if data.is_empty() && operation != 6 {
+        return insert_block(data, max_len, rng);
+    }
+    match operation {
+        0 => {
+            let bit = rng.gen_range(0..data.len() * 8);
+            data[bit / 8] ^= 0x80 >> (bit % 8);
+        }
+        1 => {
+            let pos = rng.gen_range(0..data.len());
+            data[pos] = rng.gen();
+        }
+        2 => {
+            let (value, width) = match rng.gen_range(0..3) {
+                0 => (
+                    i64::from(INTERESTING_8[rng.gen_range(0..INTERESTING_8.len())]),
+                    1,
+                ),
+                1 => (
+                    i64::from(INTERESTING_16[rng.gen_range(0..INTERESTING_16.len())]),
+                    2,
+                ),
+                _ => (
+                    i64::from(INTERESTING_32[rng.gen_range(0..INTERESTING_32.len())]),
+                    4,
+                ),
+            };
+            if data.len() < width {
+                return;
+            }
+            let pos = rng.gen_range(0..=data.len() - width);
+            write_at(data, pos, width, value as u64, rng.gen());
+        }
+        3 | 4 => {
+            let width = [1, 2, 4][rng.gen_range(0..3)];
+            if data.len() < width {
+                return;
+            }
+            let pos = rng.gen_range(0..=data.len() - width);
+            let big_endian = rng.gen::<bool>();
+            let value = read_int(&data[pos..pos + width], big_endian);
+            let delta = u64::from(rng.gen_range(1..=ARITH_MAX));
+            let value = if operation == 3 {
+                value.wrapping_add(delta)
+            } else {
+                value.wrapping_sub(delta)
+            };
+            write_at(data, pos, width, value, big_endian);
+        }
+        5 => {
+            let len = rng.gen_range(1..=data.len().min(MAX_BLOCK_LEN));
+            let pos = rng.gen_range(0..=data.len() - len);
+            data.drain(pos..pos + len);
+        }
+        6 => insert_block(data, max_len, rng),
+        _ => {
+            //Overwrite a block with a copy of another block of data
+            let len = rng.gen_range(1..=data.len().min(MAX_BLOCK_LEN));
+            let from = rng.gen_range(0..=data.len() - len);
+            let to = rng.gen_range(0..=data.len() - len);
+            data.copy_within(from..from + len, to);
+        }
+    }
+}
+
+//Inserts a copy of an existing block or a run of a single random byte
+fn insert_block<R: Rng>(data: &mut Vec<u8>, max_len: usize, rng: &mut R) {
+    if data.len() >= max_len {
+        return;
+    }
+    let len = rng.gen_range(1..=(max_len - data.len()).min(MAX_BLOCK_LEN));
+    let pos = rng.gen_range(0..=data.len());
+    let block = if data.len() >= len && rng.gen::<bool>() {
+        let from = rng.gen_range(0..=data.len() - len);
+        data[from..from + len].to_vec()
+    } else {
+        vec![rng.gen(); len]
+    };
+    data.splice(pos..pos, block);
+}
+
+fn read_int(bytes: &[u8], big_endian: bool) -> u64 {
+    let fold = |value: u64, b: &u8| (value << 8) | u64::from(*b);
+    if big_endian {
+        bytes.iter().fold(0, fold)
+    } else {
+        bytes.iter().rev().fold(0, fold)
+    }
+}
+
+fn write_at(data: &mut [u8], pos: usize, width: usize, value: u64, big_endian: bool) {
+    for i in 0..width {
+        let byte = (value >> (8 * i)) as u8;
+        if big_endian {
+            data[pos + width - 1 - i] = byte;
+        } else {
+            data[pos + i] = byte;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte_mutator::{havoc, mutate_once, read_int, write_at, NUMBER_OF_OPERATIONS};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn endianness() {
+        let mut data = vec![0; 6];
+        write_at(&mut data, 1, 4, 0x1122_3344, true);
+        assert_eq!(data, [0, 0x11, 0x22, 0x33, 0x44, 0]);
+        assert_eq!(read_int(&data[1..5], true), 0x1122_3344);
+        assert_eq!(read_int(&data[1..5], false), 0x4433_2211);
+        write_at(&mut data, 0, 2, 0xabcd, false);
+        assert_eq!(&data[..2], [0xcd, 0xab]);
+    }
+
+    #[test]
+    fn havoc_stays_in_bounds() {
+        let mut rng = StdRng::seed_from_u64(0x4e61_7574);
+        for operation in 0..NUMBER_OF_OPERATIONS {
+            for len in 0..8 {
+                let mut data = vec![0x41; len];
+                mutate_once(&mut data, 8, operation, &mut rng);
+                assert!(data.len() <= 8);
+            }
+        }
+        let mut changed = 0;
+        for _ in 0..1000 {
+            let mut data = b"1234".to_vec();
+            havoc(&mut data, 16, &mut rng);
+            assert!(data.len() <= 16);
+            if data != b"1234" {
+                changed += 1;
+            }
+        }
+        assert!(changed > 900);
+    }
+}
@@ -20,8 +20,7 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fs::File;
 use std::io::Write;
-use std::sync::atomic::AtomicBool;
-use std::sync::RwLock;
+use std::sync::{Arc, Mutex, RwLock};
 
 use context::Context;
 use newtypes::{NTermID, NodeID, RuleID};
@@ -29,25 +28,90 @@ use rule::RuleIDOrCustom;
 use serde::{Deserialize, Serialize};
 use tree::{Tree, TreeLike};
 
+//Readers get an immutable snapshot of the chunk store and never wait for writers. The writer adds
+//trees to its own copy and publishes a clone of it. Cloning is cheap: the trees and chunk lists
+//are shared between the copies and the outputs seen so far are only kept by the writer.
+//The locks are only held while an Arc is cloned or replaced.
 pub struct ChunkStoreWrapper {
-    pub chunkstore: RwLock<ChunkStore>,
-    pub is_locked: AtomicBool,
+    chunkstore: RwLock<Arc<ChunkStore>>,
+    writer: Mutex<ChunkStore>,
 }
 impl ChunkStoreWrapper {
     #[must_use]
     pub fn new(work_dir: String, extension: String) -> Self {
         ChunkStoreWrapper {
-            chunkstore: RwLock::new(ChunkStore::new(work_dir, extension)),
-            is_locked: AtomicBool::new(false),
+            chunkstore: RwLock::new(Arc::new(ChunkStore::new(
+                work_dir.clone(),
+                extension.clone(),
+            ))),
+            writer: Mutex::new(ChunkStore::new(work_dir, extension)),
         }
     }
+
+    #[must_use]
+    pub fn snapshot(&self) -> Arc<ChunkStore> {
+        self.chunkstore.read().expect("RAND_1290117799").clone()
+    }
+
+    pub fn add_tree(&self, tree: Tree, ctx: &Context) {
+        let mut writer = self.writer.lock().expect("RAND_1217841466");
+        writer.add_tree(tree, ctx);
+        let snapshot = Arc::new(writer.published());
+        *self.chunkstore.write().expect("RAND_1217841467") = snapshot;
+    }
+}
+
+//Append only vector whose clones share their elements. The elements are stored in segments of
+//decreasing power of two sizes, like the digits of a binary counter: pushing merges segments of
+//equal size. Every element is copied O(log n) times and a clone copies O(log n) pointers.
+#[derive(Clone, Serialize, Deserialize)]
+struct SharedVec<T> {
+    segments: Vec<Arc<Vec<T>>>,
+}
+
+impl<T: Clone> SharedVec<T> {
+    fn new() -> Self {
+        SharedVec { segments: vec![] }
+    }
+
+    fn push(&mut self, value: T) {
+        let mut segment = vec![value];
+        while self
+            .segments
+            .last()
+            .is_some_and(|last| last.len() == segment.len())
+        {
+            let mut last = self.segments.pop().expect("RAND_1856402731").to_vec();
+            last.append(&mut segment);
+            segment = last;
+        }
+        self.segments.push(Arc::new(segment));
+    }
+
+    fn get(&self, mut index: usize) -> &T {
+        for segment in &self.segments {
+            if index < segment.len() {
+                return &segment[index];
+            }
+            index -= segment.len();
+        }
+        panic!("index out of bounds");
+    }
+
+    fn len(&self) -> usize {
+        self.segments.iter().map(|s| s.len()).sum()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &T> {
+        self.segments.iter().flat_map(|s| s.iter())
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ChunkStore {
-    nts_to_chunks: HashMap<NTermID, Vec<(usize, NodeID)>>,
+    nts_to_chunks: HashMap<NTermID, SharedVec<(usize, NodeID)>>,
     seen_outputs: HashSet<Vec<u8>>,
-    trees: Vec<Tree>,
+    trees: SharedVec<Arc<Tree>>,
     work_dir: String,
     number_of_chunks: usize,
     extension: String,
@@ -59,13 +123,25 @@ impl ChunkStore {
         ChunkStore {
             nts_to_chunks: HashMap::new(),
             seen_outputs: HashSet::new(),
-            trees: vec![],
+            trees: SharedVec::new(),
             work_dir,
             number_of_chunks: 0,
             extension,
         }
     }
 
+    //A copy for readers, they don't need the outputs seen so far
+    fn published(&self) -> Self {
+        ChunkStore {
+            nts_to_chunks: self.nts_to_chunks.clone(),
+            seen_outputs: HashSet::new(),
+            trees: self.trees.clone(),
+            work_dir: self.work_dir.clone(),
+            number_of_chunks: self.number_of_chunks,
+            extension: self.extension.clone(),
+        }
+    }
+
     pub fn add_tree(&mut self, tree: Tree, ctx: &Context) {
         let mut buffer = vec![];
         let id = self.trees.len();
@@ -81,7 +157,7 @@ impl ChunkStore {
                 self.seen_outputs.insert(buffer.clone());
                 self.nts_to_chunks
                     .entry(tree.get_rule(n, ctx).nonterm())
-                    .or_insert_with(std::vec::Vec::new)
+                    .or_insert_with(SharedVec::new)
                     .push((id, n));
                 let mut file = File::create(format!(
                     "{}/outputs/chunks/chunk_{:09}{}",
@@ -94,7 +170,7 @@ impl ChunkStore {
             }
         }
         if contains_new_chunk {
-            self.trees.push(tree);
+            self.trees.push(Arc::new(tree));
         }
     }
 
@@ -105,11 +181,11 @@ impl ChunkStore {
             .get(&ctx.get_nt(&RuleIDOrCustom::Rule(r)));
         let relevant = chunks.map(|vec| {
             vec.iter()
-                .filter(move |&&(tid, nid)| self.trees[tid].get_rule_id(nid) != r)
+                .filter(move |&&(tid, nid)| self.trees.get(tid).get_rule_id(nid) != r)
         });
         //The unwrap_or is just a quick and dirty fix to catch Errors from the sampler
         let selected = relevant.and_then(|iter| iter.choose(&mut thread_rng()));
-        selected.map(|&(tid, nid)| (&self.trees[tid], nid))
+        selected.map(|&(tid, nid)| (self.trees.get(tid).as_ref(), nid))
     }
 
     #[must_use]
@@ -120,9 +196,10 @@ impl ChunkStore {
 
 #[cfg(test)]
 mod tests {
-    use chunkstore::ChunkStore;
+    use chunkstore::{ChunkStore, ChunkStoreWrapper, SharedVec};
     use context::Context;
     use std::fs;
+    use std::sync::Arc;
     use tree::TreeLike;
 
     #[test]
@@ -136,24 +213,67 @@ mod tests {
         println!("random_size: {random_size}");
         let tree = ctx.generate_tree_from_rule(r1, random_size);
         fs::create_dir_all("/tmp/outputs/chunks").expect("40234068");
-        let mut cks = ChunkStore::new("/tmp/".to_string());
+        let mut cks = ChunkStore::new("/tmp/".to_string(), String::new());
         cks.add_tree(tree, &ctx);
         // assert!(cks.seen_outputs.contains("a b c".as_bytes()));
         // assert!(cks.seen_outputs.contains("b c".as_bytes()));
         // assert!(cks.seen_outputs.contains("c".as_bytes()));
         assert_eq!(cks.nts_to_chunks[&ctx.nt_id("A")].len(), 1);
-        let (tree_id, _) = cks.nts_to_chunks[&ctx.nt_id("A")][0];
-        assert_eq!(cks.trees[tree_id].unparse_to_vec(&ctx), "a b c".as_bytes());
+        let (tree_id, _) = *cks.nts_to_chunks[&ctx.nt_id("A")].get(0);
+        assert_eq!(
+            cks.trees.get(tree_id).unparse_to_vec(&ctx),
+            "a b c".as_bytes()
+        );
 
         let random_size = ctx.get_random_len_for_ruleid(&r2);
         let tree = ctx.generate_tree_from_rule(r2, random_size);
         cks.add_tree(tree, &ctx);
         // assert_eq!(cks.seen_outputs.len(), 3);
         // assert_eq!(cks.nts_to_chunks[&ctx.nt_id("B")].len(), 1);
-        let (tree_id, node_id) = cks.nts_to_chunks[&ctx.nt_id("B")][0];
+        let (tree_id, node_id) = *cks.nts_to_chunks[&ctx.nt_id("B")].get(0);
         assert_eq!(
-            cks.trees[tree_id].unparse_node_to_vec(node_id, &ctx),
+            cks.trees.get(tree_id).unparse_node_to_vec(node_id, &ctx),
             "b c".as_bytes()
         );
     }
+
+    #[test]
+    fn chunk_store_wrapper_snapshots() {
+        let mut ctx = Context::new();
+        let r1 = ctx.add_rule("A", b"a {B:a}");
+        let _ = ctx.add_rule("B", b"b");
+        ctx.initialize(101);
+        fs::create_dir_all("/tmp/outputs/chunks").expect("40234069");
+        let cks = ChunkStoreWrapper::new("/tmp/".to_string(), String::new());
+        let old = cks.snapshot();
+        cks.add_tree(ctx.generate_tree_from_rule(r1, 10), &ctx);
+        assert_eq!(old.trees(), 0);
+        assert_eq!(cks.snapshot().trees(), 1);
+        cks.add_tree(ctx.generate_tree_from_rule(r1, 10), &ctx);
+        assert_eq!(cks.snapshot().trees(), 1);
+    }
+
+    #[test]
+    fn shared_vec() {
+        let mut v = SharedVec::new();
+        for i in 0..100 {
+            v.push(i);
+        }
+        let old = v.clone();
+        v.push(100);
+        assert_eq!(old.len(), 100);
+        assert_eq!(v.len(), 101);
+        assert_eq!(
+            v.iter().copied().collect::<Vec<_>>(),
+            (0..101).collect::<Vec<_>>()
+        );
+        assert_eq!(*v.get(57), 57);
+        //100 = 64 + 32 + 4, the clone shares all segments
+        assert_eq!(old.segments.len(), 3);
+        assert!(old
+            .segments
+            .iter()
+            .zip(v.segments.iter())
+            .all(|(a, b)| Arc::ptr_eq(a, b)));
+    }
 }
@@ -15,14 +15,22 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
-use rand::seq::IteratorRandom;
+use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
 
 use newtypes::{NTermID, RuleID};
 use pyo3::prelude::PyObject;
-use rule::{Rule, RuleIDOrCustom};
+use rule::{NativeScript, Rule, RuleChild, RuleIDOrCustom, Script};
+use size_distribution::SizeDistribution;
 use tree::Tree;
+use uniform_sampler::{GenerationStrategy, UniformSampler};
+use validation::{self, Finding};
+
+//Percentage of the rules without further choices that are considered during generation
+const P_INCLUDE_SHORT_RULES: usize = 0;
 
 #[derive(Clone)]
 pub struct Context {
@@ -37,6 +45,13 @@ pub struct Context {
     rules_to_num_options: HashMap<RuleID, usize>,
     nts_to_num_options: HashMap<NTermID, usize>,
     max_len: usize,
+    size_distribution: SizeDistribution,
+    //Only present if trees are generated with GenerationStrategy::Uniform
+    uniform_sampler: Option<Arc<UniformSampler>>,
+    //Where the rules were defined (e.g. grammar.py:12), used to report problems with the grammar
+    rule_sources: HashMap<RuleID, String>,
+    //Dictionary tokens that are not mapped to a nonterminal, used by the dictionary insertion
+    dictionary: Vec<Vec<u8>>,
 }
 
 impl Context {
@@ -54,6 +69,10 @@ impl Context {
             rules_to_num_options: HashMap::new(),
             nts_to_num_options: HashMap::new(),
             max_len: 0,
+            size_distribution: SizeDistribution::default(),
+            uniform_sampler: None,
+            rule_sources: HashMap::new(),
+            dictionary: vec![],
         }
     }
 
@@ -69,6 +88,48 @@ impl Context {
         &self.rules[id]
     }
 
+    #[must_use]
+    pub fn get_max_len(&self) -> usize {
+        self.max_len
+    }
+
+    #[must_use]
+    pub fn get_number_of_rules(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn set_rule_weight(&mut self, r: RuleID, weight: f64) {
+        let id: usize = r.into();
+        self.rules[id].set_weight(weight);
+    }
+
+    pub fn set_rule_source(&mut self, r: RuleID, source: String) {
+        self.rule_sources.insert(r, source);
+    }
+
+    #[must_use]
+    pub fn get_rule_source(&self, r: RuleID) -> Option<&str> {
+        self.rule_sources.get(&r).map(String::as_str)
+    }
+
+    //Checks the grammar for problems that would otherwise surface as panics during generation.
+    //Can be used before initialize.
+    #[must_use]
+    pub fn validate(&self) -> Vec<Finding> {
+        validation::validate(self)
+    }
+
+    //All nonterminals that were mentioned, including those without rules
+    #[must_use]
+    pub fn get_nonterms(&self) -> Vec<NTermID> {
+        (0..self.nt_ids_to_name.len()).map(NTermID::from).collect()
+    }
+
+    #[must_use]
+    pub fn has_rules_for_nt(&self, nt: NTermID) -> bool {
+        self.nts_to_rules.contains_key(&nt)
+    }
+
     #[must_use]
     pub fn get_nt(&self, r: &RuleIDOrCustom) -> NTermID {
         self.get_rule(r.id()).nonterm()
@@ -76,7 +137,11 @@ impl Context {
 
     #[must_use]
     pub fn get_num_children(&self, r: &RuleIDOrCustom) -> usize {
-        self.get_rule(r.id()).number_of_nonterms()
+        match r {
+            //Custom data replaces the whole subtree
+            RuleIDOrCustom::Custom(..) => 0,
+            RuleIDOrCustom::Rule(id) => self.get_rule(*id).number_of_nonterms(),
+        }
     }
 
     pub fn add_rule(&mut self, nt: &str, format: &[u8]) -> RuleID {
@@ -92,6 +157,15 @@ impl Context {
     }
 
     pub fn add_script(&mut self, nt: &str, nts: &[String], script: PyObject) -> RuleID {
+        self.add_script_rule(nt, nts, Script::Python(script))
+    }
+
+    //Like add_script, but the script is a Rust function
+    pub fn add_native_script(&mut self, nt: &str, nts: &[String], script: NativeScript) -> RuleID {
+        self.add_script_rule(nt, nts, Script::Native(script))
+    }
+
+    fn add_script_rule(&mut self, nt: &str, nts: &[String], script: Script) -> RuleID {
         let rid = self.rules.len().into();
         let rule = Rule::from_script(self, nt, nts, script);
         let ntid = self.aquire_nt_id(nt);
@@ -126,6 +200,73 @@ impl Context {
         rid
     }
 
+    //Each token becomes a terminal alternative of nt. Without a nonterminal the tokens are kept
+    //for the dictionary insertion mutation. The nonterminal has to be part of the grammar already,
+    //otherwise the tokens would end up in a new nonterminal that is never used.
+    pub fn add_dictionary(&mut self, nt: Option<&str>, tokens: &[Vec<u8>]) -> Result<(), String> {
+        if let Some(nt) = nt {
+            if self.get_nt_id(nt).is_none() {
+                return Err(format!("nonterminal {nt} does not exist"));
+            }
+        }
+        for token in tokens {
+            match nt {
+                Some(nt) if !self.has_term_rule(nt, token) => {
+                    self.add_term_rule(nt, token);
+                }
+                Some(_) => {}
+                None if !self.dictionary.contains(token) => self.dictionary.push(token.clone()),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn get_dictionary(&self) -> &[Vec<u8>] {
+        &self.dictionary
+    }
+
+    //Adds a terminal rule to a context that was already initialized. The minimal sizes and the
+    //number of options are updated incrementally, all existing RuleIDs stay the same.
+    pub fn add_term_rule_at_runtime(&mut self, nt: &str, term: &[u8]) -> RuleID {
+        let rid = self.add_term_rule(nt, term);
+        let ntid = self.get_rule(rid).nonterm();
+        self.rules_to_min_size.insert(rid, 1);
+        self.rules_to_num_options.insert(rid, 1);
+
+        if self.nts_to_min_size.get(&ntid).is_none_or(|min| *min > 1) {
+            self.nts_to_min_size.insert(ntid, 1);
+            self.propagate_min_len(vec![ntid]);
+        }
+        let num_options = self.nts_to_rules[&ntid].len();
+        if self
+            .nts_to_num_options
+            .get(&ntid)
+            .is_none_or(|num| *num < num_options)
+        {
+            self.nts_to_num_options.insert(ntid, num_options);
+            self.propagate_num_options(vec![ntid]);
+        }
+        self.calc_rule_order();
+        rid
+    }
+
+    #[must_use]
+    pub fn has_term_rule(&self, nt: &str, term: &[u8]) -> bool {
+        let Some(ntid) = self.names_to_nt_id.get(nt) else {
+            return false;
+        };
+        self.nts_to_rules.get(ntid).is_some_and(|rules| {
+            rules.iter().any(|r| match self.get_rule(*r) {
+                Rule::Plain(rule) => {
+                    rule.children.len() == 1 && rule.children[0] == RuleChild::Term(term.to_vec())
+                }
+                _ => false,
+            })
+        })
+    }
+
     pub fn aquire_nt_id(&mut self, nt: &str) -> NTermID {
         let next_id = self.nt_ids_to_name.len().into();
         let id = self.names_to_nt_id.entry(nt.into()).or_insert(next_id);
@@ -133,6 +274,11 @@ impl Context {
         *id
     }
 
+    #[must_use]
+    pub fn get_nt_id(&self, nt: &str) -> Option<NTermID> {
+        self.names_to_nt_id.get(nt).copied()
+    }
+
     #[must_use]
     pub fn nt_id(&self, nt: &str) -> NTermID {
         return *self
@@ -196,6 +342,46 @@ impl Context {
         self.calc_rule_order();
     }
 
+    //The minimal size of the nonterminals in the worklist shrank, update every rule using them
+    fn propagate_min_len(&mut self, mut worklist: Vec<NTermID>) {
+        while let Some(changed) = worklist.pop() {
+            for rid in (0..self.rules.len()).map(RuleID::from) {
+                if !self.get_rule(rid).nonterms().contains(&changed) {
+                    continue;
+                }
+                let Some(min) = self.calc_min_len_for_rule(rid) else {
+                    continue;
+                };
+                self.rules_to_min_size.insert(rid, min);
+                let nt = self.get_rule(rid).nonterm();
+                let e = self.nts_to_min_size.entry(nt).or_insert(min);
+                if *e > min {
+                    *e = min;
+                    worklist.push(nt);
+                }
+            }
+        }
+    }
+
+    //The number of options of the nonterminals in the worklist grew, update every rule using them
+    fn propagate_num_options(&mut self, mut worklist: Vec<NTermID>) {
+        while let Some(changed) = worklist.pop() {
+            for rid in (0..self.rules.len()).map(RuleID::from) {
+                if !self.get_rule(rid).nonterms().contains(&changed) {
+                    continue;
+                }
+                let num = self.calc_num_options_for_rule(rid);
+                self.rules_to_num_options.insert(rid, num);
+                let nt = self.get_rule(rid).nonterm();
+                let e = self.nts_to_num_options.entry(nt).or_insert(num);
+                if *e < num {
+                    *e = num;
+                    worklist.push(nt);
+                }
+            }
+        }
+    }
+
     fn calc_num_options_for_rule(&self, r: RuleID) -> usize {
         let mut res = 1_usize;
         for nt_id in self.get_rule(r).nonterms().iter() {
@@ -265,6 +451,15 @@ impl Context {
         self.nts_to_min_size[&nt]
     }
 
+    //The minimal size and the number of options of a nonterminal, once initialize was called
+    #[must_use]
+    pub fn get_nt_stats(&self, nt: NTermID) -> Option<(usize, usize)> {
+        Some((
+            *self.nts_to_min_size.get(&nt)?,
+            *self.nts_to_num_options.get(&nt)?,
+        ))
+    }
+
     #[must_use]
     pub fn get_random_rule_for_nt(&self, nt: NTermID, len: usize) -> RuleID {
         self.dumb_get_random_rule_for_nt(nt, len)
@@ -285,27 +480,31 @@ impl Context {
             });
     }
 
+    //Picks one of the rules that fit into max_len, weighted by the rule weights. If all rules have
+    //the same weight, rules that offer no further choices are only included with a small
+    //probability, unless there is nothing else. Otherwise that would override the weights.
+    //Rules with weight 0 are only used if no other rule fits.
     fn dumb_get_random_rule_for_nt(&self, nt: NTermID, max_len: usize) -> RuleID {
-        let p_include_short_rules = if self.nts_to_num_options[&nt] < 10 {
-            100 * 0
-        } else if max_len > 100 {
-            2 * 0
-        } else if max_len > 20 {
-            50 * 0
+        let mut rng = thread_rng();
+        let weight = |r: &&RuleID| self.get_rule(**r).weight();
+        let p_include_short_rules = if self.has_uniform_weights(nt) {
+            P_INCLUDE_SHORT_RULES
         } else {
-            100 * 0
+            100
         };
-
-        if let Some(opt) = self
+        let rules = self
             .get_applicable_rules(max_len, nt, p_include_short_rules)
-            .choose(&mut thread_rng())
-        {
-            *opt
-        } else if let Some(opt) = self
+            .collect::<Vec<_>>();
+        if let Ok(opt) = rules.choose_weighted(&mut rng, weight) {
+            return **opt;
+        }
+        let rules = self
             .get_applicable_rules(max_len, nt, 100)
-            .choose(&mut thread_rng())
-        {
-            *opt
+            .collect::<Vec<_>>();
+        if let Ok(opt) = rules.choose_weighted(&mut rng, weight) {
+            **opt
+        } else if let Some(opt) = rules.choose(&mut rng) {
+            **opt
         } else {
             panic!(
                 "there is no way to derive {} within {max_len} steps",
@@ -314,14 +513,51 @@ impl Context {
         }
     }
 
+    //Ignores rules with weight 0, they are never picked if there is an alternative
+    fn has_uniform_weights(&self, nt: NTermID) -> bool {
+        let mut weights = self.nts_to_rules[&nt]
+            .iter()
+            .map(|r| self.get_rule(*r).weight())
+            .filter(|w| *w > 0.0);
+        let first = weights.next();
+        weights.all(|w| Some(w) == first)
+    }
+
+    pub fn set_size_distribution(&mut self, size_distribution: SizeDistribution) {
+        self.size_distribution = size_distribution;
+    }
+
+    //Has to be called after initialize, the uniform sampler precomputes the number of trees for
+    //every size up to the maximal tree size
+    pub fn set_generation_strategy(&mut self, strategy: GenerationStrategy) {
+        self.uniform_sampler = match strategy {
+            GenerationStrategy::Random => None,
+            GenerationStrategy::Uniform => Some(Arc::new(UniformSampler::new(self, self.max_len))),
+        };
+    }
+
+    #[must_use]
+    pub fn get_generation_strategy(&self) -> GenerationStrategy {
+        if self.uniform_sampler.is_some() {
+            GenerationStrategy::Uniform
+        } else {
+            GenerationStrategy::Random
+        }
+    }
+
     #[must_use]
-    pub fn get_random_len_for_ruleid(&self, _rule_id: &RuleID) -> usize {
-        self.max_len //TODO?????
+    pub fn get_random_len_for_ruleid(&self, rule_id: &RuleID) -> usize {
+        self.size_distribution.sample(
+            self.rules_to_min_size[rule_id],
+            self.max_len,
+            &mut thread_rng(),
+        )
     }
 
     #[must_use]
-    pub fn get_random_len_for_nt(&self, _nt: &NTermID) -> usize {
-        self.max_len
+    pub fn get_random_len_for_nt(&self, nt: &NTermID) -> usize {
+        self.size_distribution
+            .sample(self.nts_to_min_size[nt], self.max_len, &mut thread_rng())
     }
 
     #[must_use]
@@ -331,6 +567,15 @@ impl Context {
 
     #[must_use]
     pub fn generate_tree_from_nt(&self, nt: NTermID, max_len: usize) -> Tree {
+        //The uniform sampler produces a tree with the largest possible size up to max_len
+        if let Some(sampler) = &self.uniform_sampler {
+            if let Some(tree) = sampler
+                .largest_size(nt, max_len)
+                .and_then(|len| sampler.sample(nt, len, self))
+            {
+                return tree;
+            }
+        }
         self.generate_tree_from_rule(self.get_random_rule_for_nt(nt, max_len), max_len - 1)
     }
 
@@ -342,6 +587,60 @@ impl Context {
     }
 }
 
+//A context that can grow while the fuzzer is running. Like the chunk store, readers work on an
+//immutable snapshot and writers publish a modified copy. Rules are only ever appended, so trees
+//built with an older snapshot stay valid in every later one.
+pub struct SharedContext {
+    ctx: RwLock<Arc<Context>>,
+    writer: Mutex<()>,
+    version: AtomicUsize,
+}
+
+impl SharedContext {
+    #[must_use]
+    pub fn new(ctx: Context) -> Self {
+        SharedContext {
+            ctx: RwLock::new(Arc::new(ctx)),
+            writer: Mutex::new(()),
+            version: AtomicUsize::new(0),
+        }
+    }
+
+    #[must_use]
+    pub fn snapshot(&self) -> Arc<Context> {
+        self.ctx.read().expect("RAND_3187304562").clone()
+    }
+
+    //Changes every time rules are added. Read it before taking a snapshot: the version is only
+    //increased after the new snapshot was published.
+    #[must_use]
+    pub fn version(&self) -> usize {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    //Adds (nonterminal, terminal) pairs as new rules. Returns how many were added, terminals a
+    //nonterminal already has are skipped.
+    pub fn add_term_rules(&self, terms: &[(String, Vec<u8>)]) -> usize {
+        let _writer = self.writer.lock().expect("RAND_1766590415");
+        let mut ctx = Context::clone(&self.snapshot());
+        let mut added = 0;
+        for (nt, term) in terms {
+            if !ctx.has_term_rule(nt, term) {
+                ctx.add_term_rule_at_runtime(nt, term);
+                added += 1;
+            }
+        }
+        if added > 0 {
+            //The counts of the uniform sampler do not include the new rules
+            let strategy = ctx.get_generation_strategy();
+            ctx.set_generation_strategy(strategy);
+            *self.ctx.write().expect("RAND_2432180911") = Arc::new(ctx);
+            self.version.fetch_add(1, Ordering::SeqCst);
+        }
+        added
+    }
+}
+
 impl Default for Context {
     fn default() -> Self {
         Self::new()
@@ -350,7 +649,7 @@ impl Default for Context {
 
 #[cfg(test)]
 mod tests {
-    use context::Context;
+    use context::{Context, SharedContext};
     use rule::{Rule, RuleChild, RuleIDOrCustom};
     use tree::{Tree, TreeLike};
 
@@ -448,4 +747,135 @@ mod tests {
             "((((1/1)-1)*1)+1)"
         );
     }
+
+    #[test]
+    fn weighted_rules() {
+        let mut ctx = Context::new();
+        let _ = ctx.add_rule("START", b"{A}");
+        let common = ctx.add_rule("A", b"a");
+        let rare = ctx.add_rule("A", b"b");
+        let never = ctx.add_rule("A", b"c");
+        ctx.set_rule_weight(common, 9.0);
+        ctx.set_rule_weight(never, 0.0);
+        ctx.initialize(10);
+        assert!(ctx
+            .get_rule(common)
+            .debug_show(&ctx)
+            .ends_with("[weight 9]"));
+        assert!(!ctx.get_rule(rare).debug_show(&ctx).contains("weight"));
+
+        let a = ctx.nt_id("A");
+        let mut counts = [0; 3];
+        for _ in 0..1000 {
+            let r: usize = ctx.get_random_rule_for_nt(a, 10).into();
+            counts[r - 1] += 1;
+        }
+        assert!(counts[0] > 800 && counts[1] > 20);
+        assert_eq!(counts[2], 0);
+
+        //Without any positive weight the rules are chosen uniformly
+        ctx.set_rule_weight(common, 0.0);
+        ctx.set_rule_weight(rare, 0.0);
+        for _ in 0..100 {
+            let _ = ctx.get_random_rule_for_nt(a, 10);
+        }
+    }
+
+    #[test]
+    fn weights_of_terminal_and_nonterminal_rules() {
+        let mut ctx = Context::new();
+        let _ = ctx.add_rule("START", b"{A}");
+        let terminal = ctx.add_rule("A", b"x");
+        let nonterminal = ctx.add_rule("A", b"{B}");
+        let _ = ctx.add_rule("B", b"b");
+        let _ = ctx.add_rule("B", b"c");
+        ctx.set_rule_weight(terminal, 100.0);
+        ctx.set_rule_weight(nonterminal, 1.0);
+        ctx.initialize(10);
+
+        //The terminal rule offers no further choices, its weight still has to count
+        let a = ctx.nt_id("A");
+        let picked = (0..1000)
+            .filter(|_| ctx.get_random_rule_for_nt(a, 10) == terminal)
+            .count();
+        assert!(picked > 900, "{}", picked);
+    }
+
+    #[test]
+    fn add_rules_at_runtime() {
+        let build = |ctx: &mut Context| {
+            ctx.add_rule("START", b"{DECL}{DECL}");
+            ctx.add_rule("DECL", b"var {NAME} = {VALUE};");
+            ctx.add_rule("NAME", b"{NAME}{NAME}");
+            ctx.add_rule("NAME", b"x{CHAR}");
+            ctx.add_rule("CHAR", b"a");
+            ctx.add_rule("VALUE", b"1");
+        };
+        let mut ctx = Context::new();
+        build(&mut ctx);
+        ctx.initialize(100);
+        let tree = ctx.generate_tree_from_nt(ctx.nt_id("START"), 100);
+        let unparsed = tree.unparse_to_vec(&ctx);
+
+        let learned = ctx.add_term_rule_at_runtime("NAME", b"magic");
+        let _ = ctx.add_term_rule_at_runtime("CHAR", b"b");
+        assert!(ctx.has_term_rule("NAME", b"magic"));
+        assert!(!ctx.has_term_rule("VALUE", b"magic"));
+
+        //Same result as building the grammar with these rules from the start
+        let mut expected = Context::new();
+        build(&mut expected);
+        expected.add_term_rule("NAME", b"magic");
+        expected.add_term_rule("CHAR", b"b");
+        expected.initialize(100);
+        assert_eq!(ctx.nts_to_min_size, expected.nts_to_min_size);
+        assert_eq!(ctx.rules_to_min_size, expected.rules_to_min_size);
+        assert_eq!(ctx.nts_to_num_options, expected.nts_to_num_options);
+        assert_eq!(ctx.rules_to_num_options, expected.rules_to_num_options);
+        assert_eq!(ctx.nts_to_rules, expected.nts_to_rules);
+
+        //Trees from before the change are still valid
+        assert_eq!(tree.unparse_to_vec(&ctx), unparsed);
+        assert_eq!(ctx.get_min_len_for_nt(ctx.nt_id("NAME")), 1);
+        assert_eq!(ctx.get_rules_for_nt(ctx.nt_id("NAME"))[0], learned);
+    }
+
+    #[test]
+    fn dictionaries() {
+        let mut ctx = Context::new();
+        let _ = ctx.add_rule("START", b"{NAME}");
+        let tokens = [b"foo".to_vec(), b"bar".to_vec()];
+        ctx.add_dictionary(Some("NAME"), &tokens)
+            .expect("RAND_3150246961");
+        ctx.add_dictionary(None, &tokens).expect("RAND_3150246962");
+        assert_eq!(ctx.get_rules_for_nt(ctx.nt_id("NAME")).len(), 2);
+        assert_eq!(ctx.get_dictionary(), &tokens);
+        //A typo must not silently add an unused nonterminal
+        assert!(ctx.add_dictionary(Some("NAEM"), &tokens).is_err());
+        assert!(ctx.get_nt_id("NAEM").is_none());
+    }
+
+    #[test]
+    fn shared_context_versions() {
+        let mut ctx = Context::new();
+        ctx.add_rule("START", b"{A}");
+        ctx.add_rule("A", b"a");
+        ctx.initialize(10);
+        let shared = SharedContext::new(ctx);
+        let old = shared.snapshot();
+        assert_eq!(shared.version(), 0);
+
+        let terms = vec![
+            ("A".to_string(), b"b".to_vec()),
+            ("A".to_string(), b"a".to_vec()),
+        ];
+        assert_eq!(shared.add_term_rules(&terms), 1);
+        assert_eq!(shared.version(), 1);
+        assert_eq!(shared.add_term_rules(&terms), 0);
+        assert_eq!(shared.version(), 1);
+
+        assert_eq!(old.get_rules_for_nt(old.nt_id("A")).len(), 1);
+        let new = shared.snapshot();
+        assert_eq!(new.get_rules_for_nt(new.nt_id("A")).len(), 2);
+    }
 }
@@ -0,0 +1,129 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+
+//Parses a dictionary in the format used by AFL's -x option. Every non empty line that is not a
+//comment holds one token: an optional name (with an optional @level suffix) followed by the
+//token in double quotes. Inside the quotes \\, \" and \xNN are the only escape sequences.
+pub fn parse_dictionary(content: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut tokens = vec![];
+    for (i, line) in content.split(|b| *b == b'\n').enumerate() {
+        let line = line.trim_ascii();
+        if line.is_empty() || line[0] == b'#' {
+            continue;
+        }
+        let token = parse_line(line).map_err(|e| format!("line {}: {e}", i + 1))?;
+        if !tokens.contains(&token) {
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+pub fn load_dictionary(path: &str) -> Result<Vec<Vec<u8>>, String> {
+    let content = fs::read(path).map_err(|e| format!("could not read {path}: {e}"))?;
+    parse_dictionary(&content).map_err(|e| format!("{path}: {e}"))
+}
+
+fn parse_line(line: &[u8]) -> Result<Vec<u8>, String> {
+    let start = line
+        .iter()
+        .position(|b| *b == b'"')
+        .ok_or("token is not in double quotes")?;
+    let name = &line[..start];
+    if !name.is_empty() {
+        let name = name
+            .trim_ascii_end()
+            .strip_suffix(b"=")
+            .ok_or("expected '=' after the name")?;
+        let name = name.trim_ascii_end();
+        let valid = |b: &u8| b.is_ascii_alphanumeric() || b"_@".contains(b);
+        if name.is_empty() || !name.iter().all(valid) {
+            return Err("invalid name".to_string());
+        }
+    }
+    if line.len() < start + 2 || line[line.len() - 1] != b'"' {
+        return Err("token is not terminated by a double quote".to_string());
+    }
+
+    let mut token = vec![];
+    let mut iter = line[start + 1..line.len() - 1].iter();
+    while let Some(b) = iter.next() {
+        match b {
+            b'\\' => match iter.next() {
+                Some(b'\\') => token.push(b'\\'),
+                Some(b'"') => token.push(b'"'),
+                Some(b'x') => {
+                    let hex = [*iter.next().unwrap_or(&0), *iter.next().unwrap_or(&0)];
+                    //from_str_radix would accept a sign, e.g. \x+1
+                    if !hex.iter().all(u8::is_ascii_hexdigit) {
+                        return Err("invalid \\x escape, expected two hex digits".to_string());
+                    }
+                    let hex = std::str::from_utf8(&hex).expect("RAND_3905137420");
+                    token.push(u8::from_str_radix(hex, 16).expect("RAND_3905137421"));
+                }
+                _ => return Err("invalid escape sequence".to_string()),
+            },
+            b'"' => return Err("unescaped double quote in token".to_string()),
+            b if *b < 0x20 || *b >= 0x7f => {
+                return Err("non printable character in token, use \\xNN".to_string())
+            }
+            b => token.push(*b),
+        }
+    }
+    if token.is_empty() {
+        return Err("empty token".to_string());
+    }
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use dictionary::parse_dictionary;
+
+    #[test]
+    fn afl_dictionary() {
+        let dict = b"# comment\n\
+                     \n\
+                     kw_function=\"function\"\n\
+                     kw_null@1 = \"null\"\r\n\
+                     \"\\x00\\xffa\\\\\\\"\"\n\
+                     duplicate=\"null\"\n";
+        let tokens = parse_dictionary(dict).expect("RAND_2742271593");
+        assert_eq!(
+            tokens,
+            vec![
+                b"function".to_vec(),
+                b"null".to_vec(),
+                b"\x00\xffa\\\"".to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_dictionary() {
+        assert!(parse_dictionary(b"function").is_err());
+        assert!(parse_dictionary(b"kw \"function\"").is_err());
+        assert!(parse_dictionary(b"\"function").is_err());
+        assert!(parse_dictionary(b"\"a\\nb\"").is_err());
+        assert!(parse_dictionary(b"\"\\xzz\"").is_err());
+        assert!(parse_dictionary(b"\"\\x+1\"").is_err());
+        assert!(parse_dictionary(b"\"\\x1\"").is_err());
+        let err = parse_dictionary(b"\"ok\"\n\"\"").expect_err("RAND_1062958117");
+        assert!(err.starts_with("line 2"));
+    }
+}
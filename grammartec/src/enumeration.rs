@@ -0,0 +1,208 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use context::Context;
+use newtypes::{NTermID, RuleID};
+use rule::{RegExpRule, Rule, RuleIDOrCustom};
+use tree::Tree;
+
+type Derivations = Rc<Vec<Vec<RuleIDOrCustom>>>;
+
+//Returns all derivation trees of nt with at most max_len nodes, smallest first, but no more than
+//limit trees
+#[must_use]
+pub fn enumerate_by_size(ctx: &Context, nt: NTermID, max_len: usize, limit: usize) -> Vec<Tree> {
+    let mut enumerator = Enumerator::new(ctx, limit);
+    let mut res = vec![];
+    for len in ctx.get_min_len_for_nt(nt)..=max_len {
+        for rules in enumerator.by_size(nt, len).iter() {
+            if res.len() >= limit {
+                return res;
+            }
+            res.push(Tree::from_rule_vec(rules.clone(), ctx));
+        }
+    }
+    res
+}
+
+//Returns all derivation trees of nt that are at most max_depth nodes deep, but no more than limit
+//trees
+#[must_use]
+pub fn enumerate_by_depth(ctx: &Context, nt: NTermID, max_depth: usize, limit: usize) -> Vec<Tree> {
+    let mut enumerator = Enumerator::new(ctx, limit);
+    enumerator
+        .by_depth(nt, max_depth)
+        .iter()
+        .map(|rules| Tree::from_rule_vec(rules.clone(), ctx))
+        .collect()
+}
+
+//Regex leaves are enumerated as a single fixed sample, so that the output is reproducible
+pub(crate) fn node_for_rule(ctx: &Context, rid: RuleID) -> RuleIDOrCustom {
+    match ctx.get_rule(rid) {
+        Rule::Plain(_) | Rule::Script(_) => RuleIDOrCustom::Rule(rid),
+        Rule::RegExp(RegExpRule { hir, .. }) => {
+            RuleIDOrCustom::Custom(rid, regex_mutator::generate(hir, 0))
+        }
+    }
+}
+
+//Memoizes the derivations (as rule vectors in pre-order) per nonterminal and exact size or
+//maximal depth. Every list is cut off at limit entries.
+struct Enumerator<'a> {
+    ctx: &'a Context,
+    limit: usize,
+    sizes: HashMap<(NTermID, usize), Derivations>,
+    depths: HashMap<(NTermID, usize), Derivations>,
+}
+
+impl<'a> Enumerator<'a> {
+    fn new(ctx: &'a Context, limit: usize) -> Self {
+        Enumerator {
+            ctx,
+            limit,
+            sizes: HashMap::new(),
+            depths: HashMap::new(),
+        }
+    }
+
+    //All derivations of nt with exactly len nodes
+    fn by_size(&mut self, nt: NTermID, len: usize) -> Derivations {
+        if let Some(res) = self.sizes.get(&(nt, len)) {
+            return res.clone();
+        }
+        let mut res = vec![];
+        if len >= self.ctx.get_min_len_for_nt(nt) {
+            for rid in self.ctx.get_rules_for_nt(nt) {
+                let children = self.ctx.get_rule(*rid).nonterms().to_vec();
+                for mut rest in self.sequences_by_size(&children, len - 1) {
+                    if res.len() >= self.limit {
+                        break;
+                    }
+                    let mut rules = vec![node_for_rule(self.ctx, *rid)];
+                    rules.append(&mut rest);
+                    res.push(rules);
+                }
+            }
+        }
+        let res = Rc::new(res);
+        self.sizes.insert((nt, len), res.clone());
+        res
+    }
+
+    //All ways to derive the sequence of nonterminals with exactly len nodes in total
+    fn sequences_by_size(&mut self, nts: &[NTermID], len: usize) -> Vec<Vec<RuleIDOrCustom>> {
+        let Some((first, rest)) = nts.split_first() else {
+            return if len == 0 { vec![vec![]] } else { vec![] };
+        };
+        let min_rest = rest
+            .iter()
+            .map(|nt| self.ctx.get_min_len_for_nt(*nt))
+            .sum::<usize>();
+        let mut res = vec![];
+        let min_first = self.ctx.get_min_len_for_nt(*first);
+        if len < min_first + min_rest {
+            return res;
+        }
+        for first_len in min_first..=len - min_rest {
+            let heads = self.by_size(*first, first_len);
+            if heads.is_empty() {
+                continue;
+            }
+            let tails = self.sequences_by_size(rest, len - first_len);
+            for head in heads.iter() {
+                for tail in &tails {
+                    if res.len() >= self.limit {
+                        return res;
+                    }
+                    res.push([&head[..], &tail[..]].concat());
+                }
+            }
+        }
+        res
+    }
+
+    //All derivations of nt that are at most depth nodes deep
+    fn by_depth(&mut self, nt: NTermID, depth: usize) -> Derivations {
+        if let Some(res) = self.depths.get(&(nt, depth)) {
+            return res.clone();
+        }
+        let mut res = vec![];
+        if depth > 0 {
+            for rid in self.ctx.get_rules_for_nt(nt) {
+                let mut partial = vec![vec![node_for_rule(self.ctx, *rid)]];
+                for child in self.ctx.get_rule(*rid).nonterms() {
+                    let subtrees = self.by_depth(*child, depth - 1);
+                    partial = partial
+                        .iter()
+                        .flat_map(|prefix| {
+                            subtrees
+                                .iter()
+                                .map(move |subtree| [&prefix[..], &subtree[..]].concat())
+                        })
+                        .take(self.limit)
+                        .collect();
+                }
+                let free = self.limit.saturating_sub(res.len());
+                res.extend(partial.into_iter().take(free));
+            }
+        }
+        let res = Rc::new(res);
+        self.depths.insert((nt, depth), res.clone());
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use context::Context;
+    use enumeration::{enumerate_by_depth, enumerate_by_size};
+    use tree::TreeLike;
+
+    #[test]
+    fn enumerate() {
+        let mut ctx = Context::new();
+        ctx.add_rule("START", b"{E}");
+        ctx.add_rule("E", b"({E}+{E})");
+        ctx.add_rule("E", b"1");
+        ctx.add_rule("E", b"2");
+        ctx.initialize(20);
+        let start = ctx.nt_id("START");
+
+        let trees = enumerate_by_size(&ctx, start, 6, 1000);
+        let inputs = trees
+            .iter()
+            .map(|t| String::from_utf8(t.unparse_to_vec(&ctx)).expect("RAND_2203985190"))
+            .collect::<Vec<_>>();
+        //2 leaves, 4 sums of two leaves and 16 sums of a sum and a leaf
+        assert_eq!(inputs.len(), 22);
+        assert_eq!(inputs[0], "1");
+        assert!(inputs.contains(&"(2+1)".to_string()));
+        assert_eq!(inputs.iter().collect::<HashSet<_>>().len(), inputs.len());
+        assert!(trees.iter().all(|t| t.size() <= 6));
+
+        //Depth 3: leaves, and sums of two leaves
+        assert_eq!(enumerate_by_depth(&ctx, start, 3, 1000).len(), 6);
+        //Depth 4: E has 2 + 6*6 derivations of depth 3
+        assert_eq!(enumerate_by_depth(&ctx, start, 4, 1000).len(), 38);
+        assert_eq!(enumerate_by_size(&ctx, start, 20, 7).len(), 7);
+    }
+}
@@ -0,0 +1,419 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use context::Context;
+use newtypes::{NTermID, RuleID};
+use rule::{Rule, RuleChild, DEFAULT_WEIGHT};
+
+//Serializers for grammars, e.g. to review grammars that were built programmatically. Script rules
+//can not be exported, they are replaced by placeholders that concatenate their arguments.
+
+//The rule list format of the JSON loader: [NONTERM, RHS] or [NONTERM, RHS, WEIGHT]. START comes
+//first, so the loader uses it as start symbol. JSON has no regexes, regex rules are replaced by a
+//fixed sample. JSON strings can't hold arbitrary bytes, so rules with terminals that aren't UTF-8
+//are an error.
+pub fn to_json(ctx: &Context) -> Result<String, String> {
+    let mut rules = vec![];
+    for rid in rules_in_order(ctx) {
+        let rule = ctx.get_rule(rid);
+        let rhs = match rule {
+            Rule::RegExp(r) => escape_braces(&regex_mutator::generate(&r.hir, 0)),
+            _ => format_string(ctx, rule),
+        };
+        let nt = ctx.nt_id_to_s(rule.nonterm());
+        let rhs = String::from_utf8(rhs).map_err(|e| {
+            let rhs = String::from_utf8_lossy(e.as_bytes());
+            format!("rule {nt} => {rhs} is not UTF-8, export it as Python instead")
+        })?;
+        let mut entry = format!("  [{}, {}", json_str(&nt), json_str(&rhs));
+        if rule.weight() != DEFAULT_WEIGHT {
+            write!(entry, ", {:?}", rule.weight()).expect("RAND_2885197034");
+        }
+        entry.push(']');
+        rules.push(entry);
+    }
+    Ok(format!("[\n{}\n]\n", rules.join(",\n")))
+}
+
+//A grammar file for the Python loader, with one block of rules per nonterminal
+#[must_use]
+pub fn to_python(ctx: &Context) -> String {
+    let mut res = String::new();
+    let mut last_nt = None;
+    for rid in rules_in_order(ctx) {
+        let rule = ctx.get_rule(rid);
+        let nt = rule.nonterm();
+        if last_nt != Some(nt) {
+            if last_nt.is_some() {
+                res.push('\n');
+            }
+            write!(res, "# {}", ctx.nt_id_to_s(nt)).expect("RAND_1627348601");
+            if let Some((min_size, options)) = ctx.get_nt_stats(nt) {
+                write!(
+                    res,
+                    ": min size {min_size}, options: {}",
+                    show_options(options)
+                )
+                .expect("RAND_1627348601");
+            }
+            res.push('\n');
+            last_nt = Some(nt);
+        }
+        let name = python_str(&ctx.nt_id_to_s(nt));
+        match rule {
+            Rule::Plain(_) => write!(
+                res,
+                "ctx.rule({name}, {}",
+                python_bytes(&format_string(ctx, rule))
+            ),
+            Rule::Script(r) => {
+                let args = (0..r.nonterms.len())
+                    .map(|i| format!("a{i}"))
+                    .collect::<Vec<_>>();
+                let nts = r
+                    .nonterms
+                    .iter()
+                    .map(|nt| python_str(&ctx.nt_id_to_s(*nt)))
+                    .collect::<Vec<_>>();
+                res.push_str("# placeholder, the original script can not be exported\n");
+                write!(
+                    res,
+                    "ctx.script({name}, [{}], lambda {}: b\"\"{}",
+                    nts.join(", "),
+                    args.join(", "),
+                    args.iter().map(|a| format!(" + {a}")).collect::<String>()
+                )
+            }
+            Rule::RegExp(r) => write!(res, "ctx.regex({name}, {}", python_str(&r.hir.to_string())),
+        }
+        .expect("RAND_1627348601");
+        if rule.weight() != DEFAULT_WEIGHT {
+            write!(res, ", weight={:?}", rule.weight()).expect("RAND_1627348601");
+        }
+        res.push_str(")\n");
+    }
+    res
+}
+
+//The nonterminal dependency graph: an edge from every nonterminal to the ones its rules use
+#[must_use]
+pub fn to_dot(ctx: &Context) -> String {
+    let mut res = String::from("digraph grammar {\n    node [shape=box];\n");
+    let rules = rules_in_order(ctx);
+    for nt in nts_in_order(ctx) {
+        let name = ctx.nt_id_to_s(nt);
+        if !ctx.has_rules_for_nt(nt) {
+            writeln!(
+                res,
+                "    {} [label=\"{}\\nundefined\", style=dashed];",
+                dot_str(&name),
+                dot_escape(&name)
+            )
+            .expect("RAND_2520893327");
+            continue;
+        }
+        let mut label = format!(
+            "{}\\nrules: {}",
+            dot_escape(&name),
+            ctx.get_rules_for_nt(nt).len()
+        );
+        if let Some((min_size, options)) = ctx.get_nt_stats(nt) {
+            write!(
+                label,
+                "\\nmin size: {min_size}\\noptions: {}",
+                show_options(options)
+            )
+            .expect("RAND_2520893327");
+        }
+        writeln!(res, "    {} [label=\"{label}\"];", dot_str(&name)).expect("RAND_2520893327");
+    }
+    let mut edges = HashSet::new();
+    for rid in rules {
+        let rule = ctx.get_rule(rid);
+        for child in rule.nonterms() {
+            if edges.insert((rule.nonterm(), *child)) {
+                writeln!(
+                    res,
+                    "    {} -> {};",
+                    dot_str(&ctx.nt_id_to_s(rule.nonterm())),
+                    dot_str(&ctx.nt_id_to_s(*child))
+                )
+                .expect("RAND_2520893327");
+            }
+        }
+    }
+    res.push_str("}\n");
+    res
+}
+
+//START first, then breadth first in the order the rules use the nonterminals, then the
+//unreachable ones by name. This doesn't depend on the IDs the loader assigned, so exporting an
+//exported grammar again gives the same result.
+fn nts_in_order(ctx: &Context) -> Vec<NTermID> {
+    let mut nts = vec![];
+    let mut seen = HashSet::new();
+    if let Some(start) = ctx.get_nt_id("START") {
+        nts.push(start);
+        seen.insert(start);
+    }
+    let mut i = 0;
+    while let Some(nt) = nts.get(i).copied() {
+        i += 1;
+        if !ctx.has_rules_for_nt(nt) {
+            continue;
+        }
+        let mut rules = ctx.get_rules_for_nt(nt).clone();
+        rules.sort_by_key(RuleID::to_i);
+        for rid in rules {
+            for child in ctx.get_rule(rid).nonterms() {
+                if seen.insert(*child) {
+                    nts.push(*child);
+                }
+            }
+        }
+    }
+    let mut unreachable = ctx
+        .get_nonterms()
+        .into_iter()
+        .filter(|nt| !seen.contains(nt))
+        .collect::<Vec<_>>();
+    unreachable.sort_by_key(|nt| ctx.nt_id_to_s(*nt));
+    nts.extend(unreachable);
+    nts
+}
+
+//Grouped by nonterminal, in the order the rules were added
+fn rules_in_order(ctx: &Context) -> Vec<RuleID> {
+    let mut res = vec![];
+    for nt in nts_in_order(ctx) {
+        if ctx.has_rules_for_nt(nt) {
+            let mut rules = ctx.get_rules_for_nt(nt).clone();
+            rules.sort_by_key(RuleID::to_i);
+            res.extend(rules);
+        }
+    }
+    res
+}
+
+//The format string the rule was created from
+fn format_string(ctx: &Context, rule: &Rule) -> Vec<u8> {
+    let mut res = vec![];
+    match rule {
+        Rule::Plain(r) => {
+            for child in &r.children {
+                match child {
+                    RuleChild::Term(data) => res.extend(escape_braces(data)),
+                    RuleChild::NTerm(nt) => push_nt(ctx, *nt, &mut res),
+                }
+            }
+        }
+        Rule::Script(r) => {
+            for nt in &r.nonterms {
+                push_nt(ctx, *nt, &mut res);
+            }
+        }
+        Rule::RegExp(_) => unreachable!(),
+    }
+    res
+}
+
+//The number of options saturates for recursive nonterminals
+fn show_options(options: usize) -> String {
+    if options == usize::MAX {
+        "unbounded".to_string()
+    } else {
+        options.to_string()
+    }
+}
+
+fn push_nt(ctx: &Context, nt: NTermID, res: &mut Vec<u8>) {
+    res.push(b'{');
+    res.extend(ctx.nt_id_to_s(nt).as_bytes());
+    res.push(b'}');
+}
+
+//Escapes { and } so that data can be used as the terminal part of a rule
+pub fn escape_braces(data: &[u8]) -> Vec<u8> {
+    let mut res = vec![];
+    for b in data {
+        if *b == b'{' || *b == b'}' {
+            res.push(b'\\');
+        }
+        res.push(*b);
+    }
+    res
+}
+
+//Like escape_braces, for a terminal that may be followed by a nonterminal. A backslash at its end
+//would escape the { of that nonterminal, so it is produced by backslash_nt instead. That has to be
+//a name the grammar doesn't use, its rule is added the first time it is needed.
+pub fn escape_terminal(ctx: &mut Context, data: &[u8], backslash_nt: &str) -> Vec<u8> {
+    match data.split_last() {
+        Some((b'\\', rest)) => {
+            if !ctx.has_term_rule(backslash_nt, b"\\") {
+                ctx.add_rule(backslash_nt, b"\\");
+            }
+            [
+                escape_braces(rest),
+                format!("{{{backslash_nt}}}").into_bytes(),
+            ]
+            .concat()
+        }
+        _ => escape_braces(data),
+    }
+}
+
+fn json_str(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                write!(res, "\\u{:04x}", c as u32).expect("RAND_3043625916");
+            }
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+fn python_str(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(res, "\\x{:02x}", c as u32).expect("RAND_1988423745");
+            }
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+fn python_bytes(data: &[u8]) -> String {
+    let mut res = String::from("b\"");
+    for b in data {
+        match *b {
+            b'"' => res.push_str("\\\""),
+            b'\\' => res.push_str("\\\\"),
+            b'\n' => res.push_str("\\n"),
+            b'\r' => res.push_str("\\r"),
+            b'\t' => res.push_str("\\t"),
+            0x20..=0x7e => res.push(*b as char),
+            _ => write!(res, "\\x{b:02x}").expect("RAND_4100512739"),
+        }
+    }
+    res.push('"');
+    res
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn dot_str(s: &str) -> String {
+    format!("\"{}\"", dot_escape(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use context::Context;
+    use export::{escape_terminal, to_dot, to_json, to_python};
+    use tree::TreeLike;
+
+    #[test]
+    fn export() {
+        let mut ctx = Context::new();
+        //B is mentioned first, but START uses A, which uses B
+        ctx.add_rule("A", b"a\\{{B}\\}\n");
+        let rid = ctx.add_rule("START", b"{A}{A}");
+        ctx.set_rule_weight(rid, 2.5);
+        ctx.add_rule("B", b"\"\\x\"");
+        ctx.add_regex("B", "[0-9]+");
+        ctx.initialize(10);
+
+        assert_eq!(
+            to_json(&ctx).expect("RAND_3370214459"),
+            "[\n  [\"START\", \"{A}{A}\", 2.5],\n  [\"A\", \"a\\\\{{B}\\\\}\\n\"],\n  [\"B\", \"\\\"\\\\x\\\"\"],\n  [\"B\", \"0\"]\n]\n"
+        );
+        assert_eq!(
+            to_python(&ctx),
+            "# START: min size 5, options: 4\nctx.rule(\"START\", b\"{A}{A}\", weight=2.5)\n\n# A: min size 2, options: 2\nctx.rule(\"A\", b\"a\\\\{{B}\\\\}\\n\")\n\n# B: min size 1, options: 2\nctx.rule(\"B\", b\"\\\"\\\\x\\\"\")\nctx.regex(\"B\", \"[0-9]+\")\n"
+        );
+        let dot = to_dot(&ctx);
+        assert!(dot.contains("\"START\" [label=\"START\\nrules: 1\\nmin size: 5\\noptions: 4\"];"));
+        assert!(dot.contains("\"START\" -> \"A\";"));
+        assert!(dot.contains("\"A\" -> \"B\";"));
+        assert_eq!(dot.matches("->").count(), 2);
+    }
+
+    #[test]
+    fn export_order_and_errors() {
+        let mut ctx = Context::new();
+        ctx.add_rule("Z", b"z");
+        ctx.add_rule("START", b"{B}{A}");
+        ctx.add_rule("Y", b"{A}");
+        ctx.add_rule("A", b"a");
+        ctx.add_rule("B", b"{A}b");
+        //Reachable ones breadth first, then the unreachable ones by name
+        let python = to_python(&ctx);
+        let order = ["\"START\"", "\"B\"", "\"A\"", "\"Y\"", "\"Z\""]
+            .iter()
+            .map(|nt| {
+                python
+                    .find(&format!("ctx.rule({nt}"))
+                    .expect("RAND_1081650925")
+            })
+            .collect::<Vec<_>>();
+        assert!(order.windows(2).all(|w| w[0] < w[1]), "{}", python);
+
+        ctx.add_rule("A", b"\xff");
+        assert!(to_json(&ctx).is_err());
+        assert!(to_python(&ctx).contains("ctx.rule(\"A\", b\"\\xff\")"));
+    }
+
+    #[test]
+    fn terminal_before_nonterminal() {
+        let mut ctx = Context::new();
+        let format = [
+            escape_terminal(&mut ctx, b"{\\", "BACKSLASH"),
+            b"{N}".to_vec(),
+            escape_terminal(&mut ctx, b"\\", "BACKSLASH"),
+            b"{N}".to_vec(),
+        ]
+        .concat();
+        assert_eq!(format, b"\\{{BACKSLASH}{N}{BACKSLASH}{N}".to_vec());
+        ctx.add_rule("START", &format);
+        ctx.add_rule("N", b"n");
+        ctx.initialize(10);
+        //The helper is added once
+        assert_eq!(ctx.get_rules_for_nt(ctx.nt_id("BACKSLASH")).len(), 1);
+        let tree = ctx.generate_tree_from_nt(ctx.nt_id("START"), 10);
+        assert_eq!(tree.unparse_to_vec(&ctx), b"{\\n\\n".to_vec());
+    }
+}
@@ -0,0 +1,538 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::mem;
+
+use context::Context;
+use enumeration::node_for_rule;
+use newtypes::{NTermID, NodeID, RuleID};
+use rule::RuleIDOrCustom;
+use tree::{Tree, TreeLike};
+
+//A chain of nested rule applications: every rule derives the nonterminal of the next one. Paths
+//are k rules long, or shorter if they end in a rule without nonterminals.
+pub type KPath = Vec<RuleID>;
+
+//Tracks which k-paths of the grammar (reachable from the start nonterminal) are covered by a set
+//of trees. With k = 1 this is plain rule coverage.
+pub struct KPathCoverage {
+    k: usize,
+    start: NTermID,
+    paths: Vec<KPath>,
+    all: HashSet<KPath>,
+    covered: HashSet<KPath>,
+}
+
+impl KPathCoverage {
+    #[must_use]
+    pub fn new(ctx: &Context, start: NTermID, k: usize) -> Self {
+        assert!(k > 0, "k-paths need at least one rule");
+        let mut paths = vec![];
+        let (reachable, _) = routes_from(ctx, start);
+        for nt in reachable {
+            for rid in ctx.get_rules_for_nt(nt) {
+                extend_paths(ctx, vec![*rid], k, &mut paths);
+            }
+        }
+        //Rules that use a nonterminal twice lead to the same paths twice
+        let mut all = HashSet::new();
+        paths.retain(|path| all.insert(path.clone()));
+        KPathCoverage {
+            k,
+            start,
+            paths,
+            all,
+            covered: HashSet::new(),
+        }
+    }
+
+    //Marks the k-paths of the tree as covered, returns how many were not covered before
+    pub fn add_tree(&mut self, tree: &Tree, ctx: &Context) -> usize {
+        let paths = self.paths_of(tree, ctx);
+        let before = self.covered.len();
+        self.covered.extend(paths);
+        self.covered.len() - before
+    }
+
+    //The k-paths of the grammar that occur in the tree
+    fn paths_of(&self, tree: &Tree, ctx: &Context) -> HashSet<KPath> {
+        let mut paths = HashSet::new();
+        for i in 0..tree.size() {
+            let node = NodeID::from(i);
+            let mut path = vec![tree.get_rule_id(node)];
+            let mut ancestor = tree.get_parent(node);
+            while let (Some(n), true) = (ancestor, path.len() < self.k) {
+                path.push(tree.get_rule_id(n));
+                ancestor = tree.get_parent(n);
+            }
+            path.reverse();
+            let is_leaf = ctx.get_num_children(tree.get_rule_or_custom(node)) == 0;
+            paths.extend(self.paths_ending_in(&path, is_leaf).map(<[RuleID]>::to_vec));
+        }
+        paths
+    }
+
+    //The k-paths of the grammar that end in the last rule of the chain of ancestors. Every path
+    //ending in a leaf counts, other paths only with the full length.
+    fn paths_ending_in<'a>(
+        &'a self,
+        path: &'a [RuleID],
+        is_leaf: bool,
+    ) -> impl Iterator<Item = &'a [RuleID]> + 'a {
+        let shortest = if is_leaf { 1 } else { self.k };
+        let start = path.len().saturating_sub(self.k);
+        (shortest..=path.len() - start)
+            .map(move |len| &path[path.len() - len..])
+            .filter(move |suffix| self.all.contains(*suffix))
+    }
+
+    #[must_use]
+    pub fn covered(&self) -> usize {
+        self.covered.len()
+    }
+
+    #[must_use]
+    pub fn total(&self) -> usize {
+        self.all.len()
+    }
+
+    pub fn uncovered(&self) -> impl Iterator<Item = &KPath> {
+        self.paths
+            .iter()
+            .filter(move |p| !self.covered.contains(*p))
+    }
+}
+
+impl fmt::Display for KPathCoverage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = if self.k == 1 {
+            "rules".to_string()
+        } else {
+            format!("{}-paths", self.k)
+        };
+        write!(
+            f,
+            "{}/{} {what} ({:.1}%)",
+            self.covered(),
+            self.total(),
+            100.0 * self.covered() as f64 / self.total().max(1) as f64
+        )
+    }
+}
+
+//Builds a small set of trees that covers every k-path reachable from start. For every path that
+//is still uncovered, one candidate tree is built that embeds the path into the derivations around
+//it. Those derivations are steered towards further uncovered paths, as long as the tree stays
+//within the maximal tree size of ctx. Finally, a greedy set cover picks among the candidates.
+#[must_use]
+pub fn kpath_corpus(ctx: &Context, start: NTermID, k: usize) -> (Vec<Tree>, KPathCoverage) {
+    let coverage = KPathCoverage::new(ctx, start, k);
+    let (reachable, routes) = routes_from(ctx, coverage.start);
+    let mut builder = Builder::new(ctx, coverage, &reachable);
+    let mut candidates = vec![];
+    for path in builder.coverage.paths.clone() {
+        if builder.coverage.covered.contains(&path) {
+            continue;
+        }
+        let mut chain = routes[&ctx.get_rule(path[0]).nonterm()].clone();
+        chain.extend_from_slice(&path);
+        builder.reserved = ctx.get_min_len_for_nt(start);
+        builder.push_chain(&chain);
+        let tree = Tree::from_rule_vec(mem::take(&mut builder.rules), ctx);
+        candidates.push((builder.coverage.paths_of(&tree, ctx), Some(tree)));
+    }
+
+    //Greedy set cover: take the tree that covers the most paths that are still missing (the
+    //smaller one on ties). The number of missing paths of a tree only decreases, so outdated
+    //entries of the heap are updated lazily.
+    let mut heap = candidates
+        .iter()
+        .enumerate()
+        .map(|(i, (paths, tree))| {
+            let size = tree.as_ref().map_or(0, Tree::size);
+            (paths.len(), Reverse(size), i)
+        })
+        .collect::<BinaryHeap<_>>();
+    let mut covered = HashSet::new();
+    let mut trees = vec![];
+    while let Some((missing, size, i)) = heap.pop() {
+        let (paths, tree) = &mut candidates[i];
+        let now = paths.difference(&covered).count();
+        if now == 0 {
+            continue;
+        }
+        if now < missing {
+            heap.push((now, size, i));
+            continue;
+        }
+        covered.extend(paths.drain());
+        trees.extend(tree.take());
+    }
+    (trees, builder.coverage)
+}
+
+//Builds trees in pre-order and marks the k-paths they cover as they are added
+struct Builder<'a> {
+    ctx: &'a Context,
+    coverage: KPathCoverage,
+    //The number of uncovered paths that start at each nonterminal, and that start with a given
+    //chain of rules. Indexed by RuleID, how many uncovered paths end in the rule and how many of
+    //their prefixes do.
+    open_nts: HashMap<NTermID, usize>,
+    open_prefixes: HashMap<KPath, usize>,
+    open_ends: Vec<usize>,
+    open_prefix_ends: Vec<usize>,
+    //The nonterminals that can be derived from each nonterminal, with the number of rules needed
+    distances: HashMap<NTermID, HashMap<NTermID, usize>>,
+    //For the last k-1 rules and a nonterminal, all rules before this index cover no new paths
+    first_new: HashMap<(KPath, NTermID), usize>,
+    //Cache of the distance from each nonterminal to the closest one with uncovered paths
+    nearest_open: HashMap<NTermID, Option<usize>>,
+    //The rules from the root to the current node
+    ancestors: Vec<RuleID>,
+    rules: Vec<RuleIDOrCustom>,
+    //The minimal size of the nodes that still have to be added
+    reserved: usize,
+}
+
+impl<'a> Builder<'a> {
+    fn new(ctx: &'a Context, coverage: KPathCoverage, reachable: &[NTermID]) -> Self {
+        let mut open_nts = HashMap::new();
+        let mut open_prefixes = HashMap::new();
+        let mut open_ends = vec![0; ctx.get_number_of_rules()];
+        let mut open_prefix_ends = vec![0; ctx.get_number_of_rules()];
+        for path in coverage.uncovered() {
+            *open_nts.entry(ctx.get_rule(path[0]).nonterm()).or_insert(0) += 1;
+            for len in 1..path.len() {
+                *open_prefixes.entry(path[..len].to_vec()).or_insert(0) += 1;
+                open_prefix_ends[usize::from(path[len - 1])] += 1;
+            }
+            open_ends[usize::from(path[path.len() - 1])] += 1;
+        }
+        let distances = reachable
+            .iter()
+            .map(|nt| (*nt, distances_from(ctx, *nt)))
+            .collect();
+        Builder {
+            ctx,
+            coverage,
+            open_nts,
+            open_prefixes,
+            open_ends,
+            open_prefix_ends,
+            distances,
+            first_new: HashMap::new(),
+            nearest_open: HashMap::new(),
+            ancestors: vec![],
+            rules: vec![],
+            reserved: 0,
+        }
+    }
+
+    //Appends the derivation of chain[0]: the first child that matches the next rule of the chain
+    //continues it, all other children are filled
+    fn push_chain(&mut self, chain: &[RuleID]) {
+        let (rid, mut rest) = chain.split_first().expect("RAND_1474950012");
+        self.reserved -= self
+            .ctx
+            .get_min_len_for_nt(self.ctx.get_rule(*rid).nonterm());
+        self.push_rule(*rid);
+        for nt in self.ctx.get_rule(*rid).nonterms() {
+            match rest.first() {
+                Some(next) if self.ctx.get_rule(*next).nonterm() == *nt => {
+                    self.push_chain(rest);
+                    rest = &[];
+                }
+                _ => self.push_filler(*nt),
+            }
+        }
+        self.ancestors.pop();
+    }
+
+    //The rules of a nonterminal are sorted by their minimal size, so the first rule always leads
+    //to the smallest derivation. While the tree is small enough, the first rule is preferred that
+    //covers a new path or continues an uncovered path, then the rule that derives the start of an
+    //uncovered path in the fewest steps. Afterwards, the smallest derivations end the tree.
+    fn push_filler(&mut self, nt: NTermID) {
+        self.reserved -= self.ctx.get_min_len_for_nt(nt);
+        let ctx = self.ctx;
+        let space = ctx
+            .get_max_len()
+            .saturating_sub(self.rules.len() + self.reserved);
+        let rules = ctx
+            .get_rules_for_nt(nt)
+            .iter()
+            .copied()
+            .filter(|r| min_len(ctx, *r) <= space)
+            .collect::<Vec<_>>();
+        let mut rid = self.first_new_path(nt, space).or_else(|| {
+            rules
+                .iter()
+                .find(|r| self.continues_open_path(**r))
+                .copied()
+        });
+        if rid.is_none() {
+            rid = rules
+                .iter()
+                .filter_map(|r| Some((self.distance_to_open_path(*r)?, *r)))
+                .min_by_key(|(distance, _)| *distance)
+                .map(|(_, r)| r);
+        }
+        let rid = rid.unwrap_or(ctx.get_rules_for_nt(nt)[0]);
+        self.push_rule(rid);
+        for child in self.ctx.get_rule(rid).nonterms() {
+            self.push_filler(*child);
+        }
+        self.ancestors.pop();
+    }
+
+    fn push_rule(&mut self, rid: RuleID) {
+        self.reserved += min_len(self.ctx, rid) - 1;
+        self.rules.push(node_for_rule(self.ctx, rid));
+        self.ancestors.push(rid);
+        let is_leaf = self.ctx.get_rule(rid).nonterms().is_empty();
+        let new = self
+            .coverage
+            .paths_ending_in(&self.ancestors, is_leaf)
+            .filter(|path| !self.coverage.covered.contains(*path))
+            .map(<[RuleID]>::to_vec)
+            .collect::<Vec<_>>();
+        for path in new {
+            let open = self
+                .open_nts
+                .get_mut(&self.ctx.get_rule(path[0]).nonterm())
+                .expect("RAND_2268400591");
+            *open -= 1;
+            if *open == 0 {
+                self.nearest_open.clear();
+            }
+            for len in 1..path.len() {
+                *self
+                    .open_prefixes
+                    .get_mut(&path[..len])
+                    .expect("RAND_3725520150") -= 1;
+                self.open_prefix_ends[usize::from(path[len - 1])] -= 1;
+            }
+            self.open_ends[usize::from(path[path.len() - 1])] -= 1;
+            self.coverage.covered.insert(path);
+        }
+    }
+
+    //The first rule of nt that fits into space and covers a new path. Covered paths stay covered,
+    //so the search continues where it stopped last time.
+    fn first_new_path(&mut self, nt: NTermID, space: usize) -> Option<RuleID> {
+        let start = self.ancestors.len().saturating_sub(self.coverage.k - 1);
+        let key = (self.ancestors[start..].to_vec(), nt);
+        let mut first = self.first_new.get(&key).copied().unwrap_or(0);
+        let mut found = None;
+        for (i, rid) in self.ctx.get_rules_for_nt(nt).iter().enumerate().skip(first) {
+            if !self.covers_new_path(*rid) {
+                if first == i {
+                    first += 1;
+                }
+            } else if min_len(self.ctx, *rid) <= space {
+                found = Some(*rid);
+                break;
+            }
+        }
+        self.first_new.insert(key, first);
+        found
+    }
+
+    //The last k rules if rid was the next node
+    fn path_with(&self, rid: RuleID) -> KPath {
+        let start = (self.ancestors.len() + 1).saturating_sub(self.coverage.k);
+        let mut path = self.ancestors[start..].to_vec();
+        path.push(rid);
+        path
+    }
+
+    fn covers_new_path(&self, rid: RuleID) -> bool {
+        if self.open_ends[usize::from(rid)] == 0 {
+            return false;
+        }
+        let is_leaf = self.ctx.get_rule(rid).nonterms().is_empty();
+        let path = self.path_with(rid);
+        let mut paths = self.coverage.paths_ending_in(&path, is_leaf);
+        paths.any(|path| !self.coverage.covered.contains(path))
+    }
+
+    fn continues_open_path(&self, rid: RuleID) -> bool {
+        if self.open_prefix_ends[usize::from(rid)] == 0 {
+            return false;
+        }
+        let path = self.path_with(rid);
+        (1..=path.len()).any(|len| {
+            let prefix = &path[path.len() - len..];
+            self.open_prefixes.get(prefix).copied().unwrap_or(0) > 0
+        })
+    }
+
+    //How many rules below rid the closest uncovered path can start
+    fn distance_to_open_path(&mut self, rid: RuleID) -> Option<usize> {
+        let ctx = self.ctx;
+        ctx.get_rule(rid)
+            .nonterms()
+            .iter()
+            .filter_map(|nt| self.nearest_open_from(*nt))
+            .min()
+    }
+
+    fn nearest_open_from(&mut self, nt: NTermID) -> Option<usize> {
+        if let Some(distance) = self.nearest_open.get(&nt) {
+            return *distance;
+        }
+        let open_nts = &self.open_nts;
+        let distance = self.distances[&nt]
+            .iter()
+            .filter(|(n, _)| open_nts.get(*n).copied().unwrap_or(0) > 0)
+            .map(|(_, distance)| *distance)
+            .min();
+        self.nearest_open.insert(nt, distance);
+        distance
+    }
+}
+
+fn extend_paths(ctx: &Context, path: KPath, k: usize, paths: &mut Vec<KPath>) {
+    let last = ctx.get_rule(*path.last().expect("RAND_3921504713"));
+    if path.len() == k || last.nonterms().is_empty() {
+        paths.push(path);
+        return;
+    }
+    for nt in last.nonterms() {
+        for rid in ctx.get_rules_for_nt(*nt) {
+            let mut next = path.clone();
+            next.push(*rid);
+            extend_paths(ctx, next, k, paths);
+        }
+    }
+}
+
+//Returns the nonterminals reachable from start in breadth first order, and for each of them the
+//shortest chain of rules that leads from start to a rule using it (empty for start itself)
+fn routes_from(ctx: &Context, start: NTermID) -> (Vec<NTermID>, HashMap<NTermID, Vec<RuleID>>) {
+    let mut routes = HashMap::new();
+    routes.insert(start, vec![]);
+    let mut order = vec![];
+    let mut queue = VecDeque::from(vec![start]);
+    while let Some(nt) = queue.pop_front() {
+        order.push(nt);
+        for rid in ctx.get_rules_for_nt(nt) {
+            for child in ctx.get_rule(*rid).nonterms() {
+                if !routes.contains_key(child) {
+                    let mut route = routes[&nt].clone();
+                    route.push(*rid);
+                    routes.insert(*child, route);
+                    queue.push_back(*child);
+                }
+            }
+        }
+    }
+    (order, routes)
+}
+
+//The size of the smallest derivation that starts with rid
+fn min_len(ctx: &Context, rid: RuleID) -> usize {
+    let nts = ctx.get_rule(rid).nonterms();
+    1 + nts
+        .iter()
+        .map(|nt| ctx.get_min_len_for_nt(*nt))
+        .sum::<usize>()
+}
+
+//The number of rules needed to derive each nonterminal that is reachable from nt (0 for nt itself)
+fn distances_from(ctx: &Context, nt: NTermID) -> HashMap<NTermID, usize> {
+    let mut distances = HashMap::new();
+    distances.insert(nt, 0);
+    let mut queue = VecDeque::from(vec![nt]);
+    while let Some(nt) = queue.pop_front() {
+        let distance = distances[&nt] + 1;
+        for rid in ctx.get_rules_for_nt(nt) {
+            for child in ctx.get_rule(*rid).nonterms() {
+                if !distances.contains_key(child) {
+                    distances.insert(*child, distance);
+                    queue.push_back(*child);
+                }
+            }
+        }
+    }
+    distances
+}
+
+#[cfg(test)]
+mod tests {
+    use context::Context;
+    use kpath::{kpath_corpus, KPathCoverage};
+    use tree::TreeLike;
+
+    #[test]
+    fn kpath_coverage() {
+        let mut ctx = Context::new();
+        ctx.add_rule("START", b"{E}");
+        ctx.add_rule("E", b"({E}+{E})");
+        ctx.add_rule("E", b"-{E}");
+        ctx.add_rule("E", b"1");
+        ctx.add_rule("UNUSED", b"{E}");
+        ctx.initialize(20);
+        let start = ctx.nt_id("START");
+
+        //Rule coverage, the unreachable rule does not count
+        let (trees, coverage) = kpath_corpus(&ctx, start, 1);
+        assert_eq!(coverage.total(), 4);
+        assert_eq!(coverage.covered(), 4);
+        assert!(trees.len() <= 3);
+
+        //Starting at START, the sum and the negation: 6 paths of length 3 and one that ends in
+        //the leaf after 2 rules. Plus the leaf itself.
+        let (trees, coverage) = kpath_corpus(&ctx, start, 3);
+        assert_eq!(coverage.total(), 3 * 7 + 1);
+        assert_eq!(coverage.covered(), coverage.total());
+        assert_eq!(coverage.uncovered().count(), 0);
+
+        let mut check = KPathCoverage::new(&ctx, start, 3);
+        for tree in &trees {
+            check.add_tree(tree, &ctx);
+        }
+        assert_eq!(check.covered(), check.total());
+        check = KPathCoverage::new(&ctx, start, 3);
+        check.add_tree(&trees[0], &ctx);
+        assert!(check.covered() < check.total());
+    }
+
+    #[test]
+    fn kpath_corpus_is_small() {
+        let mut ctx = Context::new();
+        ctx.add_rule("START", b"{LIST}");
+        ctx.add_rule("LIST", b"{ITEM},{LIST}");
+        ctx.add_rule("LIST", b"");
+        for i in 0..30 {
+            ctx.add_rule("ITEM", format!("item{i}").as_bytes());
+        }
+        ctx.add_rule("ITEM", b"[{LIST}]");
+        ctx.initialize(50);
+        let start = ctx.nt_id("START");
+
+        //Embedding every path into the smallest derivations needs one tree per item. Filling the
+        //lists with uncovered items, a few trees of at most 52 nodes suffice.
+        let (trees, coverage) = kpath_corpus(&ctx, start, 2);
+        assert_eq!(coverage.covered(), coverage.total());
+        assert!(trees.len() <= 3, "{}", trees.len());
+        assert!(trees.iter().all(|t| t.size() <= 52));
+    }
+}
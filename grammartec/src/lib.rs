@@ -25,10 +25,20 @@ extern crate regex_mutator;
 extern crate regex_syntax;
 extern crate serde;
 
+pub mod byte_mutator;
 pub mod chunkstore;
 pub mod context;
+pub mod dictionary;
+pub mod enumeration;
+pub mod export;
+pub mod kpath;
 pub mod mutator;
 pub mod newtypes;
+pub mod parser;
+pub mod probabilities;
 pub mod recursion_info;
 pub mod rule;
+pub mod size_distribution;
 pub mod tree;
+pub mod uniform_sampler;
+pub mod validation;
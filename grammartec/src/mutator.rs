@@ -22,13 +22,17 @@ use rand::Rng;
 use std::collections::HashSet;
 use std::mem;
 
+use byte_mutator;
 use chunkstore::ChunkStore;
 use context::Context;
 use newtypes::NodeID;
 use recursion_info::RecursionInfo;
-use rule::RuleIDOrCustom;
+use rule::{Rule, RuleIDOrCustom};
 use tree::{Tree, TreeLike, TreeMutation};
 
+//Leaves may grow to twice their size, but short ones at least to this many bytes
+const MIN_LEAF_HAVOC_LEN: usize = 32;
+
 pub struct Mutator {
     scratchpad: Tree,
 }
@@ -165,6 +169,130 @@ impl Mutator {
         Ok(())
     }
 
+    //Input-to-state replacement: every occurrence of `from` in a leaf (a terminal rule or a
+    //regular expression) is replaced by `to` in turn. The new leaf is stored as custom data, the
+    //tester also gets the node and its new content.
+    pub fn mut_replace_in_leaves<F, E>(
+        tree: &Tree,
+        ctx: &Context,
+        from: &[u8],
+        to: &[u8],
+        tester: &mut F,
+    ) -> Result<(), E>
+    where
+        F: FnMut(&TreeMutation, NodeID, &[u8], &Context) -> Result<(), E>,
+    {
+        if from.is_empty() {
+            return Ok(());
+        }
+        for i in 0..tree.size() {
+            let n = NodeID::from(i);
+            if !Mutator::is_leaf(tree, n, ctx) {
+                continue;
+            }
+            let data = tree.unparse_node_to_vec(n, ctx);
+            if data.len() < from.len() {
+                continue;
+            }
+            for pos in 0..=data.len() - from.len() {
+                if &data[pos..pos + from.len()] != from {
+                    continue;
+                }
+                let mut new_data = data[..pos].to_vec();
+                new_data.extend_from_slice(to);
+                new_data.extend_from_slice(&data[pos + from.len()..]);
+                let repl = [RuleIDOrCustom::Custom(tree.get_rule_id(n), new_data)];
+                let mutation = TreeMutation {
+                    prefix: &tree.rules[..i],
+                    repl: &repl,
+                    postfix: &tree.rules[i + 1..],
+                };
+                tester(&mutation, n, repl[0].data(), ctx)?;
+            }
+        }
+        Ok(())
+    }
+
+    //Replaces a random leaf with a random token of the dictionary that is not mapped to a
+    //nonterminal. The token is stored as custom data of the leaf.
+    pub fn mut_dict_insert<F, E>(tree: &Tree, ctx: &Context, tester: &mut F) -> Result<(), E>
+    where
+        F: FnMut(&TreeMutation, &Context) -> Result<(), E>,
+    {
+        let mut rng = rand::thread_rng();
+        let Some(token) = ctx.get_dictionary().choose(&mut rng) else {
+            return Ok(());
+        };
+        let leaves = (0..tree.size())
+            .filter(|i| Mutator::is_leaf(tree, NodeID::from(*i), ctx))
+            .collect::<Vec<_>>();
+        if let Some(&i) = leaves.choose(&mut rng) {
+            let repl = [RuleIDOrCustom::Custom(
+                tree.get_rule_id(NodeID::from(i)),
+                token.clone(),
+            )];
+            let mutation = TreeMutation {
+                prefix: &tree.rules[..i],
+                repl: &repl,
+                postfix: &tree.rules[i + 1..],
+            };
+            tester(&mutation, ctx)?;
+        }
+        Ok(())
+    }
+
+    //Applies byte level havoc to the content of a random leaf. The result is stored as custom
+    //data, so the structure of the tree stays the same.
+    pub fn mut_leaf_havoc<F, E>(tree: &Tree, ctx: &Context, tester: &mut F) -> Result<(), E>
+    where
+        F: FnMut(&TreeMutation, &Context) -> Result<(), E>,
+    {
+        let mut rng = rand::thread_rng();
+        let leaves = (0..tree.size())
+            .filter(|i| Mutator::is_leaf(tree, NodeID::from(*i), ctx))
+            .collect::<Vec<_>>();
+        if let Some(&i) = leaves.choose(&mut rng) {
+            let n = NodeID::from(i);
+            let mut data = tree.unparse_node_to_vec(n, ctx);
+            let max_len = (data.len() * 2).max(MIN_LEAF_HAVOC_LEN);
+            byte_mutator::havoc(&mut data, max_len, &mut rng);
+            let repl = [RuleIDOrCustom::Custom(tree.get_rule_id(n), data)];
+            let mutation = TreeMutation {
+                prefix: &tree.rules[..i],
+                repl: &repl,
+                postfix: &tree.rules[i + 1..],
+            };
+            tester(&mutation, ctx)?;
+        }
+        Ok(())
+    }
+
+    //Applies byte level havoc to the unparsed tree (code). The result is an opaque tree: a single
+    //node with the rule of the root whose custom data is the whole input.
+    pub fn mut_raw_havoc<F, E>(
+        tree: &Tree,
+        code: &[u8],
+        ctx: &Context,
+        tester: &mut F,
+    ) -> Result<(), E>
+    where
+        F: FnMut(&TreeMutation, &Context) -> Result<(), E>,
+    {
+        let mut data = code.to_vec();
+        let max_len = (data.len() * 2).max(MIN_LEAF_HAVOC_LEN);
+        byte_mutator::havoc(&mut data, max_len, &mut rand::thread_rng());
+        let repl = [RuleIDOrCustom::Custom(
+            tree.get_rule_id(NodeID::from(0)),
+            data,
+        )];
+        let mutation = TreeMutation {
+            prefix: &[],
+            repl: &repl,
+            postfix: &[],
+        };
+        tester(&mutation, ctx)
+    }
+
     //pub fn rec_splice<F, E>(
     //    &mut self,
     //    tree: &Tree,
@@ -279,6 +407,18 @@ impl Mutator {
         Ok(())
     }
 
+    //Terminal rules, regular expressions and nodes with custom data
+    fn is_leaf(tree: &Tree, n: NodeID, ctx: &Context) -> bool {
+        if tree.has_custom_data(n) {
+            return true;
+        }
+        match tree.get_rule(n, ctx) {
+            Rule::Plain(r) => r.nonterms.is_empty(),
+            Rule::RegExp(_) => true,
+            Rule::Script(_) => false,
+        }
+    }
+
     fn find_parent_with_nt(tree: &Tree, mut node: NodeID, ctx: &Context) -> Option<NodeID> {
         let nt = tree.get_rule(node, ctx).nonterm();
         while let Some(parent) = tree.get_parent(node) {
@@ -315,7 +455,7 @@ mod tests {
     use chunkstore::ChunkStore;
     use context::Context;
     use mutator::Mutator;
-    use newtypes::RuleID;
+    use newtypes::{NodeID, RuleID};
     use rule::RuleIDOrCustom;
     use std::collections::HashSet;
     use std::str;
@@ -478,6 +618,128 @@ mod tests {
         }
     }
 
+    #[test]
+    fn replace_in_leaves() {
+        let mut ctx = Context::new();
+        let r1 = ctx.add_rule("START", b"{A}={B}");
+        let _ = ctx.add_term_rule("A", b"magic_key");
+        //B contains exactly one "1"
+        let _ = ctx.add_regex("B", "1[2-9]");
+        ctx.initialize(101);
+        let tree = ctx.generate_tree_from_rule(r1, 100);
+        let unparse = tree.unparse_to_vec(&ctx);
+
+        let mut results = vec![];
+        let mut tester = |tree_mut: &TreeMutation, _n: NodeID, data: &[u8], ctx: &Context| {
+            results.push((tree_mut.to_tree(ctx).unparse_to_vec(ctx), data.to_vec()));
+            Ok::<_, ()>(())
+        };
+        Mutator::mut_replace_in_leaves(&tree, &ctx, b"key", b"door", &mut tester)
+            .expect("RAND_1405830183");
+        Mutator::mut_replace_in_leaves(&tree, &ctx, b"=", b"!", &mut tester)
+            .expect("RAND_3117826005");
+        Mutator::mut_replace_in_leaves(&tree, &ctx, b"1", b"77", &mut tester)
+            .expect("RAND_2283170337");
+
+        //Terminals of rules with nonterminals ("=") are not leaves and stay untouched
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, [b"magic_door=", &unparse[10..]].concat());
+        assert_eq!(results[0].1, b"magic_door");
+        assert_eq!(results[1].0, [b"magic_key=77", &unparse[11..]].concat());
+    }
+
+    #[test]
+    fn dict_insert() {
+        let mut ctx = Context::new();
+        let r1 = ctx.add_rule("START", b"({A},{B})");
+        let _ = ctx.add_term_rule("A", b"a");
+        let _ = ctx.add_regex("B", "[0-9]");
+        ctx.initialize(101);
+        let tree = ctx.generate_tree_from_rule(r1, 100);
+
+        let mut results = HashSet::new();
+        let mut tester = |tree_mut: &TreeMutation, ctx: &Context| {
+            results.insert(tree_mut.to_tree(ctx).unparse_to_vec(ctx));
+            Ok::<_, ()>(())
+        };
+        //Nothing to insert without tokens
+        Mutator::mut_dict_insert(&tree, &ctx, &mut tester).expect("RAND_3407016321");
+        assert!(results.is_empty());
+
+        ctx.add_dictionary(None, &[b"TOKEN".to_vec()])
+            .expect("RAND_1310428517");
+        let unparse = tree.unparse_to_vec(&ctx);
+        let mut results = HashSet::new();
+        let mut tester = |tree_mut: &TreeMutation, ctx: &Context| {
+            results.insert(tree_mut.to_tree(ctx).unparse_to_vec(ctx));
+            Ok::<_, ()>(())
+        };
+        for _ in 0..100 {
+            Mutator::mut_dict_insert(&tree, &ctx, &mut tester).expect("RAND_2559813906");
+        }
+        let expected = [
+            [b"(TOKEN", &unparse[2..]].concat(),
+            [&unparse[..3], b"TOKEN)"].concat(),
+        ];
+        assert_eq!(results, expected.iter().cloned().collect());
+    }
+
+    #[test]
+    fn leaf_havoc() {
+        let mut ctx = Context::new();
+        let r1 = ctx.add_rule("START", b"({A},{B})");
+        let _ = ctx.add_term_rule("A", b"aaaa");
+        let _ = ctx.add_regex("B", "[0-9]{4}");
+        ctx.initialize(101);
+        let tree = ctx.generate_tree_from_rule(r1, 100);
+        let unparse = tree.unparse_to_vec(&ctx);
+
+        let mut tester = |tree_mut: &TreeMutation, ctx: &Context| {
+            //Only the content of one of the leaves changes
+            let new_tree = tree_mut.to_tree(ctx);
+            assert_eq!(new_tree.size(), tree.size());
+            let a = new_tree.unparse_node_to_vec(NodeID::from(1), ctx);
+            let b = new_tree.unparse_node_to_vec(NodeID::from(2), ctx);
+            assert!(a == unparse[1..5] || b == unparse[6..10]);
+            assert!(a.len() <= 32 && b.len() <= 32);
+            let new_unparse = new_tree.unparse_to_vec(ctx);
+            assert_eq!(new_unparse, [b"(", &a[..], b",", &b[..], b")"].concat());
+            Ok::<_, ()>(())
+        };
+        for _ in 0..100 {
+            Mutator::mut_leaf_havoc(&tree, &ctx, &mut tester).expect("RAND_1884213050");
+        }
+    }
+
+    #[test]
+    fn raw_havoc() {
+        let mut ctx = Context::new();
+        let r1 = ctx.add_rule("START", b"({A},{A})");
+        let _ = ctx.add_rule("A", b"a{A}");
+        let _ = ctx.add_term_rule("A", b"b");
+        ctx.initialize(101);
+        let tree = ctx.generate_tree_from_rule(r1, 100);
+        let unparse = tree.unparse_to_vec(&ctx);
+
+        let mut opaque_trees = vec![];
+        let mut tester = |tree_mut: &TreeMutation, ctx: &Context| {
+            opaque_trees.push(tree_mut.to_tree(ctx));
+            Ok::<_, ()>(())
+        };
+        for _ in 0..10 {
+            Mutator::mut_raw_havoc(&tree, &unparse, &ctx, &mut tester).expect("RAND_4212270185");
+        }
+        for opaque in &opaque_trees {
+            assert_eq!(opaque.size(), 1);
+            assert_eq!(opaque.get_rule_id(NodeID::from(0)), r1);
+            assert_eq!(
+                opaque.unparse_to_vec(&ctx),
+                opaque.get_custom_rule_data(NodeID::from(0))
+            );
+            assert!(opaque.calc_recursions(&ctx).is_none());
+        }
+    }
+
     #[test]
     fn deterministic_rule() {
         let mut ctx = Context::new();
@@ -507,7 +769,7 @@ mod tests {
     #[test]
     fn deterministic_splice() {
         let mut ctx = Context::new();
-        let mut cks = ChunkStore::new("/tmp/".to_string());
+        let mut cks = ChunkStore::new("/tmp/".to_string(), String::new());
         let r1 = ctx.add_rule("A", b"a {A:a}");
         let _ = ctx.add_rule("A", b"b {A:a}");
         let r3 = ctx.add_rule("A", b"c {A:a}");
@@ -0,0 +1,341 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{HashMap, HashSet};
+
+use context::Context;
+use newtypes::{NTermID, RuleID};
+use regex::bytes::Regex;
+use rule::{Rule, RuleChild, RuleIDOrCustom};
+use tree::Tree;
+
+//Turns an input back into a tree of the grammar with an Earley parser. Regex terminals match
+//greedily (like the tokens of a lexer) and script rules can't be inverted, so inputs that need
+//them to be parsed differently are rejected.
+
+//A rule with the number of children that were matched so far, starting at origin
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Item {
+    rule: RuleID,
+    dot: usize,
+    origin: usize,
+}
+
+struct Parser<'a> {
+    ctx: &'a Context,
+    input: &'a [u8],
+    //sets[i] contains the items that matched the input up to position i
+    sets: Vec<Vec<Item>>,
+    seen: Vec<HashSet<Item>>,
+    //Nonterminals that matched the empty input at position i
+    nullable_at: Vec<HashSet<NTermID>>,
+    predicted_at: Vec<HashSet<NTermID>>,
+    regexes: HashMap<RuleID, Option<Regex>>,
+}
+
+impl<'a> Parser<'a> {
+    fn len(&self, rule: RuleID) -> usize {
+        match self.ctx.get_rule(rule) {
+            Rule::Plain(r) => r.children.len(),
+            Rule::RegExp(_) | Rule::Script(_) => 1,
+        }
+    }
+
+    fn add(&mut self, pos: usize, item: Item) {
+        if self.seen[pos].insert(item) {
+            self.sets[pos].push(item);
+        }
+    }
+
+    //The length of the greedy match of the regex rule at pos
+    fn match_regex(&mut self, rule: RuleID, pos: usize) -> Option<usize> {
+        let ctx = self.ctx;
+        let input = self.input;
+        let regex = self
+            .regexes
+            .entry(rule)
+            .or_insert_with(|| match ctx.get_rule(rule) {
+                Rule::RegExp(r) => Regex::new(&format!("^(?:{})", r.hir)).ok(),
+                _ => None,
+            });
+        regex
+            .as_ref()
+            .and_then(|regex| regex.find(&input[pos..]))
+            .map(|m| m.end())
+    }
+
+    fn predict(&mut self, nt: NTermID, pos: usize) {
+        if !self.predicted_at[pos].insert(nt) {
+            return;
+        }
+        for &rule in self.ctx.get_rules_for_nt(nt) {
+            match self.ctx.get_rule(rule) {
+                Rule::Plain(_) => self.add(
+                    pos,
+                    Item {
+                        rule,
+                        dot: 0,
+                        origin: pos,
+                    },
+                ),
+                Rule::RegExp(_) => {
+                    if let Some(len) = self.match_regex(rule, pos) {
+                        self.add(
+                            pos + len,
+                            Item {
+                                rule,
+                                dot: 1,
+                                origin: pos,
+                            },
+                        );
+                    }
+                }
+                Rule::Script(_) => {}
+            }
+        }
+    }
+
+    fn complete(&mut self, item: Item, pos: usize) {
+        let nt = self.ctx.get_nt(&RuleIDOrCustom::Rule(item.rule));
+        if item.origin == pos {
+            //Items that are added to this set later on skip nt right away
+            self.nullable_at[pos].insert(nt);
+        }
+        let mut i = 0;
+        while i < self.sets[item.origin].len() {
+            let waiting = self.sets[item.origin][i];
+            if self.next_nt(waiting) == Some(nt) {
+                let advanced = Item {
+                    dot: waiting.dot + 1,
+                    ..waiting
+                };
+                self.add(pos, advanced);
+            }
+            i += 1;
+        }
+    }
+
+    fn next_nt(&self, item: Item) -> Option<NTermID> {
+        match self.ctx.get_rule(item.rule) {
+            Rule::Plain(r) => match r.children.get(item.dot) {
+                Some(RuleChild::NTerm(nt)) => Some(*nt),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn run(&mut self, start: NTermID) {
+        self.predict(start, 0);
+        for pos in 0..=self.input.len() {
+            let mut i = 0;
+            while i < self.sets[pos].len() {
+                let item = self.sets[pos][i];
+                i += 1;
+                if item.dot == self.len(item.rule) {
+                    self.complete(item, pos);
+                    continue;
+                }
+                let Rule::Plain(r) = self.ctx.get_rule(item.rule) else {
+                    continue;
+                };
+                match &r.children[item.dot] {
+                    RuleChild::Term(term) => {
+                        if self.input[pos..].starts_with(term) {
+                            let advanced = Item {
+                                dot: item.dot + 1,
+                                ..item
+                            };
+                            self.add(pos + term.len(), advanced);
+                        }
+                    }
+                    RuleChild::NTerm(nt) => {
+                        let nt = *nt;
+                        self.predict(nt, pos);
+                        if self.nullable_at[pos].contains(&nt) {
+                            self.add(
+                                pos,
+                                Item {
+                                    dot: item.dot + 1,
+                                    ..item
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    //Finds a rule of nt that matched from start to end, except for the ones that are already
+    //being built for the same range (that would loop forever)
+    fn completed(&self, nt: NTermID, start: usize, end: usize, path: &[Item]) -> Vec<RuleID> {
+        self.sets[end]
+            .iter()
+            .filter(|item| item.origin == start && item.dot == self.len(item.rule))
+            .filter(|item| self.ctx.get_nt(&RuleIDOrCustom::Rule(item.rule)) == nt)
+            .filter(|item| {
+                !path
+                    .iter()
+                    .any(|p| p.rule == item.rule && p.origin == start && p.dot == end)
+            })
+            .map(|item| item.rule)
+            .collect()
+    }
+
+    //Appends the nodes of a derivation of rule from start to end in pre-order. path holds the
+    //rules that are built around this one, with the end of their range as dot.
+    fn build(
+        &self,
+        rule: RuleID,
+        start: usize,
+        end: usize,
+        path: &mut Vec<Item>,
+        res: &mut Vec<RuleIDOrCustom>,
+    ) -> bool {
+        let r = match self.ctx.get_rule(rule) {
+            Rule::Plain(r) => r,
+            _ => {
+                res.push(RuleIDOrCustom::Custom(
+                    rule,
+                    self.input[start..end].to_vec(),
+                ));
+                return true;
+            }
+        };
+        //Find where each child starts, from the last one backwards. An item with dot children
+        //that ends at a position guarantees that the first dot children can match up to there.
+        let mut bounds = vec![end];
+        let mut children = vec![];
+        let mut pos = end;
+        for dot in (0..r.children.len()).rev() {
+            let prefix = |p: usize| {
+                self.seen[p].contains(&Item {
+                    rule,
+                    dot,
+                    origin: start,
+                })
+            };
+            let found = match &r.children[dot] {
+                RuleChild::Term(term) => pos
+                    .checked_sub(term.len())
+                    .filter(|&p| self.input[p..pos] == term[..] && prefix(p))
+                    .map(|p| (p, None)),
+                RuleChild::NTerm(nt) => (start..=pos).rev().find_map(|p| {
+                    if !prefix(p) {
+                        return None;
+                    }
+                    self.completed(*nt, p, pos, path)
+                        .first()
+                        .map(|child| (p, Some(*child)))
+                }),
+            };
+            let Some((p, child)) = found else {
+                return false;
+            };
+            children.push(child);
+            bounds.push(p);
+            pos = p;
+        }
+        if pos != start {
+            return false;
+        }
+        res.push(RuleIDOrCustom::Rule(rule));
+        path.push(Item {
+            rule,
+            dot: end,
+            origin: start,
+        });
+        for (child, range) in children.iter().rev().zip(bounds.windows(2).rev()) {
+            if let Some(child) = child {
+                if !self.build(*child, range[1], range[0], path, res) {
+                    return false;
+                }
+            }
+        }
+        path.pop();
+        true
+    }
+}
+
+//Parses the input as a derivation of nt. Returns None if it doesn't match the grammar.
+#[must_use]
+pub fn parse(ctx: &Context, nt: NTermID, input: &[u8]) -> Option<Tree> {
+    let mut parser = Parser {
+        ctx,
+        input,
+        sets: vec![vec![]; input.len() + 1],
+        seen: vec![HashSet::new(); input.len() + 1],
+        nullable_at: vec![HashSet::new(); input.len() + 1],
+        predicted_at: vec![HashSet::new(); input.len() + 1],
+        regexes: HashMap::new(),
+    };
+    parser.run(nt);
+    let mut res = vec![];
+    for rule in parser.completed(nt, 0, input.len(), &[]) {
+        res.clear();
+        if parser.build(rule, 0, input.len(), &mut vec![], &mut res) {
+            return Some(Tree::from_rule_vec(res, ctx));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use context::Context;
+    use parser::parse;
+    use tree::TreeLike;
+
+    #[test]
+    fn parse_inputs() {
+        let mut ctx = Context::new();
+        let _ = ctx.add_rule("START", b"{EXPR}");
+        let _ = ctx.add_rule("EXPR", b"{EXPR} + {EXPR}");
+        let _ = ctx.add_rule("EXPR", b"({EXPR})");
+        let _ = ctx.add_rule("EXPR", b"{NUM}{OPT}");
+        let _ = ctx.add_rule("OPT", b"");
+        let _ = ctx.add_rule("OPT", b"!");
+        let _ = ctx.add_regex("NUM", "[0-9]+");
+        ctx.initialize(100);
+        let start = ctx.nt_id("START");
+        for input in [&b"1"[..], b"(12 + 3!) + 4", b"((7))"] {
+            let tree = parse(&ctx, start, input).expect("RAND_2314072187");
+            assert_eq!(tree.unparse_to_vec(&ctx), input);
+            assert_eq!(tree.get_rule(0.into(), &ctx).nonterm(), start);
+        }
+        for input in [&b""[..], b"1 +", b"(1", b"a", b"1!!"] {
+            assert!(parse(&ctx, start, input).is_none());
+        }
+    }
+
+    #[test]
+    fn parse_nullable_and_cyclic_rules() {
+        let mut ctx = Context::new();
+        let _ = ctx.add_rule("START", b"{A}{B}x");
+        let _ = ctx.add_rule("A", b"{B}");
+        let _ = ctx.add_rule("B", b"{A}");
+        let _ = ctx.add_rule("B", b"");
+        let _ = ctx.add_rule("B", b"b");
+        ctx.initialize(100);
+        let start = ctx.nt_id("START");
+        for input in [&b"x"[..], b"bx", b"bbx"] {
+            let tree = parse(&ctx, start, input).expect("RAND_2314072188");
+            assert_eq!(tree.unparse_to_vec(&ctx), input);
+        }
+        assert!(parse(&ctx, start, b"bbbx").is_none());
+    }
+}
@@ -0,0 +1,177 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+
+use context::Context;
+use newtypes::{NTermID, RuleID};
+use serde::{Deserialize, Serialize};
+use tree::{Tree, TreeLike};
+
+//Probability of each rule among the rules of its nonterminal, estimated from how often the rule
+//was used in a corpus of trees. The nonterminal names are stored as well, so that applying the
+//probabilities to a different grammar is detected.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RuleProbabilities {
+    //Indexed by RuleID
+    rules: Vec<(String, f64)>,
+}
+
+impl RuleProbabilities {
+    //Laplace smoothing: every rule is counted `smoothing` more times than it was seen, so rules
+    //that never occur in the corpus keep a small probability. Rules that ctx doesn't know are
+    //ignored: saved trees also contain the rules a campaign added at runtime (dictionaries,
+    //cmplog), which only exist in that campaign.
+    pub fn learn<'a, I>(trees: I, ctx: &Context, smoothing: f64) -> Self
+    where
+        I: IntoIterator<Item = &'a Tree>,
+    {
+        let mut counts = vec![0.0; ctx.get_number_of_rules()];
+        for tree in trees {
+            for i in 0..tree.size() {
+                let id: usize = tree.get_rule_id(i.into()).into();
+                if let Some(count) = counts.get_mut(id) {
+                    *count += 1.0;
+                }
+            }
+        }
+
+        let mut totals: HashMap<NTermID, f64> = HashMap::new();
+        for (id, count) in counts.iter().enumerate() {
+            let nt = ctx.get_rule(RuleID::from(id)).nonterm();
+            *totals.entry(nt).or_insert(0.0) += count + smoothing;
+        }
+        let rules = counts
+            .iter()
+            .enumerate()
+            .map(|(id, count)| {
+                let nt = ctx.get_rule(RuleID::from(id)).nonterm();
+                let total = totals[&nt];
+                let p = if total > 0.0 {
+                    (count + smoothing) / total
+                } else {
+                    1.0 / ctx.get_rules_for_nt(nt).len() as f64
+                };
+                (ctx.nt_id_to_s(nt), p)
+            })
+            .collect();
+        RuleProbabilities { rules }
+    }
+
+    //Uncommon derivations: every rule gets a probability proportional to 1/p, so rules that were
+    //rare in the corpus become the most likely ones.
+    #[must_use]
+    pub fn inverted(&self) -> Self {
+        let mut totals: HashMap<&str, f64> = HashMap::new();
+        for (nt, p) in &self.rules {
+            *totals.entry(nt).or_insert(0.0) += inverse(*p);
+        }
+        let rules = self
+            .rules
+            .iter()
+            .map(|(nt, p)| (nt.clone(), inverse(*p) / totals[nt.as_str()]))
+            .collect();
+        RuleProbabilities { rules }
+    }
+
+    #[must_use]
+    pub fn get(&self, r: RuleID) -> f64 {
+        let id: usize = r.into();
+        self.rules[id].1
+    }
+
+    //Uses the probabilities as rule weights
+    pub fn apply(&self, ctx: &mut Context) -> Result<(), String> {
+        if self.rules.len() != ctx.get_number_of_rules() {
+            return Err(format!(
+                "the probabilities are for {} rules, the grammar has {}",
+                self.rules.len(),
+                ctx.get_number_of_rules()
+            ));
+        }
+        for (id, (nt, p)) in self.rules.iter().enumerate() {
+            let rid = RuleID::from(id);
+            let rule_nt = ctx.nt_id_to_s(ctx.get_rule(rid).nonterm());
+            if *nt != rule_nt {
+                return Err(format!("rule {id} derives {rule_nt}, not {nt}"));
+            }
+            if !p.is_finite() || *p < 0.0 {
+                return Err(format!("invalid probability {p} for rule {id}"));
+            }
+            ctx.set_rule_weight(rid, *p);
+        }
+        Ok(())
+    }
+}
+
+fn inverse(p: f64) -> f64 {
+    if p > 0.0 {
+        1.0 / p
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use context::Context;
+    use probabilities::RuleProbabilities;
+    use rule::RuleIDOrCustom;
+    use tree::Tree;
+
+    #[test]
+    fn learn_probabilities() {
+        let mut ctx = Context::new();
+        let r0 = ctx.add_rule("START", b"{A}{A}{A}");
+        let r1 = ctx.add_rule("A", b"a");
+        let r2 = ctx.add_rule("A", b"b");
+        let r3 = ctx.add_rule("A", b"c");
+        ctx.initialize(10);
+        let rules = [r0, r1, r1, r2]
+            .iter()
+            .map(|r| RuleIDOrCustom::Rule(*r))
+            .collect();
+        let tree = Tree::from_rule_vec(rules, &ctx);
+
+        let probs = RuleProbabilities::learn(vec![&tree, &tree], &ctx, 1.0);
+        assert!((probs.get(r0) - 1.0).abs() < 1e-9);
+        assert!((probs.get(r1) - 5.0 / 9.0).abs() < 1e-9);
+        assert!((probs.get(r2) - 3.0 / 9.0).abs() < 1e-9);
+        assert!((probs.get(r3) - 1.0 / 9.0).abs() < 1e-9);
+
+        //1/p is 9/5, 9/3 and 9/1, normalized by 69/5
+        let inverted = probs.inverted();
+        assert!((inverted.get(r0) - 1.0).abs() < 1e-9);
+        assert!((inverted.get(r1) - 9.0 / 69.0).abs() < 1e-9);
+        assert!((inverted.get(r3) - 45.0 / 69.0).abs() < 1e-9);
+
+        inverted.apply(&mut ctx).expect("RAND_2907167840");
+        assert!((ctx.get_rule(r3).weight() - 45.0 / 69.0).abs() < 1e-9);
+
+        //Probabilities and trees of a different grammar
+        let mut other = Context::new();
+        let _ = other.add_rule("START", b"{A}");
+        let _ = other.add_rule("B", b"b");
+        let _ = other.add_rule("A", b"a");
+        let _ = other.add_rule("A", b"b");
+        assert!(probs.apply(&mut other).is_err());
+        let r4 = other.add_rule("C", b"c");
+        assert!(probs.apply(&mut other).is_err());
+        let unknown = Tree::from_rule_vec(vec![RuleIDOrCustom::Rule(r4)], &other);
+        let ignored = RuleProbabilities::learn(vec![&tree, &tree, &unknown], &ctx, 1.0);
+        assert_eq!(ignored, probs);
+    }
+}
@@ -30,6 +30,27 @@ pub enum RuleChild {
     NTerm(NTermID),
 }
 
+pub const DEFAULT_WEIGHT: f64 = 1.0;
+
+lazy_static! {
+    static ref SPLITTER: regex::Regex =
+        regex::Regex::new(r"^\{([a-zA-Z_][a-zA-Z_\-0-9]*)(?::([a-zA-Z_\-0-9]*))?\}$")
+            .expect("RAND_1363289094");
+    static ref TOKENIZER: regex::bytes::Regex =
+        regex::bytes::RegexBuilder::new(r"(?-u)(\{[^}\\]+\})|((?:[^{\\]|\\\{|\\\}|\\)+)")
+            .dot_matches_new_line(true)
+            .build()
+            .expect("RAND_994455541");
+} //TOKENIZER Changed from (\{[^}\\]+\})|((?:[^{\\]|\\\{|\\\}|\\\\)+) because of problems with \\ (\\ was not matched and therefore thrown away)
+
+fn show_weight(weight: f64) -> String {
+    if (weight - DEFAULT_WEIGHT).abs() < f64::EPSILON {
+        String::new()
+    } else {
+        format!(" [weight {weight}]")
+    }
+}
+
 fn show_bytes(bs: &[u8]) -> String {
     use std::ascii::escape_default;
     use std::str;
@@ -54,16 +75,15 @@ impl RuleChild {
     }
 
     fn split_nt_description(nonterm: &str) -> (String, String) {
-        lazy_static! {
-            static ref SPLITTER: regex::Regex =
-                regex::Regex::new(r"^\{([A-Z][a-zA-Z_\-0-9]*)(?::([a-zA-Z_\-0-9]*))?\}$")
-                    .expect("RAND_1363289094");
-        }
+        let name = RuleChild::parse_nt_description(nonterm).unwrap_or_else(|e| panic!("{}", e));
+        (name, String::new())
+    }
 
-        //splits {A:a} or {A} into A and maybe a
-        let descr = SPLITTER.captures(nonterm).unwrap_or_else(|| panic!("{}", "could not interpret Nonterminal {nonterm:?}. Nonterminal Descriptions need to match start with a capital letter and con only contain [a-zA-Z_-0-9]"));
+    //splits {A:a} or {A} into A and maybe a
+    fn parse_nt_description(nonterm: &str) -> Result<String, String> {
+        let descr = SPLITTER.captures(nonterm).ok_or_else(|| format!("could not interpret Nonterminal {nonterm:?}. Nonterminal Descriptions need to start with a letter or _ and can only contain [a-zA-Z_-0-9]"))?;
         //let name = descr.get(2).map(|m| m.as_str().into()).unwrap_or(default.to_string()));
-        (descr[1].into(), String::new())
+        Ok(descr[1].into())
     }
 
     fn debug_show(&self, ctx: &Context) -> String {
@@ -107,20 +127,46 @@ pub enum Rule {
 pub struct RegExpRule {
     pub nonterm: NTermID,
     pub hir: Hir,
+    pub weight: f64,
 }
 
 impl RegExpRule {
     #[must_use]
     pub fn debug_show(&self, ctx: &Context) -> String {
-        format!("{} => {:?}", ctx.nt_id_to_s(self.nonterm), self.hir)
+        format!(
+            "{} => {:?}{}",
+            ctx.nt_id_to_s(self.nonterm),
+            self.hir,
+            show_weight(self.weight)
+        )
     }
 }
 
+//Script functions implemented in Rust get the output of each nonterminal, e.g. to prefix it
+//with its length
+pub type NativeScript = fn(&[Vec<u8>]) -> Vec<u8>;
+
 #[derive(Debug)]
+pub enum Script {
+    Python(PyObject),
+    Native(NativeScript),
+}
+
+impl Clone for Script {
+    fn clone(&self) -> Self {
+        match self {
+            Script::Python(script) => Python::with_gil(|py| Script::Python(script.clone_ref(py))),
+            Script::Native(script) => Script::Native(*script),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ScriptRule {
     pub nonterm: NTermID,
     pub nonterms: Vec<NTermID>,
-    pub script: PyObject,
+    pub script: Script,
+    pub weight: f64,
 }
 
 impl ScriptRule {
@@ -132,7 +178,11 @@ impl ScriptRule {
             .map(|nt| ctx.nt_id_to_s(*nt))
             .collect::<Vec<_>>()
             .join(", ");
-        format!("{} => func({args})", ctx.nt_id_to_s(self.nonterm))
+        format!(
+            "{} => func({args}){}",
+            ctx.nt_id_to_s(self.nonterm),
+            show_weight(self.weight)
+        )
     }
 }
 
@@ -141,6 +191,7 @@ pub struct PlainRule {
     pub nonterm: NTermID,
     pub children: Vec<RuleChild>,
     pub nonterms: Vec<NTermID>,
+    pub weight: f64,
 }
 
 impl PlainRule {
@@ -152,17 +203,11 @@ impl PlainRule {
             .map(|child| child.debug_show(ctx))
             .collect::<Vec<_>>()
             .join(", ");
-        format!("{} => {args}", ctx.nt_id_to_s(self.nonterm))
-    }
-}
-
-impl Clone for ScriptRule {
-    fn clone(&self) -> Self {
-        Python::with_gil(|py| ScriptRule {
-            nonterm: self.nonterm,
-            nonterms: self.nonterms.clone(),
-            script: self.script.clone_ref(py),
-        })
+        format!(
+            "{} => {args}{}",
+            ctx.nt_id_to_s(self.nonterm),
+            show_weight(self.weight)
+        )
     }
 }
 
@@ -171,29 +216,47 @@ impl Rule {
         ctx: &mut Context,
         nonterm: &str,
         nterms: &[String],
-        script: PyObject,
+        script: Script,
     ) -> Self {
         return Self::Script(ScriptRule {
             nonterm: ctx.aquire_nt_id(nonterm),
             nonterms: nterms.iter().map(|s| ctx.aquire_nt_id(s)).collect(),
             script,
+            weight: DEFAULT_WEIGHT,
         });
     }
 
     pub fn from_regex(ctx: &mut Context, nonterm: &str, regex: &str) -> Self {
+        let hir = Self::parse_regex(regex).unwrap_or_else(|e| panic!("{}", e));
+        Self::RegExp(RegExpRule {
+            nonterm: ctx.aquire_nt_id(nonterm),
+            hir,
+            weight: DEFAULT_WEIGHT,
+        })
+    }
+
+    pub fn parse_regex(regex: &str) -> Result<Hir, String> {
         use regex_syntax::ParserBuilder;
 
         let mut parser = ParserBuilder::new()
             .unicode(true)
             .allow_invalid_utf8(true)
             .build();
+        parser
+            .parse(regex)
+            .map_err(|e| format!("invalid regex {regex:?}: {e}"))
+    }
 
-        let hir = parser.parse(regex).unwrap();
-
-        Self::RegExp(RegExpRule {
-            nonterm: ctx.aquire_nt_id(nonterm),
-            hir,
-        })
+    //Checks the nonterminal descriptions of a format string without adding anything to a context
+    pub fn check_format(format: &[u8]) -> Result<(), String> {
+        for cap in TOKENIZER.captures_iter(format) {
+            if let Some(sub) = cap.get(1) {
+                let nt = std::str::from_utf8(sub.as_bytes())
+                    .map_err(|_| "nonterminals need to be valid strings".to_string())?;
+                RuleChild::parse_nt_description(nt)?;
+            }
+        }
+        Ok(())
     }
 
     #[must_use]
@@ -221,6 +284,7 @@ impl Rule {
             nonterm: ctx.aquire_nt_id(nonterm),
             children,
             nonterms,
+            weight: DEFAULT_WEIGHT,
         })
     }
 
@@ -232,6 +296,7 @@ impl Rule {
             nonterm: ntermid,
             children,
             nonterms,
+            weight: DEFAULT_WEIGHT,
         })
     }
 
@@ -262,14 +327,6 @@ impl Rule {
     }
 
     fn tokenize(format: &[u8], ctx: &mut Context) -> Vec<RuleChild> {
-        lazy_static! {
-            static ref TOKENIZER: regex::bytes::Regex =
-                regex::bytes::RegexBuilder::new(r"(?-u)(\{[^}\\]+\})|((?:[^{\\]|\\\{|\\\}|\\)+)")
-                    .dot_matches_new_line(true)
-                    .build()
-                    .expect("RAND_994455541");
-        } //RegExp Changed from (\{[^}\\]+\})|((?:[^{\\]|\\\{|\\\}|\\\\)+) because of problems with \\ (\\ was not matched and therefore thrown away)
-
         return TOKENIZER
             .captures_iter(format)
             .map(|cap| {
@@ -303,6 +360,29 @@ impl Rule {
         return self.nonterms().len();
     }
 
+    //Relative probability of the rule among the rules of its nonterminal during generation
+    #[must_use]
+    pub fn weight(&self) -> f64 {
+        match self {
+            Rule::Script(r) => r.weight,
+            Rule::Plain(r) => r.weight,
+            Rule::RegExp(r) => r.weight,
+        }
+    }
+
+    pub fn set_weight(&mut self, weight: f64) {
+        assert!(
+            weight.is_finite() && weight >= 0.0,
+            "invalid rule weight: {}",
+            weight
+        );
+        match self {
+            Rule::Script(r) => r.weight = weight,
+            Rule::Plain(r) => r.weight = weight,
+            Rule::RegExp(r) => r.weight = weight,
+        }
+    }
+
     #[must_use]
     pub fn nonterm(&self) -> NTermID {
         match self {
@@ -384,3 +464,30 @@ impl Rule {
         total_size
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use context::Context;
+    use rule::{Rule, RuleChild};
+
+    #[test]
+    fn nonterminal_names() {
+        assert!(Rule::check_format(b"{EXPR}").is_ok());
+        //Imported grammars keep their lowercase names
+        assert!(Rule::check_format(b"{expr} + {_term-2}").is_ok());
+        assert!(Rule::check_format(b"{expr:lhs}").is_ok());
+        assert!(Rule::check_format(b"{2expr}").is_err());
+        assert!(Rule::check_format(b"{-expr}").is_err());
+        assert!(Rule::check_format(b"{ex pr}").is_err());
+
+        let mut ctx = Context::new();
+        let r = Rule::from_format(&mut ctx, "expr", b"{_term}+{expr}");
+        assert_eq!(r.nonterms().len(), 2);
+        assert_eq!(ctx.nt_id_to_s(r.nonterms()[0]), "_term");
+        assert_eq!(ctx.nt_id_to_s(r.nonterm()), "expr");
+        assert_eq!(
+            RuleChild::from_nt("{expr}", &mut ctx),
+            RuleChild::NTerm(r.nonterms()[1])
+        );
+    }
+}
@@ -0,0 +1,222 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::str::FromStr;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+//How the size of a newly generated (sub)tree is chosen, between the minimal size of the
+//nonterminal or rule and the maximal tree size
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum SizeDistribution {
+    //Always the maximal size
+    #[default]
+    Max,
+    Uniform,
+    //Small sizes are as likely as large orders of magnitude
+    LogUniform,
+    //The minimal size plus a geometrically distributed number of nodes with the given mean
+    Geometric {
+        mean: f64,
+    },
+}
+
+impl SizeDistribution {
+    pub fn sample<R: Rng>(&self, min: usize, max: usize, rng: &mut R) -> usize {
+        let max = max.max(min);
+        let size = match *self {
+            SizeDistribution::Max => max,
+            SizeDistribution::Uniform => rng.gen_range(min..=max),
+            SizeDistribution::LogUniform => {
+                let low = (min.max(1) as f64).ln();
+                let high = (max.max(1) as f64).ln();
+                rng.gen_range(low..=high).exp().round() as usize
+            }
+            SizeDistribution::Geometric { mean } => {
+                //Number of failures before the first success with p = 1/(mean+1)
+                let q = mean.max(0.0) / (mean.max(0.0) + 1.0);
+                let u = 1.0 - rng.gen::<f64>();
+                if q > 0.0 {
+                    min.saturating_add((u.ln() / q.ln()).floor() as usize)
+                } else {
+                    min
+                }
+            }
+        };
+        size.clamp(min, max)
+    }
+}
+
+//Accepts max, uniform, log-uniform and geometric:MEAN
+impl FromStr for SizeDistribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max" => Ok(SizeDistribution::Max),
+            "uniform" => Ok(SizeDistribution::Uniform),
+            "log-uniform" => Ok(SizeDistribution::LogUniform),
+            _ => match s.strip_prefix("geometric:").map(str::parse::<f64>) {
+                Some(Ok(mean)) if mean.is_finite() && mean >= 0.0 => {
+                    Ok(SizeDistribution::Geometric { mean })
+                }
+                _ => Err(format!("unknown size distribution: {s}")),
+            },
+        }
+    }
+}
+
+//Histogram of tree sizes with one bucket per power of two
+#[derive(Clone, Debug, Default)]
+pub struct SizeStats {
+    buckets: [u64; 32],
+    count: u64,
+    total: u64,
+    max: usize,
+}
+
+impl SizeStats {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, size: usize) {
+        self.buckets[Self::bucket(size)] += 1;
+        self.count += 1;
+        self.total += size as u64;
+        self.max = self.max.max(size);
+    }
+
+    pub fn merge(&mut self, other: &SizeStats) {
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += other;
+        }
+        self.count += other.count;
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+
+    #[must_use]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    #[must_use]
+    pub fn average(&self) -> u64 {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+
+    #[must_use]
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    //An upper bound for the size of the given percentage of trees: the smallest power of two
+    //that is at least as large
+    #[must_use]
+    pub fn percentile(&self, percent: u64) -> usize {
+        let wanted = (self.count * percent).div_ceil(100);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= wanted && seen > 0 {
+                return (1usize << i).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    fn bucket(size: usize) -> usize {
+        (size.next_power_of_two().trailing_zeros() as usize).min(31)
+    }
+}
+
+impl fmt::Display for SizeStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "avg {} | 50% <= {} | 90% <= {} | max {}",
+            self.average(),
+            self.percentile(50),
+            self.percentile(90),
+            self.max
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use size_distribution::{SizeDistribution, SizeStats};
+
+    #[test]
+    fn sizes_in_bounds() {
+        let mut rng = StdRng::seed_from_u64(0x7369_7a65);
+        let distributions = [
+            SizeDistribution::Max,
+            SizeDistribution::Uniform,
+            SizeDistribution::LogUniform,
+            SizeDistribution::Geometric { mean: 0.0 },
+            SizeDistribution::Geometric { mean: 20.0 },
+        ];
+        for dist in &distributions {
+            let mut stats = SizeStats::new();
+            for _ in 0..1000 {
+                let size = dist.sample(3, 1000, &mut rng);
+                assert!((3..=1000).contains(&size));
+                stats.add(size);
+            }
+            match dist {
+                SizeDistribution::Max => assert_eq!(stats.average(), 1000),
+                SizeDistribution::Uniform => assert!((400..600).contains(&stats.average())),
+                SizeDistribution::LogUniform => assert!(stats.percentile(50) <= 64),
+                SizeDistribution::Geometric { mean } => {
+                    assert!((stats.average() as f64 - 3.0 - mean).abs() < 5.0);
+                }
+            }
+        }
+        //The minimum wins over the maximum
+        assert_eq!(SizeDistribution::Uniform.sample(10, 5, &mut rng), 10);
+    }
+
+    #[test]
+    fn parse_and_stats() {
+        assert_eq!("max".parse(), Ok(SizeDistribution::Max));
+        assert_eq!(
+            "geometric:50".parse(),
+            Ok(SizeDistribution::Geometric { mean: 50.0 })
+        );
+        assert!("geometric:-1".parse::<SizeDistribution>().is_err());
+        assert!("normal".parse::<SizeDistribution>().is_err());
+
+        let mut stats = SizeStats::new();
+        for size in [1, 2, 3, 4, 5, 100] {
+            stats.add(size);
+        }
+        let mut other = SizeStats::new();
+        other.add(1000);
+        stats.merge(&other);
+        assert_eq!(stats.count(), 7);
+        assert_eq!(stats.average(), 159);
+        assert_eq!(stats.percentile(50), 4);
+        assert_eq!(stats.percentile(90), 1000);
+        assert_eq!(stats.max(), 1000);
+    }
+}
@@ -28,13 +28,13 @@ use pyo3::FromPyObject;
 use rand::thread_rng;
 use rand::Rng;
 use recursion_info::RecursionInfo;
-use rule::{PlainRule, RegExpRule, Rule, RuleChild, RuleIDOrCustom, ScriptRule};
+use rule::{PlainRule, RegExpRule, Rule, RuleChild, RuleIDOrCustom, Script, ScriptRule};
 use serde::{Deserialize, Serialize};
 
 enum UnparseStep<'dat> {
     Term(&'dat [u8]),
     Nonterm(NTermID),
-    Script(usize, PyObject),
+    Script(usize, &'dat Script),
     PushBuffer(),
 }
 
@@ -67,7 +67,7 @@ impl<'data, 'tree: 'data, 'ctx: 'data, W: Write, T: TreeLike> Unparser<'data, 't
         match self.stack.pop() {
             Some(UnparseStep::Term(data)) => self.write(data),
             Some(UnparseStep::Nonterm(nt)) => self.nonterm(nt),
-            Some(UnparseStep::Script(num, expr)) => self.unwrap_script(num, &expr),
+            Some(UnparseStep::Script(num, script)) => self.unwrap_script(num, script),
             Some(UnparseStep::PushBuffer()) => self.push_buffer(),
             None => return false,
         };
@@ -85,19 +85,22 @@ impl<'data, 'tree: 'data, 'ctx: 'data, W: Write, T: TreeLike> Unparser<'data, 't
     fn nonterm(&mut self, nt: NTermID) {
         self.next_rule(nt);
     }
-    fn unwrap_script(&mut self, num: usize, expr: &PyObject) {
-        Python::with_gil(|py| {
-            self.script(py, num, expr)
-                .map_err(|e| e.print_and_set_sys_last_vars(py))
-                .unwrap();
-        });
-    }
-    fn script(&mut self, py: Python, num: usize, expr: &PyObject) -> PyResult<()> {
+    fn unwrap_script(&mut self, num: usize, script: &Script) {
         let bufs = self.buffers.split_off(self.buffers.len() - num);
         let bufs = bufs
             .into_iter()
             .map(std::io::Cursor::into_inner)
             .collect::<Vec<_>>();
+        match script {
+            Script::Python(expr) => Python::with_gil(|py| {
+                self.script(py, &bufs, expr)
+                    .map_err(|e| e.print_and_set_sys_last_vars(py))
+                    .unwrap();
+            }),
+            Script::Native(script) => self.write(&script(&bufs)),
+        }
+    }
+    fn script(&mut self, py: Python, bufs: &[Vec<u8>], expr: &PyObject) -> PyResult<()> {
         let byte_arrays = bufs.iter().map(|b| PyBytes::new(py, b));
         let res = expr.call1(py, PyTuple::new(py, byte_arrays))?;
         if res.as_ref(py).is_instance_of::<PyString>()? {
@@ -124,6 +127,11 @@ impl<'data, 'tree: 'data, 'ctx: 'data, W: Write, T: TreeLike> Unparser<'data, 't
         assert_eq!(nt, rule.nonterm());
         self.i += 1;
         match rule {
+            //Any node may carry custom data, e.g. after an input-to-state replacement. Such a node
+            //has no children, the data is the output of the whole subtree.
+            _ if self.tree.has_custom_data(nid) => {
+                self.next_regexp(self.tree.get_custom_rule_data(nid));
+            }
             Rule::Plain(r) => self.next_plain(r),
             Rule::Script(r) => self.next_script(r),
             Rule::RegExp(_) => self.next_regexp(self.tree.get_custom_rule_data(nid)),
@@ -140,15 +148,9 @@ impl<'data, 'tree: 'data, 'ctx: 'data, W: Write, T: TreeLike> Unparser<'data, 't
         }
     }
 
-    fn next_script(&mut self, r: &ScriptRule) {
-        {
-            Python::with_gil(|py| {
-                self.stack.push(UnparseStep::Script(
-                    r.nonterms.len(),
-                    r.script.clone_ref(py),
-                ));
-            });
-        }
+    fn next_script(&mut self, r: &'ctx ScriptRule) {
+        self.stack
+            .push(UnparseStep::Script(r.nonterms.len(), &r.script));
         for nterm in r.nonterms.iter().rev() {
             self.stack.push(UnparseStep::Nonterm(*nterm));
             self.stack.push(UnparseStep::PushBuffer());
@@ -175,6 +177,9 @@ where
     fn get_rule<'c>(&self, n: NodeID, ctx: &'c Context) -> &'c Rule;
     fn get_rule_or_custom(&self, n: NodeID) -> &RuleIDOrCustom;
     fn get_custom_rule_data(&self, n: NodeID) -> &[u8];
+    fn has_custom_data(&self, n: NodeID) -> bool {
+        matches!(self.get_rule_or_custom(n), RuleIDOrCustom::Custom(..))
+    }
     fn get_nonterm_id(&self, n: NodeID, ctx: &Context) -> NTermID {
         self.get_rule(n, ctx).nonterm()
     }
@@ -299,6 +304,9 @@ impl Tree {
             } else {
                 panic!("Not a valid tree for unparsing!");
             }
+            if self.has_custom_data(node_id) {
+                continue;
+            }
             let rule = self.get_rule(node_id, ctx);
             for nonterm in rule.nonterms().iter().rev() {
                 stack.push((*nonterm, node_id));
@@ -553,6 +561,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn check_unparse_native_script() {
+        //Prefixes the output of the nonterminal with its length
+        fn length_prefixed(args: &[Vec<u8>]) -> Vec<u8> {
+            [format!("{}:", args[0].len()).as_bytes(), &args[0]].concat()
+        }
+        let mut ctx = Context::new();
+        let c = ctx.add_rule("C", b"[{B}]");
+        let b = ctx.add_native_script("B", &["A".to_string()], length_prefixed);
+        let a1 = ctx.add_rule("A", b"a{B}");
+        let a2 = ctx.add_rule("A", b"bc");
+        ctx.initialize(20);
+        let rules = [c, b, a1, b, a2]
+            .iter()
+            .map(|rid| RuleIDOrCustom::Rule(*rid))
+            .collect();
+        let tree = Tree::from_rule_vec(rules, &ctx);
+        assert_eq!(tree.unparse_to_vec(&ctx), b"[5:a2:bc]");
+    }
+
     #[test]
     fn check_find_recursions() {
         let mut ctx = Context::new();
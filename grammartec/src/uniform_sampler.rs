@@ -0,0 +1,258 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use num::bigint::BigUint;
+use num::{One, Zero};
+use rand::{thread_rng, Rng};
+
+use context::Context;
+use newtypes::{NTermID, RuleID};
+use rule::{RegExpRule, Rule, RuleIDOrCustom};
+use tree::Tree;
+
+//How generate_tree_from_nt builds a tree of (at most) the requested size
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum GenerationStrategy {
+    //Top down random walk, guided by the rule weights
+    #[default]
+    Random,
+    //Every derivation tree with the chosen size is equally likely, rule weights are ignored
+    Uniform,
+}
+
+impl FromStr for GenerationStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(GenerationStrategy::Random),
+            "uniform" => Ok(GenerationStrategy::Uniform),
+            _ => Err(format!("unknown generation strategy: {s}")),
+        }
+    }
+}
+
+//Samples uniformly among all derivation trees of a nonterminal with a given number of nodes. The
+//number of trees is precomputed for every nonterminal and every size up to max_len.
+#[derive(Clone, Debug)]
+pub struct UniformSampler {
+    max_len: usize,
+    //nt_counts[nt][n]: number of derivation trees of nt with exactly n nodes
+    nt_counts: HashMap<NTermID, Vec<BigUint>>,
+    //suffix_counts[rule][i][n]: number of ways the children i.. of rule derive exactly n nodes
+    suffix_counts: Vec<Vec<Vec<BigUint>>>,
+}
+
+impl UniformSampler {
+    #[must_use]
+    pub fn new(ctx: &Context, max_len: usize) -> Self {
+        let rules = (0..ctx.get_number_of_rules())
+            .map(RuleID::from)
+            .collect::<Vec<_>>();
+        let mut nt_counts = HashMap::new();
+        for r in &rules {
+            nt_counts
+                .entry(ctx.get_rule(*r).nonterm())
+                .or_insert_with(|| vec![BigUint::zero(); max_len + 1]);
+        }
+        let mut suffix_counts = rules
+            .iter()
+            .map(|r| {
+                let number_of_children = ctx.get_rule(*r).number_of_nonterms();
+                let mut table = vec![vec![BigUint::zero(); max_len + 1]; number_of_children + 1];
+                table[number_of_children][0] = BigUint::one();
+                table
+            })
+            .collect::<Vec<_>>();
+
+        //A tree of size n is a root plus children with n-1 nodes in total, which only depends on
+        //the counts for smaller sizes
+        for n in 1..=max_len {
+            for (r, table) in rules.iter().zip(suffix_counts.iter_mut()) {
+                let rule = ctx.get_rule(*r);
+                for (i, nt) in rule.nonterms().iter().enumerate().rev() {
+                    let mut sum = BigUint::zero();
+                    for first in 1..n {
+                        let rest = &table[i + 1][n - 1 - first];
+                        let child = &nt_counts[nt][first];
+                        if !rest.is_zero() && !child.is_zero() {
+                            sum += child * rest;
+                        }
+                    }
+                    table[i][n - 1] = sum;
+                }
+                let count = table[0][n - 1].clone();
+                if !count.is_zero() {
+                    nt_counts.get_mut(&rule.nonterm()).expect("RAND_2051874396")[n] += count;
+                }
+            }
+        }
+        UniformSampler {
+            max_len,
+            nt_counts,
+            suffix_counts,
+        }
+    }
+
+    //Number of derivation trees of nt with exactly len nodes
+    #[must_use]
+    pub fn count(&self, nt: NTermID, len: usize) -> BigUint {
+        self.nt_counts
+            .get(&nt)
+            .and_then(|counts| counts.get(len))
+            .cloned()
+            .unwrap_or_else(BigUint::zero)
+    }
+
+    //The largest size up to max_len for which nt has a derivation tree
+    #[must_use]
+    pub fn largest_size(&self, nt: NTermID, max_len: usize) -> Option<usize> {
+        let counts = self.nt_counts.get(&nt)?;
+        (1..=max_len.min(self.max_len))
+            .rev()
+            .find(|n| !counts[*n].is_zero())
+    }
+
+    //Returns a uniformly chosen derivation tree of nt with exactly len nodes, or None if there is
+    //none
+    #[must_use]
+    pub fn sample(&self, nt: NTermID, len: usize, ctx: &Context) -> Option<Tree> {
+        if self.count(nt, len).is_zero() {
+            return None;
+        }
+        let mut rng = thread_rng();
+        let mut rules = vec![];
+        let mut stack = vec![(nt, len)];
+        while let Some((nt, len)) = stack.pop() {
+            let rid = self.sample_rule(nt, len, ctx, &mut rng);
+            let rule = ctx.get_rule(rid);
+            rules.push(match rule {
+                Rule::Plain(_) | Rule::Script(_) => RuleIDOrCustom::Rule(rid),
+                Rule::RegExp(RegExpRule { hir, .. }) => {
+                    RuleIDOrCustom::Custom(rid, regex_mutator::generate(hir, rng.gen::<u64>()))
+                }
+            });
+            let sizes = self.sample_child_sizes(rid, len - 1, ctx, &mut rng);
+            //The children are generated from left to right
+            for (child, size) in rule.nonterms().iter().zip(sizes).rev() {
+                stack.push((*child, size));
+            }
+        }
+        Some(Tree::from_rule_vec(rules, ctx))
+    }
+
+    fn sample_rule<R: Rng>(&self, nt: NTermID, len: usize, ctx: &Context, rng: &mut R) -> RuleID {
+        let mut choice = random_below(&self.nt_counts[&nt][len], rng);
+        for rid in ctx.get_rules_for_nt(nt) {
+            let count = &self.suffix_counts[rid.to_i()][0][len - 1];
+            if choice < *count {
+                return *rid;
+            }
+            choice -= count;
+        }
+        unreachable!(
+            "the counts of {} do not match its rules",
+            ctx.nt_id_to_s(nt)
+        )
+    }
+
+    //Splits len nodes among the children of the rule
+    fn sample_child_sizes<R: Rng>(
+        &self,
+        rid: RuleID,
+        mut len: usize,
+        ctx: &Context,
+        rng: &mut R,
+    ) -> Vec<usize> {
+        let table = &self.suffix_counts[rid.to_i()];
+        let mut sizes = vec![];
+        for (i, nt) in ctx.get_rule(rid).nonterms().iter().enumerate() {
+            let mut choice = random_below(&table[i][len], rng);
+            let size = (1..=len)
+                .find(|first| {
+                    let count = &self.nt_counts[nt][*first] * &table[i + 1][len - first];
+                    if choice < count {
+                        return true;
+                    }
+                    choice -= count;
+                    false
+                })
+                .expect("RAND_3468710233");
+            sizes.push(size);
+            len -= size;
+        }
+        sizes
+    }
+}
+
+//Uniformly random number in 0..bound, by rejection sampling
+fn random_below<R: Rng>(bound: &BigUint, rng: &mut R) -> BigUint {
+    assert!(!bound.is_zero());
+    let bits = bound.bits();
+    let mut bytes = vec![0_u8; bits.div_ceil(8) as usize];
+    loop {
+        rng.fill(&mut bytes[..]);
+        if !bits.is_multiple_of(8) {
+            let last = bytes.len() - 1;
+            bytes[last] &= (1_u8 << (bits % 8)) - 1;
+        }
+        let res = BigUint::from_bytes_le(&bytes);
+        if res < *bound {
+            return res;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use context::Context;
+    use num::bigint::BigUint;
+    use tree::TreeLike;
+    use uniform_sampler::UniformSampler;
+
+    #[test]
+    fn counts_and_uniformity() {
+        //Binary trees: the number of trees with n inner nodes is the n-th catalan number
+        let mut ctx = Context::new();
+        ctx.add_rule("T", b"({T}{T})");
+        ctx.add_rule("T", b"x");
+        ctx.initialize(20);
+        let sampler = UniformSampler::new(&ctx, 21);
+        let t = ctx.nt_id("T");
+        let catalan = [1_u32, 1, 2, 5, 14, 42, 132, 429, 1430, 4862, 16796];
+        for (inner, expected) in catalan.iter().enumerate() {
+            assert_eq!(sampler.count(t, 2 * inner + 1), BigUint::from(*expected));
+            assert_eq!(sampler.count(t, 2 * inner + 2), BigUint::from(0_u32));
+        }
+        assert_eq!(sampler.largest_size(t, 20), Some(19));
+        assert!(sampler.sample(t, 4, &ctx).is_none());
+
+        let mut seen = HashMap::new();
+        for _ in 0..1400 {
+            let tree = sampler.sample(t, 9, &ctx).expect("RAND_1390545718");
+            assert_eq!(tree.size(), 9);
+            *seen.entry(tree.unparse_to_vec(&ctx)).or_insert(0) += 1;
+        }
+        //All 14 trees with 4 inner nodes show up about equally often
+        assert_eq!(seen.len(), 14);
+        assert!(seen.values().all(|n| *n > 50 && *n < 150));
+    }
+}
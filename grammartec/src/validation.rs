@@ -0,0 +1,358 @@
+// Nautilus
+// Copyright (C) 2020  Daniel Teuchert, Cornelius Aschermann, Sergej Schumilo
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use pyo3::prelude::*;
+use pyo3::types::PyTuple;
+use regex_syntax::hir::{Class, Hir, HirKind, RepetitionRange};
+
+use context::Context;
+use newtypes::RuleID;
+use rule::{Rule, Script, ScriptRule};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    //The grammar can not be used, generation would panic
+    Error,
+    //The grammar works, but probably not as intended
+    Warning,
+}
+
+#[derive(Clone, Debug)]
+pub struct Finding {
+    pub severity: Severity,
+    //The rule the finding is about, if any
+    pub rule: Option<RuleID>,
+    //Where the rule was defined, if the loader recorded it
+    pub source: Option<String>,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(ctx: &Context, severity: Severity, rule: Option<RuleID>, message: String) -> Self {
+        Finding {
+            severity,
+            rule,
+            source: rule.and_then(|r| ctx.get_rule_source(r)).map(String::from),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error: ")?,
+            Severity::Warning => write!(f, "warning: ")?,
+        }
+        if let Some(source) = &self.source {
+            write!(f, "{source}: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+pub fn validate(ctx: &Context) -> Vec<Finding> {
+    let rules = (0..ctx.get_number_of_rules())
+        .map(RuleID::from)
+        .collect::<Vec<_>>();
+    let mut findings = vec![];
+    check_start(ctx, &mut findings);
+    check_undefined(ctx, &rules, &mut findings);
+    check_unreachable(ctx, &mut findings);
+    check_productive(ctx, &rules, &mut findings);
+    check_duplicates(ctx, &rules, &mut findings);
+    check_scripts(ctx, &rules, &mut findings);
+    check_regexes(ctx, &rules, &mut findings);
+    findings
+}
+
+fn check_start(ctx: &Context, findings: &mut Vec<Finding>) {
+    if !ctx
+        .get_nt_id("START")
+        .is_some_and(|nt| ctx.has_rules_for_nt(nt))
+    {
+        findings.push(Finding::new(
+            ctx,
+            Severity::Error,
+            None,
+            "there is no rule for START".to_string(),
+        ));
+    }
+}
+
+fn check_undefined(ctx: &Context, rules: &[RuleID], findings: &mut Vec<Finding>) {
+    for rid in rules {
+        let mut reported = HashSet::new();
+        for nt in ctx.get_rule(*rid).nonterms() {
+            if !ctx.has_rules_for_nt(*nt) && reported.insert(*nt) {
+                findings.push(Finding::new(
+                    ctx,
+                    Severity::Error,
+                    Some(*rid),
+                    format!(
+                        "nonterminal {} is used but never defined",
+                        ctx.nt_id_to_s(*nt)
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+fn check_unreachable(ctx: &Context, findings: &mut Vec<Finding>) {
+    let Some(start) = ctx.get_nt_id("START") else {
+        return;
+    };
+    let mut reachable = HashSet::from([start]);
+    let mut queue = VecDeque::from(vec![start]);
+    while let Some(nt) = queue.pop_front() {
+        if !ctx.has_rules_for_nt(nt) {
+            continue;
+        }
+        for rid in ctx.get_rules_for_nt(nt) {
+            for child in ctx.get_rule(*rid).nonterms() {
+                if reachable.insert(*child) {
+                    queue.push_back(*child);
+                }
+            }
+        }
+    }
+    for nt in ctx.get_nonterms() {
+        if !reachable.contains(&nt) && ctx.has_rules_for_nt(nt) {
+            findings.push(Finding::new(
+                ctx,
+                Severity::Warning,
+                Some(ctx.get_rules_for_nt(nt)[0]),
+                format!(
+                    "nonterminal {} is unreachable from START",
+                    ctx.nt_id_to_s(nt)
+                ),
+            ));
+        }
+    }
+}
+
+//A nonterminal is productive if one of its rules only uses productive nonterminals. Undefined
+//nonterminals are reported separately.
+fn check_productive(ctx: &Context, rules: &[RuleID], findings: &mut Vec<Finding>) {
+    let mut productive = HashSet::new();
+    let mut something_changed = true;
+    while something_changed {
+        something_changed = false;
+        for rid in rules {
+            let rule = ctx.get_rule(*rid);
+            if !productive.contains(&rule.nonterm())
+                && rule.nonterms().iter().all(|nt| productive.contains(nt))
+            {
+                productive.insert(rule.nonterm());
+                something_changed = true;
+            }
+        }
+    }
+    for nt in ctx.get_nonterms() {
+        if !productive.contains(&nt) && ctx.has_rules_for_nt(nt) {
+            findings.push(Finding::new(
+                ctx,
+                Severity::Error,
+                Some(ctx.get_rules_for_nt(nt)[0]),
+                format!(
+                    "nonterminal {} can not derive any input (missing base/non recursive case?)",
+                    ctx.nt_id_to_s(nt)
+                ),
+            ));
+        }
+    }
+}
+
+fn check_duplicates(ctx: &Context, rules: &[RuleID], findings: &mut Vec<Finding>) {
+    let mut seen = HashMap::new();
+    for rid in rules {
+        let key = match ctx.get_rule(*rid) {
+            Rule::Plain(r) => format!("{:?}", (r.nonterm, &r.children)),
+            Rule::RegExp(r) => format!("{:?}", (r.nonterm, &r.hir)),
+            Rule::Script(_) => continue,
+        };
+        if let Some(first) = seen.get(&key) {
+            let location = ctx
+                .get_rule_source(*first)
+                .map(|source| format!(" (first defined at {source})"))
+                .unwrap_or_default();
+            findings.push(Finding::new(
+                ctx,
+                Severity::Warning,
+                Some(*rid),
+                format!(
+                    "duplicate rule {}{location}",
+                    ctx.get_rule(*rid).debug_show(ctx)
+                ),
+            ));
+        } else {
+            seen.insert(key, *rid);
+        }
+    }
+}
+
+//Python scripts are called with one argument per nonterminal
+fn check_scripts(ctx: &Context, rules: &[RuleID], findings: &mut Vec<Finding>) {
+    let scripts = rules
+        .iter()
+        .filter_map(|rid| match ctx.get_rule(*rid) {
+            Rule::Script(ScriptRule {
+                script: Script::Python(script),
+                nonterms,
+                ..
+            }) => Some((*rid, script, nonterms)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if scripts.is_empty() {
+        return;
+    }
+    Python::with_gil(|py| {
+        let Ok(inspect) = py.import("inspect") else {
+            return;
+        };
+        for (rid, script, nonterms) in scripts {
+            //Builtins may not have a signature
+            let Ok(signature) = inspect.call_method1("signature", (script.as_ref(py),)) else {
+                continue;
+            };
+            let args = PyTuple::new(py, nonterms.iter().map(|_| py.None()));
+            if let Err(e) = signature.call_method1("bind", args) {
+                findings.push(Finding::new(
+                    ctx,
+                    Severity::Error,
+                    Some(rid),
+                    format!(
+                        "script can not be called with the {} nonterminals of {}: {}",
+                        nonterms.len(),
+                        ctx.get_rule(rid).debug_show(ctx),
+                        e.value(py)
+                    ),
+                ));
+            }
+        }
+    });
+}
+
+fn check_regexes(ctx: &Context, rules: &[RuleID], findings: &mut Vec<Finding>) {
+    for rid in rules {
+        let Rule::RegExp(rule) = ctx.get_rule(*rid) else {
+            continue;
+        };
+        if let Some(problem) = regex_problem(&rule.hir) {
+            findings.push(Finding::new(
+                ctx,
+                Severity::Error,
+                Some(*rid),
+                format!(
+                    "regex of {} can not be generated: {problem}",
+                    ctx.nt_id_to_s(rule.nonterm)
+                ),
+            ));
+        }
+    }
+}
+
+//Finds the constructs the regex generator can not handle
+pub fn regex_problem(hir: &Hir) -> Option<String> {
+    let mut stack = vec![hir];
+    while let Some(hir) = stack.pop() {
+        match hir.kind() {
+            HirKind::Class(Class::Unicode(cls)) => {
+                if cls.ranges().is_empty() {
+                    return Some("it contains an empty character class".to_string());
+                }
+                if cls.ranges().iter().any(|r| !r.end().is_ascii()) {
+                    return Some(
+                        "a character class (e.g. . or \\w) contains non-ASCII characters, only ASCII ranges are supported".to_string(),
+                    );
+                }
+            }
+            HirKind::Class(Class::Bytes(cls)) if cls.ranges().is_empty() => {
+                return Some("it contains an empty character class".to_string());
+            }
+            HirKind::Repetition(rep) => {
+                if let regex_syntax::hir::RepetitionKind::Range(RepetitionRange::Bounded(a, b)) =
+                    rep.kind
+                {
+                    if a == b {
+                        return Some(format!("write {{{a}}} instead of {{{a},{b}}}"));
+                    }
+                }
+                stack.push(&rep.hir);
+            }
+            HirKind::Group(grp) => stack.push(&grp.hir),
+            HirKind::Concat(hirs) | HirKind::Alternation(hirs) => stack.extend(hirs.iter()),
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use context::Context;
+    use validation::Severity;
+
+    #[test]
+    fn validate() {
+        let mut ctx = Context::new();
+        let start = ctx.add_rule("START", b"{A}{UNDEFINED}");
+        ctx.set_rule_source(start, "grammar.py:1".to_string());
+        ctx.add_rule("A", b"a");
+        let dup = ctx.add_rule("A", b"a");
+        ctx.set_rule_source(dup, "grammar.py:3".to_string());
+        ctx.add_rule("LOOP", b"{LOOP}x");
+        ctx.add_regex("R", "[a-z]{2,2}");
+        ctx.add_regex("R", "[a-zäöü]");
+
+        let findings = ctx
+            .validate()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        let expected = [
+            "error: grammar.py:1: nonterminal UNDEFINED is used but never defined",
+            "warning: grammar.py:3: duplicate rule A => \"a\"",
+            "warning: nonterminal LOOP is unreachable from START",
+            "error: nonterminal LOOP can not derive any input (missing base/non recursive case?)",
+            "error: regex of R can not be generated: write {2} instead of {2,2}",
+        ];
+        for e in &expected {
+            assert!(
+                findings.iter().any(|f| f.starts_with(e)),
+                "missing {:?} in {:?}",
+                e,
+                findings
+            );
+        }
+        assert!(findings.iter().any(|f| f.contains("non-ASCII")));
+        //START is not productive because of UNDEFINED, R is unreachable as well
+        assert_eq!(findings.len(), expected.len() + 3);
+
+        let mut ctx = Context::new();
+        ctx.add_rule("START", b"{A}");
+        ctx.add_rule("A", b"a");
+        assert!(ctx.validate().is_empty());
+        ctx = Context::new();
+        ctx.add_rule("A", b"a");
+        assert_eq!(ctx.validate()[0].severity, Severity::Error);
+    }
+}
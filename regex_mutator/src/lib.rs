@@ -117,7 +117,7 @@ fn append_unicode_range(res: &mut Vec<u8>, scr: &mut RegexScript, cls: ClassUnic
 }
 
 fn append_byte_range(res: &mut Vec<u8>, scr: &mut RegexScript, cls: ClassBytesRange) {
-    res.push(scr.get_range(cls.start() as usize, (cls.end() + 1) as usize) as u8);
+    res.push(scr.get_range(cls.start() as usize, cls.end() as usize + 1) as u8);
 }
 
 fn append_class(res: &mut Vec<u8>, scr: &mut RegexScript, cls: &Class) {